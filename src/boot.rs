@@ -0,0 +1,85 @@
+use crate::mode::Model;
+
+// Register state left behind by each model's boot ROM, used when skipping it
+pub struct BootValues {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub div: u8,
+    // (address, value) pairs written in order, NR52 first so the APU accepts the rest
+    pub io: &'static [(u16, u8)]
+}
+
+const DMG_IO: [(u16, u8); 5] = [
+    (0xFF26, 0xF1),
+    (0xFF25, 0xF3),
+    (0xFF24, 0x77),
+    (0xFF40, 0x91),
+    (0xFF47, 0xFC)
+];
+
+// The SGB boot ROM leaves channel 1 off
+const SGB_IO: [(u16, u8); 5] = [
+    (0xFF26, 0xF0),
+    (0xFF25, 0xF3),
+    (0xFF24, 0x77),
+    (0xFF40, 0x91),
+    (0xFF47, 0xFC)
+];
+
+impl BootValues {
+    pub fn new(model: Model) -> Self {
+        // F on DMG and MGB depends on the header checksum, these assume it is non-zero
+        match model {
+            Model::DMG => Self {
+                af: 0x01B0,
+                bc: 0x0013,
+                de: 0x00D8,
+                hl: 0x014D,
+                sp: 0xFFFE,
+                div: 0xAB,
+                io: &DMG_IO
+            },
+            Model::MGB => Self {
+                af: 0xFFB0,
+                bc: 0x0013,
+                de: 0x00D8,
+                hl: 0x014D,
+                sp: 0xFFFE,
+                div: 0xAB,
+                io: &DMG_IO
+            },
+            Model::SGB => Self {
+                af: 0x0100,
+                bc: 0x0014,
+                de: 0x0000,
+                hl: 0xC060,
+                sp: 0xFFFE,
+                div: 0x00,
+                io: &SGB_IO
+            },
+            // DIV on CGB and AGB depends on how long the logo animation ran
+            Model::CGB => Self {
+                af: 0x1180,
+                bc: 0x0000,
+                de: 0xFF56,
+                hl: 0x000D,
+                sp: 0xFFFE,
+                div: 0x00,
+                io: &DMG_IO
+            },
+            // Bit 0 of B is only set on a GBA, CGB games check it to detect one
+            Model::AGB => Self {
+                af: 0x1100,
+                bc: 0x0100,
+                de: 0xFF56,
+                hl: 0x000D,
+                sp: 0xFFFE,
+                div: 0x00,
+                io: &DMG_IO
+            }
+        }
+    }
+}
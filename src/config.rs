@@ -0,0 +1,140 @@
+use crate::mbc::mode::MBCMode;
+use crate::mode::{ClockSpeed, Model};
+use crate::ppu::DmgPalette;
+
+// Options for CPU::new_with_config, the defaults are a DMG skipping the boot ROM
+pub struct EmulatorConfig {
+    pub model: Model,
+    pub clock: ClockSpeed,
+    // Detected from the cartridge header when unset
+    pub mbc_mode: Option<MBCMode>,
+    // Overlays the cartridge until the game writes $FF50
+    pub boot_rom: Option<Vec<u8>>,
+    pub print_serial: bool,
+    // Defaults to the model's LCD tint
+    pub palette: Option<DmgPalette>,
+    // Defaults to the output device's preferred rate
+    pub sample_rate: Option<u32>,
+    // Off for headless runs, the APU still runs but no output device is opened
+    pub audio: bool,
+    // DIV seed passed to CPU::set_deterministic
+    pub deterministic: Option<u16>
+}
+
+impl Default for EmulatorConfig {
+    fn default() -> Self {
+        Self {
+            model: Model::DMG,
+            clock: ClockSpeed::Standard,
+            mbc_mode: None,
+            boot_rom: None,
+            print_serial: false,
+            palette: None,
+            sample_rate: None,
+            audio: true,
+            deterministic: None
+        }
+    }
+}
+
+impl EmulatorConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn clock(mut self, clock: ClockSpeed) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn mbc_mode(mut self, mbc_mode: MBCMode) -> Self {
+        self.mbc_mode = Some(mbc_mode);
+        self
+    }
+
+    pub fn boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+        self.boot_rom = Some(boot_rom);
+        self
+    }
+
+    pub fn print_serial(mut self, print_serial: bool) -> Self {
+        self.print_serial = print_serial;
+        self
+    }
+
+    pub fn palette(mut self, palette: DmgPalette) -> Self {
+        self.palette = Some(palette);
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn audio(mut self, audio: bool) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    pub fn deterministic(mut self, div_seed: u16) -> Self {
+        self.deterministic = Some(div_seed);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn defaults_are_a_dmg_skipping_the_boot_rom() {
+        let config = EmulatorConfig::new();
+        assert!(config.model == Model::DMG);
+        assert!(config.clock == ClockSpeed::Standard);
+        assert_eq!(config.mbc_mode, None);
+        assert!(config.boot_rom.is_none());
+        assert!(!config.print_serial);
+        assert_eq!(config.palette, None);
+        assert_eq!(config.sample_rate, None);
+        assert!(config.audio);
+        assert_eq!(config.deterministic, None);
+    }
+
+    #[test]
+    fn builder_sets_every_option() {
+        let config = EmulatorConfig::new()
+            .model(Model::CGB)
+            .clock(ClockSpeed::SGB)
+            .mbc_mode(MBCMode::MBC5)
+            .boot_rom(vec![0x31; 0x100])
+            .print_serial(true)
+            .palette(DmgPalette::Grayscale)
+            .sample_rate(22050)
+            .audio(false)
+            .deterministic(0x1234);
+        assert!(config.model == Model::CGB);
+        assert!(config.clock == ClockSpeed::SGB);
+        assert_eq!(config.mbc_mode, Some(MBCMode::MBC5));
+        assert_eq!(config.boot_rom, Some(vec![0x31; 0x100]));
+        assert!(config.print_serial);
+        assert_eq!(config.palette, Some(DmgPalette::Grayscale));
+        assert_eq!(config.sample_rate, Some(22050));
+        assert!(!config.audio);
+        assert_eq!(config.deterministic, Some(0x1234));
+    }
+
+    #[test]
+    fn cpus_are_built_from_the_config() {
+        let config = EmulatorConfig::new().sample_rate(22050).audio(false).deterministic(0x1200);
+        let cpu = CPU::new_with_config(vec![0x00; 0x8000], config);
+        assert_eq!(cpu.mem.apu.sample_rate(), 22050);
+        assert_eq!(cpu.peek(0xFF04), 0x12);
+    }
+}
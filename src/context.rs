@@ -1,6 +1,5 @@
 use wgpu::util::DeviceExt;
 use winit::window::Window;
-use crate::ppu::{SCREEN_H, SCREEN_W};
 
 // Code here is mostly derived from https://sotrh.github.io/learn-wgpu/beginner/tutorial1-window/
 
@@ -64,12 +63,14 @@ pub struct Context {
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
     texture: wgpu::Texture,
+    texture_size: wgpu::Extent3d,
     bind_group: wgpu::BindGroup,
     window: Window,
 }
 
 impl Context {
-    pub async fn new(window: Window) -> Self {
+    // Frames passed to update are width x height RGBA
+    pub async fn new(window: Window, width: usize, height: usize) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -119,13 +120,14 @@ impl Context {
         };
         surface.configure(&device, &config);
 
+        let texture_size = wgpu::Extent3d {
+            width: width as u32,
+            height: height as u32,
+            depth_or_array_layers: 1,
+        };
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Texture"),
-            size: wgpu::Extent3d {
-                width: SCREEN_W as u32,
-                height: SCREEN_H as u32,
-                depth_or_array_layers: 1,
-            },
+            size: texture_size,
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
@@ -254,6 +256,7 @@ impl Context {
             vertex_buffer,
             index_buffer,
             texture,
+            texture_size,
             bind_group
         }
     }
@@ -284,14 +287,10 @@ impl Context {
             &rgba,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(4 * SCREEN_W as u32),
-                rows_per_image: Some(SCREEN_H as u32),
-            },
-            wgpu::Extent3d {
-                width: SCREEN_W as u32,
-                height: SCREEN_H as u32,
-                depth_or_array_layers: 1,
+                bytes_per_row: Some(4 * self.texture_size.width),
+                rows_per_image: Some(self.texture_size.height),
             },
+            self.texture_size,
         );
     }
 
@@ -1,27 +1,79 @@
-use crate::mbc::mode::MBCMode;
+use crate::mbc::mode::MBC;
+use crate::mbc::loader;
+use crate::config::EmulatorConfig;
 use crate::mmu::MMU;
-use crate::mode::GBMode;
+use crate::boot::BootValues;
+use crate::mode::{GBMode, Model};
+use crate::ppu;
 use crate::registers::{Registers, Flags};
 use crate::memory::Memory;
+use crate::fnv::FnvHasher;
+use crate::timing::FRAME_DOTS;
+use crate::input_script::InputScript;
+use std::hash::{Hash, Hasher};
+
+const CYCLES_PER_FRAME: u64 = FRAME_DOTS as u64;
+// The DMG boot ROM locks up on a bad logo, skip_boot gives up after this many frames
+const BOOT_SKIP_FRAMES: u64 = 600;
 
 pub struct CPU {
+    model: Model,
     reg: Registers,
     pub mem: MMU,
     halted: bool,
+    // HALT entered with IME=0 and an interrupt pending fails to increment PC
+    halt_bug: bool,
     // Enabled Interrupts
     ime: bool,
-    ime_ask: bool
+    // Set by EI, committed to IME one instruction later
+    ime_ask: bool,
+    paused: bool,
+    // Frames completed by run_frames, used to time scripted input
+    frame: u64,
+    input_script: Option<InputScript>
 }
 
 impl CPU {
-    pub fn new(mode: GBMode, mbc_mode: MBCMode, print_serial: bool, rom: Vec<u8>, booting: bool) -> Self {
-        Self {
-            reg: Registers::new(mode, booting),
-            mem: MMU::new(mode, mbc_mode, print_serial, rom),
+    pub fn new_with_config(rom: Vec<u8>, config: EmulatorConfig) -> Self {
+        let mbc_mode = match config.mbc_mode.or_else(|| loader::detect_mbc(rom[0x0147], rom.len())) {
+            Some(v) => v,
+            None => panic!("Unsupported Cart Type! {:#04x}", rom[0x0147]),
+        };
+
+        CPU::with_cartridge(loader::new_mbc(mbc_mode, rom), config)
+    }
+
+    // Starts with an already loaded cartridge, config.mbc_mode is ignored
+    pub fn with_cartridge(mbc: Box<dyn MBC>, config: EmulatorConfig) -> Self {
+        let model = config.model;
+        let booting = config.boot_rom.is_some();
+
+        let mut cpu = Self {
+            model,
+            reg: Registers::new(model, booting),
+            mem: MMU::with_cartridge(model.mode(), config.clock, mbc, config.print_serial, config.sample_rate, config.audio),
             halted: false,
+            halt_bug: false,
             ime: false,
-            ime_ask: false
+            ime_ask: false,
+            paused: false,
+            frame: 0,
+            input_script: None
+        };
+
+        match config.boot_rom {
+            Some(boot_rom) => cpu.mem.map_boot_rom(boot_rom),
+            None => cpu.mem.apply_boot_values(&BootValues::new(model)),
+        }
+        cpu.mem.ppu.set_dmg_palette_preset(config.palette.unwrap_or(model.default_palette()));
+        if !booting {
+            cpu.apply_dmg_compat();
+        }
+        if let Some(div_seed) = config.deterministic {
+            cpu.set_deterministic(div_seed);
         }
+
+        cpu
     }
 
     pub fn cycle(&mut self) -> u32 {
@@ -32,21 +84,243 @@ impl CPU {
             } else if self.halted {
                 1
             } else {
-                if self.ime_ask && !self.ime {
+                // EI takes effect after the following instruction retires,
+                // unless that instruction was DI
+                let ime_pending = self.ime_ask;
+                let count = self.op_call();
+                if ime_pending && self.ime_ask {
                     self.ime = true;
                     self.ime_ask = false;
                 }
-
-                self.op_call()
+                count
             }
         };
         cycles * 4
     }
 
+    // Hot-swaps the cartridge and restarts from the state the boot ROM would leave behind,
+    // keeping the audio stream and frontend settings
+    pub fn insert_cartridge(&mut self, mbc: Box<dyn MBC>) {
+        self.mem.insert_cartridge(mbc);
+        self.reg = Registers::new(self.model, false);
+        self.halted = false;
+        self.halt_bug = false;
+        self.ime = false;
+        self.ime_ask = false;
+        self.frame = 0;
+        self.mem.apply_boot_values(&BootValues::new(self.model));
+        self.apply_dmg_compat();
+    }
+
+    // Does what the CGB boot ROM would for DMG-only games when there isn't one: switches
+    // the PPU to compatibility mode and colourises the game
+    fn apply_dmg_compat(&mut self) {
+        if self.model.mode() != GBMode::Color || self.mem.peek(0x0143) & 0x80 != 0 {
+            return;
+        }
+        self.mem.ppu.dmg_compat = true;
+        self.mem.ppu.force_compat_palette(ppu::DEFAULT_COMPAT_PALETTE);
+    }
+
+    // Runs the boot ROM until it unmaps itself through $FF50, falling back to the state it
+    // would have left behind if that takes too long. Does nothing without a boot ROM.
+    pub fn skip_boot(&mut self) {
+        let mut elapsed = 0;
+        while self.mem.boot_rom_mapped() {
+            if elapsed >= BOOT_SKIP_FRAMES * CYCLES_PER_FRAME {
+                self.mem.unmap_boot_rom();
+                self.reg = Registers::new(self.model, false);
+                self.halted = false;
+                self.halt_bug = false;
+                self.ime = false;
+                self.ime_ask = false;
+                self.mem.apply_boot_values(&BootValues::new(self.model));
+                break;
+            }
+
+            let cycles = self.cycle();
+            elapsed += cycles as u64;
+            self.mem.cycle(cycles);
+        }
+    }
+
+    // Stops the machine from being stepped and silences audio
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.mem.apu.pause();
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.mem.apu.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Formats switchable ROM addresses as bank:addr (e.g. 02:4000) like mGBA and BGB traces
+    pub fn format_address(&self, a: u16) -> String {
+        match a {
+            0x4000..=0x7FFF => format!("{:02X}:{:04X}", self.mem.rom_bank(), a),
+            _ => format!("{:04X}", a),
+        }
+    }
+
+    // One trace line for the instruction about to execute
+    pub fn trace(&self) -> String {
+        format!("{} {}", self.format_address(self.reg.pc), self.reg)
+    }
+
+    // Stable hash of all emulated state, equal for machines in identical states
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = FnvHasher::new();
+        self.reg.hash(&mut hasher);
+        self.halted.hash(&mut hasher);
+        self.halt_bug.hash(&mut hasher);
+        self.ime.hash(&mut hasher);
+        self.ime_ask.hash(&mut hasher);
+        self.mem.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Runs one CPU step and the rest of the machine alongside it
+    fn step(&mut self) -> u32 {
+        let cycles = self.cycle();
+        self.mem.cycle(cycles);
+        cycles
+    }
+
+    // Single-steps one instruction, interrupt dispatch or halted M-cycle,
+    // keeping the PPU, APU and timer in lockstep. Returns the cycles taken.
+    pub fn step_instruction(&mut self) -> u8 {
+        self.step() as u8
+    }
+
+    // Reads and writes memory for trainers and tests without any access timing
+    pub fn peek(&self, a: u16) -> u8 {
+        self.mem.peek(a)
+    }
+
+    pub fn poke(&mut self, a: u16, v: u8) {
+        self.mem.poke(a, v);
+    }
+
+    // Starts the divider at `div_seed` and cuts off host time (the MBC3 clock), so games that
+    // seed their RNG from DIV behave the same on every run with the same input
+    pub fn set_deterministic(&mut self, div_seed: u16) {
+        self.mem.set_deterministic(div_seed);
+    }
+
+    // CPU cycles run between the two most recent frames
+    pub fn last_frame_cycles(&self) -> u64 {
+        self.mem.last_frame_cycles()
+    }
+
+    // Output samples covered by the most recent frame, see APU::end_frame
+    pub fn last_frame_samples(&self) -> u32 {
+        self.mem.apu.last_frame_samples()
+    }
+
+    // The CPU runs twice as many cycles per frame in double speed mode
+    pub fn target_frame_cycles(&self) -> u64 {
+        if self.mem.double_speed() {
+            CYCLES_PER_FRAME * 2
+        } else {
+            CYCLES_PER_FRAME
+        }
+    }
+
+    pub fn cartridge_ram(&self) -> Option<&[u8]> {
+        self.mem.cartridge_ram()
+    }
+
+    pub fn cartridge_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.mem.cartridge_ram_mut()
+    }
+
+    // Scripted input is applied at frame boundaries during run_frames
+    pub fn load_input_script(&mut self, script: InputScript) {
+        self.input_script = Some(script);
+    }
+
+    pub fn run_frames(&mut self, frames: u64) {
+        for _ in 0..frames {
+            if let Some(script) = self.input_script.as_mut() {
+                for &(_, button, pressed) in script.take_due(self.frame) {
+                    if pressed {
+                        self.mem.joypad.down(button);
+                    } else {
+                        self.mem.joypad.up(button);
+                    }
+                }
+            }
+
+            // Frames still take their usual time with the LCD off
+            let target = self.target_frame_cycles();
+            let mut elapsed = 0;
+            while elapsed < target {
+                let cycles = self.cycle();
+                elapsed += cycles as u64;
+                if self.mem.cycle(cycles) {
+                    break;
+                }
+            }
+            self.frame += 1;
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.reg.sp = sp;
+    }
+
+    // Calls the routine at `pc` with `a` loaded, returning to `ret`. Used to drive
+    // code that was never meant to run on its own, like GBS music drivers.
+    pub fn call_routine(&mut self, pc: u16, ret: u16, a: u8) {
+        self.push(ret);
+        self.reg.pc = pc;
+        self.reg.a = a;
+        self.halted = false;
+    }
+
+    // Steps until PC reaches `pc`, returns false if the cycle budget ran out first
+    pub fn run_until_pc(&mut self, pc: u16, max_cycles: u64) -> bool {
+        let mut elapsed = 0;
+        while self.reg.pc != pc {
+            if elapsed >= max_cycles {
+                return false;
+            }
+            elapsed += self.step() as u64;
+        }
+        true
+    }
+
+    // Steps until `addr` is written to, returns false if the cycle budget ran out first
+    pub fn run_until_write(&mut self, addr: u16, max_cycles: u64) -> bool {
+        let mut elapsed = 0;
+        let mut hit = false;
+        self.mem.set_write_watch(Some(addr));
+
+        while elapsed < max_cycles {
+            elapsed += self.step() as u64;
+            if self.mem.take_write_watch_hit() {
+                hit = true;
+                break;
+            }
+        }
+
+        self.mem.set_write_watch(None);
+        hit
+    }
+
     fn interrupt(&mut self) -> u32 {
         let intf = self.mem.read(0xFF0F);
         let inte = self.mem.read(0xFFFF);
-        let triggered = intf & inte;
+        let triggered = intf & inte & 0x1F;
         if triggered == 0 {
             return 0;
         }
@@ -57,18 +331,36 @@ impl CPU {
         }
         self.ime = false;
 
-        let n = triggered.trailing_zeros();
-        let remaining = intf & !(1 << n);
-        self.mem.write(0xFF0F, remaining);
+        // 5 M-cycles: 2 internal, 2 pushing PC and 1 jumping to the vector.
+        // The vector is only chosen after the high byte is pushed, so if that push
+        // lands on IE it can redirect the interrupt, or cancel it and jump to $0000.
+        let pc = self.reg.pc;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.mem.write(self.reg.sp, (pc >> 8) as u8);
 
-        self.push(self.reg.pc);
-        self.reg.pc = 0x0040 | ((n as u16) << 3);
-        4
+        let intf = self.mem.read(0xFF0F);
+        let triggered = intf & self.mem.read(0xFFFF) & 0x1F;
+
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.mem.write(self.reg.sp, pc as u8);
+
+        if triggered == 0 {
+            self.reg.pc = 0x0000;
+        } else {
+            let n = triggered.trailing_zeros();
+            self.mem.write(0xFF0F, intf & !(1 << n));
+            self.reg.pc = 0x0040 | ((n as u16) << 3);
+        }
+        5
     }
 
     pub fn read_byte(&mut self) -> u8 {
         let byte = self.mem.read(self.reg.pc);
-        self.reg.pc += 1;
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.reg.pc += 1;
+        }
         byte
     }
 
@@ -114,7 +406,7 @@ impl CPU {
             0x0E => { self.reg.c = self.read_byte();                  2 },
             0x0F => { self.reg.a = self.alu_rrc(self.reg.a);
                       self.reg.set_flag(Flags::Z, false);             1 },
-            0x10 => {                                                 1 },
+            0x10 => { self.mem.switch_speed();                        1 },
             0x11 => { let v = self.read_word();
                       self.reg.set_de(v);                             3 },
             0x12 => { self.mem.write(self.reg.get_de(), self.reg.a);  2 },
@@ -249,7 +541,7 @@ impl CPU {
                       self.mem.write(a, self.reg.h);                  2 },
             0x75 => { let a = self.reg.get_hl();
                       self.mem.write(a, self.reg.l);                  2 },
-            0x76 => { self.halted = true;                             1 },
+            0x76 => { self.halt();                                    1 },
             0x77 => { let a = self.reg.get_hl();
                       self.mem.write(a, self.reg.a);                  2 },
             0x78 => { self.reg.a = self.reg.b;                        1 },
@@ -729,6 +1021,16 @@ impl CPU {
         }
     }
 
+    fn halt(&mut self) {
+        let pending = self.mem.read(0xFF0F) & self.mem.read(0xFFFF) & 0x1F;
+        if !self.ime && pending != 0 {
+            // CPU doesn't halt, and the next byte is read twice
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
+
     fn jr(&mut self, cond: bool) -> u32 {
         let byte = self.read_byte() as i8;
         if cond {
@@ -1033,4 +1335,486 @@ impl CPU {
         self.reg.set_flag(Flags::H, false);
         self.reg.set_flag(Flags::N, false);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::Interrupts;
+    use crate::joypad::JoypadButton;
+    use crate::mbc::rom_only::ROMOnly;
+    use crate::ppu::DmgPalette;
+
+    // A 32KB ROM-only cart with `program` at the $0100 entry point, started without audio
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        CPU::new_with_config(rom, EmulatorConfig::new().audio(false))
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_instruction_twice() {
+        // HALT; INC A
+        let mut cpu = cpu_with_program(&[0x76, 0x3C]);
+        cpu.reg.a = 0x00;
+        cpu.poke(0xFFFF, Interrupts::V_BLANK.bits());
+        cpu.poke(0xFF0F, Interrupts::V_BLANK.bits());
+
+        cpu.step_instruction();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc(), 0x0101);
+
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0x0101);
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0x0102);
+        assert_eq!(cpu.reg.a, 0x02);
+    }
+
+    #[test]
+    fn halt_wakes_without_servicing_when_ime_is_clear() {
+        // HALT; INC A
+        let mut cpu = cpu_with_program(&[0x76, 0x3C]);
+        cpu.reg.a = 0x00;
+        cpu.poke(0xFFFF, Interrupts::V_BLANK.bits());
+
+        cpu.step_instruction();
+        assert!(cpu.halted);
+        cpu.step_instruction();
+        assert!(cpu.halted);
+
+        cpu.poke(0xFF0F, Interrupts::V_BLANK.bits());
+        cpu.step_instruction();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc(), 0x0102);
+        assert_eq!(cpu.reg.a, 0x01);
+        // Left pending, since it was never serviced
+        assert_eq!(cpu.peek(0xFF0F) & 0x1F, Interrupts::V_BLANK.bits());
+    }
+
+    #[test]
+    fn ei_enables_interrupts_after_the_next_instruction() {
+        // EI; NOP; NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0x00, 0x00]);
+        cpu.poke(0xFFFF, Interrupts::V_BLANK.bits());
+        cpu.poke(0xFF0F, Interrupts::V_BLANK.bits());
+
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0x0101);
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0x0102);
+
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0x0040);
+        assert_eq!(cpu.peek(0xFF0F) & 0x1F, 0x00);
+    }
+
+    #[test]
+    fn di_straight_after_ei_blocks_interrupts() {
+        // EI; DI; NOP; NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0xF3, 0x00, 0x00]);
+        cpu.poke(0xFFFF, Interrupts::V_BLANK.bits());
+        cpu.poke(0xFF0F, Interrupts::V_BLANK.bits());
+
+        for _ in 0..4 {
+            cpu.step_instruction();
+        }
+        assert_eq!(cpu.pc(), 0x0104);
+        assert_eq!(cpu.peek(0xFF0F) & 0x1F, Interrupts::V_BLANK.bits());
+    }
+
+    #[test]
+    fn run_until_pc_stops_at_the_jump_target() {
+        // JP $0150, with JR -2 looping forever at $0150
+        let mut program = vec![0x00; 0x52];
+        program[0x00..0x03].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        program[0x50..0x52].copy_from_slice(&[0x18, 0xFE]);
+        let mut cpu = cpu_with_program(&program);
+        assert!(cpu.run_until_pc(0x0150, 1000));
+        assert_eq!(cpu.pc(), 0x0150);
+
+        assert!(!cpu.run_until_pc(0x0200, 1000));
+        assert_eq!(cpu.pc(), 0x0150);
+    }
+
+    #[test]
+    fn run_until_write_stops_after_the_write() {
+        // LD A, $42; LD ($C000), A
+        let mut cpu = cpu_with_program(&[0x3E, 0x42, 0xEA, 0x00, 0xC0]);
+        assert!(cpu.run_until_write(0xC000, 1000));
+        assert_eq!(cpu.pc(), 0x0105);
+        assert_eq!(cpu.peek(0xC000), 0x42);
+
+        assert!(!cpu.run_until_write(0xC000, 1000));
+    }
+
+    #[test]
+    fn state_hash_is_deterministic() {
+        // INC A; JR -3
+        let program = [0x3C, 0x18, 0xFD];
+        let mut a = cpu_with_program(&program);
+        let mut b = cpu_with_program(&program);
+        a.run_frames(2);
+        b.run_frames(2);
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        b.poke(0xC000, b.peek(0xC000).wrapping_add(1));
+        assert_ne!(a.state_hash(), b.state_hash());
+    }
+
+    fn cpu_for_model(model: Model) -> CPU {
+        CPU::new_with_config(vec![0x00; 0x8000], EmulatorConfig::new().model(model).audio(false))
+    }
+
+    #[test]
+    fn boot_values_set_af_per_model() {
+        assert_eq!(cpu_for_model(Model::DMG).reg.get_af(), 0x01B0);
+        assert_eq!(cpu_for_model(Model::CGB).reg.get_af(), 0x1180);
+    }
+
+    #[test]
+    fn step_instruction_runs_one_instruction() {
+        // NOP; LD A, $42; JP $0100
+        let mut cpu = cpu_with_program(&[0x00, 0x3E, 0x42, 0xC3, 0x00, 0x01]);
+        for (pc, cycles) in [(0x0101, 4), (0x0103, 8), (0x0100, 16)] {
+            let start = cpu.mem.total_cycles();
+            assert_eq!(cpu.step_instruction(), cycles);
+            assert_eq!(cpu.pc(), pc);
+            assert_eq!(cpu.mem.total_cycles() - start, cycles as u64);
+        }
+        assert_eq!(cpu.reg.a, 0x42);
+    }
+
+    #[test]
+    fn pausing_fades_the_audio_out() {
+        // JR -2
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        // Channel 1 at 512 Hz, panned to both sides
+        for (a, v) in [(0xFF25, 0xFF), (0xFF11, 0x80), (0xFF12, 0xF0), (0xFF13, 0x00), (0xFF14, 0x87)] {
+            cpu.mem.write(a, v);
+        }
+        cpu.run_frames(2);
+        cpu.pause();
+
+        let mut samples = vec![(0.0, 0.0); 8192];
+        let n = cpu.mem.apu.drain_samples(&mut samples);
+        assert_eq!(n, cpu.mem.apu.sample_rate() as usize / 10);
+
+        let peak = |frames: &[(f64, f64)]| frames.iter().fold(0.0_f64, |m, f| m.max(f.0.abs()));
+        let start = peak(&samples[..n / 10]);
+        let end = peak(&samples[n - n / 10..n]);
+        assert!(start > 0.01);
+        assert!(end < start / 10.0);
+    }
+
+    #[test]
+    fn trace_prefixes_switchable_rom_with_the_bank() {
+        // 128KB MBC1 cart
+        let mut rom = vec![0x00; 0x20000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x02;
+        let mut cpu = CPU::new_with_config(rom, EmulatorConfig::new().audio(false));
+
+        assert_eq!(cpu.format_address(0x0150), "0150");
+        assert_eq!(cpu.format_address(0x4000), "01:4000");
+
+        cpu.poke(0x2000, 0x03);
+        cpu.reg.pc = 0x4123;
+        assert_eq!(cpu.format_address(0x7FFF), "03:7FFF");
+        assert!(cpu.trace().starts_with("03:4123 "));
+        assert_eq!(cpu.format_address(0xC000), "C000");
+    }
+
+    // Dispatches an interrupt from PC=$0200 with SP=$0000, so the PC high byte ($02)
+    // is pushed onto IE
+    fn dispatch_onto_ie(intf: u8) -> CPU {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.reg.pc = 0x0200;
+        cpu.reg.sp = 0x0000;
+        cpu.ime = true;
+        cpu.poke(0xFFFF, Interrupts::V_BLANK.bits());
+        cpu.poke(0xFF0F, intf);
+        cpu.step_instruction();
+        cpu
+    }
+
+    #[test]
+    fn ie_push_can_redirect_the_interrupt() {
+        let cpu = dispatch_onto_ie((Interrupts::V_BLANK | Interrupts::LCD).bits());
+        assert_eq!(cpu.pc(), 0x0048);
+        assert_eq!(cpu.peek(0xFFFF), 0x02);
+        assert_eq!(cpu.peek(0xFF0F) & 0x1F, Interrupts::V_BLANK.bits());
+    }
+
+    #[test]
+    fn ie_push_can_cancel_the_interrupt() {
+        let cpu = dispatch_onto_ie(Interrupts::V_BLANK.bits());
+        assert_eq!(cpu.pc(), 0x0000);
+        assert_eq!(cpu.peek(0xFF0F) & 0x1F, Interrupts::V_BLANK.bits());
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn agb_boots_with_b_bit_0_set() {
+        let agb = cpu_for_model(Model::AGB);
+        assert_eq!(agb.reg.a, 0x11);
+        assert_eq!(agb.reg.b, 0x01);
+        assert_eq!(cpu_for_model(Model::CGB).reg.b, 0x00);
+    }
+
+    #[test]
+    fn input_scripts_press_buttons_on_their_frame() {
+        // JR -2
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        // Select the action buttons
+        cpu.poke(0xFF00, 0x10);
+
+        let mut script = InputScript::new();
+        script.push(4, JoypadButton::START, false);
+        script.push(2, JoypadButton::START, true);
+        cpu.load_input_script(script);
+
+        cpu.run_frames(2);
+        assert_eq!(cpu.peek(0xFF00) & 0x0F, 0x0F);
+        cpu.run_frames(1);
+        assert_eq!(cpu.peek(0xFF00) & 0x0F, 0x0F & !JoypadButton::START.bits());
+        cpu.run_frames(1);
+        assert_eq!(cpu.peek(0xFF00) & 0x0F, 0x0F & !JoypadButton::START.bits());
+        cpu.run_frames(1);
+        assert_eq!(cpu.peek(0xFF00) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn frames_take_about_70224_cycles() {
+        // JR -2
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        cpu.poke(0xFF40, 0x91);
+        cpu.run_frames(3);
+
+        // Frames end on an instruction boundary, so allow one instruction either way
+        let cycles = cpu.last_frame_cycles();
+        assert_eq!(cpu.target_frame_cycles(), 70224);
+        assert!(cycles.abs_diff(70224) <= 12, "{} cycles", cycles);
+    }
+
+    // An MBC3+TIMER+RAM+BATTERY cart latching the clock in a loop, seeded with `div_seed`
+    fn seeded_rtc_cpu(div_seed: u16) -> CPU {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x02;
+        // LD A, $00; LD ($6000), A; INC A; LD ($6000), A; JR -11
+        let program = [0x3E, 0x00, 0xEA, 0x00, 0x60, 0x3C, 0xEA, 0x00, 0x60, 0x18, 0xF5];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        let mut cpu = CPU::new_with_config(rom, EmulatorConfig::new().audio(false));
+        cpu.set_deterministic(div_seed);
+        cpu
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let mut a = seeded_rtc_cpu(0x1234);
+        let mut b = seeded_rtc_cpu(0x1234);
+        assert_eq!(a.peek(0xFF04), 0x12);
+        a.run_frames(5);
+        b.run_frames(5);
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        // The latched clock never picks up host time
+        a.poke(0x0000, 0x0A);
+        a.poke(0x4000, 0x08);
+        assert_eq!(a.peek(0xA000), 0x00);
+
+        let mut c = seeded_rtc_cpu(0x4321);
+        c.run_frames(5);
+        assert_ne!(b.state_hash(), c.state_hash());
+    }
+
+    #[test]
+    fn if_reads_its_upper_bits_as_1_and_manual_writes_are_serviced() {
+        // LD A, $04; LDH ($0F), A; LDH A, ($0F)
+        let mut cpu = cpu_with_program(&[0x3E, 0x04, 0xE0, 0x0F, 0xF0, 0x0F]);
+        cpu.poke(0xFFFF, 0x00);
+        cpu.poke(0xFF0F, 0x00);
+        for _ in 0..3 {
+            cpu.step_instruction();
+        }
+        assert_eq!(cpu.reg.a, 0xE4);
+        assert_eq!(cpu.pc(), 0x0106);
+
+        cpu.ime = true;
+        cpu.poke(0xFFFF, Interrupts::TIMER.bits());
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0x0050);
+        assert_eq!(cpu.peek(0xFF0F), 0xE0);
+    }
+
+    #[test]
+    fn inserting_a_cartridge_restarts_on_the_new_one() {
+        // JR -2
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        cpu.poke(0xFF40, 0x91);
+        cpu.poke(0xC000, 0x42);
+        cpu.run_frames(2);
+        assert_eq!(cpu.peek(0x0000), 0x00);
+
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0000] = 0xAA;
+        cpu.insert_cartridge(Box::new(ROMOnly::new(rom)));
+        assert_eq!(cpu.pc(), 0x0100);
+        assert_eq!(cpu.peek(0x0000), 0xAA);
+        assert_eq!(cpu.peek(0xC000), 0x00);
+        assert_eq!(cpu.peek(0xFF44), 0x00);
+
+        // NOPs to the end of the ROM
+        cpu.run_frames(1);
+        assert!(cpu.pc() > 0x0100);
+    }
+
+    #[test]
+    fn default_palette_follows_the_model() {
+        let white = |cpu: &CPU| cpu.mem.ppu.dump_palettes().bg[0][0];
+        assert_eq!(white(&cpu_for_model(Model::DMG)), DmgPalette::Green.colors()[0]);
+        assert_eq!(white(&cpu_for_model(Model::MGB)), DmgPalette::Grayscale.colors()[0]);
+
+        let config = EmulatorConfig::new().model(Model::MGB).palette(DmgPalette::Kirokaze).audio(false);
+        let cpu = CPU::new_with_config(vec![0x00; 0x8000], config);
+        assert_eq!(white(&cpu), DmgPalette::Kirokaze.colors()[0]);
+    }
+
+    #[test]
+    fn skip_boot_runs_the_boot_rom_until_it_unmaps() {
+        // LD A, $01; LDH ($50), A
+        let mut boot_rom = vec![0x00; 0x100];
+        boot_rom[..4].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0000] = 0xAA;
+        let mut cpu = CPU::new_with_config(rom, EmulatorConfig::new().boot_rom(boot_rom).audio(false));
+        assert_eq!(cpu.peek(0x0000), 0x3E);
+
+        cpu.skip_boot();
+        assert!(!cpu.mem.boot_rom_mapped());
+        assert_eq!(cpu.peek(0x0000), 0xAA);
+        assert_eq!(cpu.pc(), 0x0004);
+
+        // Nothing left to skip
+        let mut cpu = cpu_with_program(&[]);
+        cpu.skip_boot();
+        assert_eq!(cpu.pc(), 0x0100);
+    }
+
+    #[test]
+    fn runs_frames_without_an_audio_device() {
+        // JR -2
+        let mut cpu = cpu_with_program(&[0x18, 0xFE]);
+        cpu.poke(0xFF40, 0x91);
+        cpu.poke(0xFF12, 0xF0);
+        cpu.poke(0xFF14, 0x80);
+        cpu.run_frames(5);
+
+        // The channels keep running and rendering, nothing plays them
+        assert_eq!(cpu.peek(0xFF26) & 0x01, 0x01);
+        assert!(cpu.mem.apu.buffered_samples() > 0);
+        assert_eq!(cpu.mem.apu.samples_played(), 0);
+    }
+
+    #[test]
+    fn frames_count_the_samples_rendered_in_them() {
+        let run = || {
+            // JR -2
+            let mut rom = vec![0x00; 0x8000];
+            rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+            let config = EmulatorConfig::new().sample_rate(48000).audio(false).deterministic(0);
+            let mut cpu = CPU::new_with_config(rom, config);
+            cpu.poke(0xFF40, 0x91);
+            // Starts partway into a frame
+            cpu.run_frames(1);
+            let mut out = vec![(0.0, 0.0); 4096];
+            cpu.mem.apu.drain_samples(&mut out);
+
+            (0..60).map(|_| {
+                cpu.run_frames(1);
+                let drained = cpu.mem.apu.drain_samples(&mut out);
+                assert_eq!(cpu.last_frame_samples() as usize, drained);
+                cpu.last_frame_samples()
+            }).collect::<Vec<_>>()
+        };
+
+        let counts = run();
+        // 803.65 samples a frame at 48 kHz
+        assert!(counts.iter().all(|&n| (802..=805).contains(&n)), "{:?}", counts);
+        let total: u64 = counts.iter().map(|&n| n as u64).sum();
+        assert!(total.abs_diff(60 * 70224 * 48000 / 4_194_304) <= 1, "{}", total);
+        assert_eq!(counts, run());
+    }
+
+    #[test]
+    fn cartridge_ram_edits_are_seen_by_the_game() {
+        // LD A, $0A; LD ($0000), A; LD A, ($A010); LD ($A011), A
+        let program = [0x3E, 0x0A, 0xEA, 0x00, 0x00, 0xFA, 0x10, 0xA0, 0xEA, 0x11, 0xA0];
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        // MBC1 with 8KB of battery-backed RAM
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let mut cpu = CPU::new_with_config(rom, EmulatorConfig::new().audio(false));
+
+        cpu.cartridge_ram_mut().unwrap()[0x10] = 0x42;
+        for _ in 0..4 {
+            cpu.step_instruction();
+        }
+        assert_eq!(cpu.reg.a, 0x42);
+        assert_eq!(cpu.cartridge_ram().unwrap()[0x11], 0x42);
+
+        let mut rom_only = cpu_with_program(&[]);
+        assert!(rom_only.cartridge_ram().is_none());
+        assert!(rom_only.cartridge_ram_mut().is_none());
+    }
+
+    #[test]
+    fn oam_dma_started_by_ldh_finishes_161_m_cycles_after_the_write() {
+        // JP $FF80, running from HRAM like games do since DMA takes the rest of the bus
+        let mut cpu = cpu_with_program(&[0xC3, 0x80, 0xFF]);
+        // LDH ($46), A; JR -2
+        for (i, &v) in [0xE0, 0x46, 0x18, 0xFE].iter().enumerate() {
+            cpu.poke(0xFF80 + i as u16, v);
+        }
+        for i in 0..0xA0 {
+            cpu.poke(0xC000 + i, i as u8 ^ 0x5A);
+            cpu.poke(0xFE00 + i, 0xFF);
+        }
+        cpu.reg.a = 0xC0;
+        cpu.step_instruction();
+        assert_eq!(cpu.pc(), 0xFF80);
+        cpu.step_instruction();
+        let copied = |cpu: &CPU| (0..0xA0).take_while(|&i| cpu.peek(0xFE00 + i) == i as u8 ^ 0x5A).count();
+        assert_eq!(copied(&cpu), 0);
+
+        // One setup M-cycle after the write, then a byte per M-cycle
+        let mut m_cycles = 0;
+        while m_cycles < 170 {
+            m_cycles += cpu.step_instruction() as usize / 4;
+            assert_eq!(copied(&cpu), m_cycles.saturating_sub(1).min(160), "{} M-cycles", m_cycles);
+            assert_eq!(cpu.mem.ppu.oam_dma_active, (1..161).contains(&m_cycles), "{} M-cycles", m_cycles);
+        }
+    }
+
+    #[test]
+    fn dmg_only_carts_on_cgb_start_in_compatibility_mode() {
+        let cgb = |cgb_flag: u8| {
+            let mut rom = vec![0x00; 0x8000];
+            rom[0x0143] = cgb_flag;
+            CPU::new_with_config(rom, EmulatorConfig::new().model(Model::CGB).audio(false))
+        };
+
+        let dmg_only = cgb(0x00);
+        assert!(dmg_only.mem.ppu.dmg_compat);
+        // Right+A: a red OBJ palette 0
+        assert_eq!(dmg_only.mem.ppu.dump_palettes().obj[0][1], (0xFF, 0x84, 0x84));
+        assert!(!cgb(0x80).mem.ppu.dmg_compat);
+        assert!(!cpu_for_model(Model::DMG).mem.ppu.dmg_compat);
+
+        // A DMG-only cart inserted later is set up the same way
+        let mut cpu = cgb(0x80);
+        cpu.insert_cartridge(Box::new(ROMOnly::new(vec![0x00; 0x8000])));
+        assert!(cpu.mem.ppu.dmg_compat);
+    }
+}
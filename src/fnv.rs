@@ -0,0 +1,23 @@
+use std::hash::Hasher;
+
+// 64-bit FNV-1a, stable across runs and platforms unlike DefaultHasher
+pub struct FnvHasher(u64);
+
+impl FnvHasher {
+    pub fn new() -> Self {
+        Self(0xCBF2_9CE4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::io;
+use crate::config::EmulatorConfig;
+use crate::cpu::CPU;
+use crate::mbc::loader;
+use crate::mbc::mode::MBCMode;
+use crate::memory::Memory;
+use crate::mode::{GBMode, Model};
+use crate::timing::FRAME_DOTS;
+
+// The header is followed by the driver code and music data, loaded at load_address
+const HEADER_SIZE: usize = 0x70;
+// INIT and PLAY return here, to a HALT loop that waits out the rest of the play period
+const RETURN_ADDRESS: u16 = 0x0080;
+// Generous limit on how long INIT or PLAY may run before giving up on them
+const ROUTINE_MAX_CYCLES: u64 = FRAME_DOTS as u64 * 600;
+
+// https://ocremix.org/info/GBS_Format_Specification
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    // 1-based, as stored in the file
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < HEADER_SIZE || &data[0x00..0x03] != b"GBS" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a GBS file!"));
+        }
+
+        let word = |i: usize| (data[i + 1] as u16) << 8 | data[i] as u16;
+        let text = |i: usize| {
+            let field = &data[i..i + 0x20];
+            let end = field.iter().position(|&c| c == 0x00).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+
+        let header = Self {
+            version: data[0x03],
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50)
+        };
+
+        // The stubs below $0400 would be overwritten otherwise
+        if header.load_address < 0x0400 || header.load_address >= 0x8000 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid GBS load address ({:#06x})!", header.load_address)));
+        }
+
+        Ok(header)
+    }
+
+    // Bit 7 of TAC asks for the CPU to run in CGB double speed
+    pub fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+
+    // PLAY is called from the timer interrupt if TAC enables the timer, otherwise at VBlank.
+    // In CPU cycles, so in double speed the timer plays twice as fast while VBlank keeps its rate.
+    pub fn play_period(&self) -> u64 {
+        if self.timer_control & 0x04 != 0 {
+            let divider = match self.timer_control & 0x03 {
+                0 => 1024,
+                1 => 16,
+                2 => 64,
+                _ => 256,
+            };
+            divider * (256 - self.timer_modulo as u64)
+        } else if self.double_speed() {
+            FRAME_DOTS as u64 * 2
+        } else {
+            FRAME_DOTS as u64
+        }
+    }
+}
+
+// Plays a GBS rip by running its driver on an otherwise empty MBC5 cartridge
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    rom: Vec<u8>,
+    pub cpu: CPU,
+    song: u8,
+    // Set by select_song, INIT is run by the next step_frame
+    init_pending: bool
+}
+
+impl GbsPlayer {
+    pub fn new(data: &[u8], config: EmulatorConfig) -> io::Result<Self> {
+        let header = GbsHeader::parse(data)?;
+        let rom = Self::build_rom(&header, &data[HEADER_SIZE..]);
+        // Only a CGB can switch to double speed
+        let config = if header.double_speed() && config.model.mode() != GBMode::Color {
+            config.model(Model::CGB)
+        } else {
+            config
+        };
+        let cpu = CPU::new_with_config(rom.clone(), config.mbc_mode(MBCMode::MBC5));
+
+        let mut player = Self {
+            header,
+            rom,
+            cpu,
+            song: 0,
+            init_pending: false
+        };
+        player.select_song(player.header.first_song.saturating_sub(1));
+        Ok(player)
+    }
+
+    fn build_rom(header: &GbsHeader, code: &[u8]) -> Vec<u8> {
+        let load = header.load_address as usize;
+        let size = (load + code.len()).next_power_of_two().max(0x8000);
+        let mut rom = vec![0xFF; size];
+        rom[load..load + code.len()].copy_from_slice(code);
+
+        // RST vectors jump to the same offset from the load address
+        for rst in (0x00..0x40).step_by(8) {
+            let target = header.load_address + rst as u16;
+            rom[rst..rst + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+        }
+        // PLAY is driven directly, so any interrupt the driver enables just returns
+        for vector in (0x40..=0x60).step_by(8) {
+            rom[vector] = 0xD9;
+        }
+        // HALT, JR -3
+        let ret = RETURN_ADDRESS as usize;
+        rom[ret..ret + 3].copy_from_slice(&[0x76, 0x18, 0xFD]);
+
+        // MBC5 with 8KB of RAM, which drivers are allowed to use
+        rom[0x0147] = 0x1A;
+        rom[0x0148] = (size / 0x8000).trailing_zeros() as u8;
+        rom[0x0149] = 0x02;
+        rom
+    }
+
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    // Resets the machine and sets the CPU up to call INIT for `song` (0-based),
+    // the next step_frame runs it before the first PLAY
+    pub fn select_song(&mut self, song: u8) {
+        self.song = song.min(self.header.song_count.saturating_sub(1));
+        self.cpu.insert_cartridge(loader::new_mbc(MBCMode::MBC5, self.rom.clone()));
+
+        self.cpu.mem.write(0x0000, 0x0A);
+        self.cpu.mem.write(0x2000, 0x01);
+        self.cpu.mem.write(0xFF06, self.header.timer_modulo);
+        self.cpu.mem.write(0xFF07, self.header.timer_control);
+        if self.header.double_speed() {
+            self.cpu.mem.write(0xFF4D, 0x01);
+            self.cpu.mem.switch_speed();
+        }
+
+        self.cpu.set_sp(self.header.stack_pointer);
+        self.cpu.call_routine(self.header.init_address, RETURN_ADDRESS, self.song);
+        self.init_pending = true;
+    }
+
+    // Calls PLAY once and runs until the next call is due. A PLAY that overruns its
+    // period is allowed to finish, pushing the next call back. Returns false if INIT or
+    // PLAY didn't return within ROUTINE_MAX_CYCLES.
+    pub fn step_frame(&mut self) -> bool {
+        if self.init_pending {
+            self.init_pending = false;
+            if !self.cpu.run_until_pc(RETURN_ADDRESS, ROUTINE_MAX_CYCLES) {
+                return false;
+            }
+        }
+
+        let period = self.header.play_period();
+        self.cpu.call_routine(self.header.play_address, RETURN_ADDRESS, self.song);
+
+        let mut elapsed = 0;
+        loop {
+            let returned = (RETURN_ADDRESS..RETURN_ADDRESS + 3).contains(&self.cpu.pc());
+            if elapsed >= period && returned {
+                return true;
+            }
+            if elapsed >= ROUTINE_MAX_CYCLES {
+                return false;
+            }
+            elapsed += self.cpu.step_instruction() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three songs loaded at $0400. INIT stores the song number at $C000,
+    // PLAY counts its calls at $C001.
+    fn gbs() -> Vec<u8> {
+        let mut data = vec![0x00; HEADER_SIZE];
+        data[0x00..0x04].copy_from_slice(b"GBS\x01");
+        data[0x04] = 3;
+        data[0x05] = 2;
+        data[0x06..0x0E].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x04, 0x04, 0xFE, 0xFF]);
+        data[0x10..0x15].copy_from_slice(b"Title");
+        data[0x30..0x36].copy_from_slice(b"Author");
+        // LD ($C000), A; RET
+        data.extend_from_slice(&[0xEA, 0x00, 0xC0, 0xC9]);
+        // LD HL, $C001; INC (HL); RET
+        data.extend_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+        data
+    }
+
+    fn player() -> GbsPlayer {
+        GbsPlayer::new(&gbs(), EmulatorConfig::new().audio(false)).unwrap()
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let header = GbsHeader::parse(&gbs()).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.first_song, 2);
+        assert_eq!(header.load_address, 0x0400);
+        assert_eq!(header.init_address, 0x0400);
+        assert_eq!(header.play_address, 0x0404);
+        assert_eq!(header.stack_pointer, 0xFFFE);
+        assert_eq!(header.title, "Title");
+        assert_eq!(header.author, "Author");
+        assert_eq!(header.copyright, "");
+        assert_eq!(header.play_period(), FRAME_DOTS as u64);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut data = gbs();
+        data[0x00] = b'X';
+        assert!(GbsHeader::parse(&data).is_err());
+        assert!(GbsHeader::parse(&gbs()[..HEADER_SIZE - 1]).is_err());
+
+        // Over the stubs at the start of the ROM
+        let mut data = gbs();
+        data[0x06..0x08].copy_from_slice(&[0x00, 0x02]);
+        assert!(GbsHeader::parse(&data).is_err());
+    }
+
+    #[test]
+    fn timer_driven_rips_play_at_the_timer_rate() {
+        let mut data = gbs();
+        // 4096 Hz timer, overflowing every 64 ticks
+        data[0x0E] = 0xC0;
+        data[0x0F] = 0x04;
+        assert_eq!(GbsHeader::parse(&data).unwrap().play_period(), 1024 * 64);
+    }
+
+    #[test]
+    fn select_song_sets_the_cpu_up_at_init() {
+        let mut player = player();
+        // The first song in the header is 1-based
+        assert_eq!(player.song(), 1);
+        assert_eq!(player.cpu.pc(), 0x0400);
+        // Returning to the HALT loop
+        assert_eq!(player.cpu.peek(0xFFFC), RETURN_ADDRESS as u8);
+        assert_eq!(player.cpu.peek(0xFFFD), (RETURN_ADDRESS >> 8) as u8);
+        assert_eq!(player.cpu.peek(0xC000), 0x00);
+
+        assert!(player.step_frame());
+        assert_eq!(player.cpu.peek(0xC000), 1);
+        assert_eq!(player.cpu.peek(0xC001), 1);
+        assert!(player.step_frame());
+        assert_eq!(player.cpu.peek(0xC001), 2);
+
+        player.select_song(7);
+        assert_eq!(player.song(), 2);
+        assert_eq!(player.cpu.pc(), 0x0400);
+        assert_eq!(player.cpu.peek(0xC001), 0);
+        assert!(player.step_frame());
+        assert_eq!(player.cpu.peek(0xC000), 2);
+        assert_eq!(player.cpu.peek(0xC001), 1);
+    }
+
+    #[test]
+    fn double_speed_rips_run_the_cpu_in_double_speed() {
+        let mut data = gbs();
+        data[0x0F] = 0x80;
+        let mut player = GbsPlayer::new(&data, EmulatorConfig::new().audio(false)).unwrap();
+        assert!(player.cpu.mem.double_speed());
+        // VBlank still comes at the same rate, which is twice as many CPU cycles
+        assert_eq!(player.header.play_period(), FRAME_DOTS as u64 * 2);
+        assert!(player.step_frame());
+        assert_eq!(player.cpu.peek(0xC001), 1);
+
+        // Timer periods are counted in CPU cycles, so those rips play twice as fast
+        data[0x0F] = 0x84;
+        assert_eq!(GbsHeader::parse(&data).unwrap().play_period(), 1024 * 256);
+    }
+}
@@ -0,0 +1,127 @@
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::memory::Memory;
+
+// Something on the other side of the CGB's IR port, e.g. a second emulator instance
+pub trait InfraredLink: Send {
+    // Whether the other side's LED is currently on
+    fn receiving(&self) -> bool;
+    fn set_emitting(&mut self, on: bool);
+}
+
+// One end of a pair of ports facing each other, each sees the LED the other end drives
+pub struct PairedLink {
+    local: Arc<AtomicBool>,
+    remote: Arc<AtomicBool>
+}
+
+impl PairedLink {
+    pub fn pair() -> (PairedLink, PairedLink) {
+        let a = Arc::new(AtomicBool::new(false));
+        let b = Arc::new(AtomicBool::new(false));
+        (
+            PairedLink { local: a.clone(), remote: b.clone() },
+            PairedLink { local: b, remote: a }
+        )
+    }
+}
+
+impl InfraredLink for PairedLink {
+    fn receiving(&self) -> bool {
+        self.remote.load(Ordering::Relaxed)
+    }
+
+    fn set_emitting(&mut self, on: bool) {
+        self.local.store(on, Ordering::Relaxed);
+    }
+}
+
+// RP ($FF56), CGB only
+pub struct Infrared {
+    emitting: bool,
+    // Bits 6-7, both have to be set for the receiver to work
+    read_enable: u8,
+    link: Option<Box<dyn InfraredLink>>
+}
+
+impl Infrared {
+    pub fn new() -> Self {
+        Self {
+            emitting: false,
+            read_enable: 0,
+            link: None
+        }
+    }
+
+    pub fn attach_link(&mut self, mut link: Box<dyn InfraredLink>) {
+        link.set_emitting(self.emitting);
+        self.link = Some(link);
+    }
+
+    fn signal(&self) -> bool {
+        self.read_enable == 0b11 && self.link.as_ref().is_some_and(|l| l.receiving())
+    }
+}
+
+impl Hash for Infrared {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.emitting.hash(state);
+        self.read_enable.hash(state);
+    }
+}
+
+impl Memory for Infrared {
+    fn read(&self, a: u16) -> u8 {
+        match a {
+            // Bit 1 is 0 while a signal is received, bits 2-5 are unused
+            0xFF56 => self.read_enable << 6 | 0x3C | (!self.signal() as u8) << 1 | self.emitting as u8,
+            _ => panic!("Read to unsupported Infrared address ({:#06x})!", a),
+        }
+    }
+
+    fn write(&mut self, a: u16, v: u8) {
+        match a {
+            0xFF56 => {
+                self.emitting = v & 0x01 != 0;
+                self.read_enable = v >> 6;
+                let emitting = self.emitting;
+                if let Some(link) = self.link.as_mut() {
+                    link.set_emitting(emitting);
+                }
+            },
+            _ => panic!("Write to unsupported Infrared address ({:#06x})!", a),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paired_ports_see_each_others_led() {
+        let (a_link, b_link) = PairedLink::pair();
+        let mut a = Infrared::new();
+        let mut b = Infrared::new();
+        a.attach_link(Box::new(a_link));
+        b.attach_link(Box::new(b_link));
+
+        // Nothing is received until the read enable bits are set
+        a.write(0xFF56, 0x01);
+        assert_eq!(b.read(0xFF56) & 0x02, 0x02);
+        b.write(0xFF56, 0xC0);
+        assert_eq!(b.read(0xFF56), 0xFC);
+        assert_eq!(a.read(0xFF56), 0x3F);
+
+        a.write(0xFF56, 0x00);
+        assert_eq!(b.read(0xFF56), 0xFE);
+
+        // The other direction, with the receiver's own LED on as well
+        a.write(0xFF56, 0xC0);
+        b.write(0xFF56, 0xC1);
+        assert_eq!(a.read(0xFF56), 0xFC);
+        assert_eq!(b.read(0xFF56), 0xFF);
+    }
+}
@@ -0,0 +1,31 @@
+use crate::joypad::JoypadButton;
+
+// Button presses and releases keyed by frame number, for reproducible runs
+pub struct InputScript {
+    events: Vec<(u64, JoypadButton, bool)>,
+    next: usize
+}
+
+impl InputScript {
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            next: 0
+        }
+    }
+
+    // Events can be pushed in any order, they're applied in frame order
+    pub fn push(&mut self, frame: u64, button: JoypadButton, pressed: bool) {
+        self.events.push((frame, button, pressed));
+        self.events.sort_by_key(|&(frame, _, _)| frame);
+    }
+
+    // Returns the events due on or before `frame` that haven't been applied yet
+    pub fn take_due(&mut self, frame: u64) -> &[(u64, JoypadButton, bool)] {
+        let start = self.next;
+        while self.next < self.events.len() && self.events[self.next].0 <= frame {
+            self.next += 1;
+        }
+        &self.events[start..self.next]
+    }
+}
\ No newline at end of file
@@ -1,3 +1,4 @@
+use std::hash::{Hash, Hasher};
 use bitflags::bitflags;
 use crate::memory::Memory;
 use crate::mmu::Interrupts;
@@ -20,19 +21,81 @@ pub struct Joypad {
     matrix: u8,
     select: u8,
     previous_select: u8,
+    sgb: SGBPacketReader,
     pub interrupts: Interrupts
 }
 
+// Super Game Boy packets are sent by pulsing P14/P15 on $FF00:
+// both low resets, P14 low sends a 0, P15 low sends a 1, and both high
+// separates bits. 128 bits are sent LSB first, followed by a 0 stop bit.
+struct SGBPacketReader {
+    receiving: bool,
+    previous_lines: u8,
+    bit_index: usize,
+    packet: [u8; 16],
+    callback: Option<Box<dyn FnMut([u8; 16]) + Send>>
+}
+
+impl SGBPacketReader {
+    fn new() -> Self {
+        Self {
+            receiving: false,
+            previous_lines: 0x30,
+            bit_index: 0,
+            packet: [0; 16],
+            callback: None
+        }
+    }
+
+    fn write(&mut self, lines: u8) {
+        let previous = self.previous_lines;
+        self.previous_lines = lines;
+
+        if lines == 0x00 {
+            self.receiving = true;
+            self.bit_index = 0;
+            self.packet = [0; 16];
+            return;
+        }
+
+        // Only count each pulse once, on the edge out of the idle state
+        if !self.receiving || previous != 0x30 || lines == 0x30 {
+            return;
+        }
+
+        let bit = lines == 0x10;
+        if self.bit_index < 128 {
+            if bit {
+                self.packet[self.bit_index / 8] |= 1 << (self.bit_index % 8);
+            }
+            self.bit_index += 1;
+        } else {
+            self.receiving = false;
+            if !bit {
+                if let Some(callback) = self.callback.as_mut() {
+                    callback(self.packet);
+                }
+            }
+        }
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Self {
             matrix: 0xFF,
             select: 0x0F,
             previous_select: 0x0F,
+            sgb: SGBPacketReader::new(),
             interrupts: Interrupts::empty()
         }
     }
 
+    // Called with every completed SGB command packet, byte 0 holds the command and length
+    pub fn set_sgb_callback(&mut self, callback: Box<dyn FnMut([u8; 16]) + Send>) {
+        self.sgb.callback = Some(callback);
+    }
+
     pub fn down(&mut self, button: JoypadButton) {
         self.matrix &= !button.bits();
         self.update_joypad();
@@ -53,6 +116,15 @@ impl Joypad {
     }
 }
 
+impl Hash for Joypad {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.matrix.hash(state);
+        self.select.hash(state);
+        self.previous_select.hash(state);
+        self.interrupts.hash(state);
+    }
+}
+
 impl Memory for Joypad {
     fn read(&self, a: u16) -> u8 {
         match a {
@@ -73,10 +145,48 @@ impl Memory for Joypad {
 
     fn write(&mut self, a: u16, v: u8) {
         match a {
-            0xFF00 => self.select = (v & 0x30),
+            0xFF00 => {
+                self.select = (v & 0x30);
+                self.sgb.write(self.select);
+            },
             _ => panic!("Write to unsupported Joypad address ({:#06x})!", a),
         }
 
         self.update_joypad();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Pulses one packet onto P14/P15 the way the SGB boot code does
+    fn send_packet(joypad: &mut Joypad, packet: &[u8; 16]) {
+        joypad.write(0xFF00, 0x00);
+        joypad.write(0xFF00, 0x30);
+        let bits = (0..128).map(|i| packet[i / 8] >> (i % 8) & 0x01 != 0);
+        for bit in bits.chain(std::iter::once(false)) {
+            joypad.write(0xFF00, if bit { 0x10 } else { 0x20 });
+            joypad.write(0xFF00, 0x30);
+        }
+    }
+
+    #[test]
+    fn sgb_packets_are_decoded() {
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let mut joypad = Joypad::new();
+        let received = packets.clone();
+        joypad.set_sgb_callback(Box::new(move |packet| received.lock().unwrap().push(packet)));
+
+        // PAL01 with a few colours
+        let mut packet = [0x00; 16];
+        packet[0] = 0x01;
+        for (i, b) in packet.iter_mut().enumerate().skip(1) {
+            *b = (i as u8).wrapping_mul(0x1D);
+        }
+        send_packet(&mut joypad, &packet);
+
+        assert_eq!(*packets.lock().unwrap(), vec![packet]);
+    }
+}
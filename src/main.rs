@@ -2,12 +2,17 @@
 extern crate num_derive;
 
 use crate::context::Context;
+use crate::config::EmulatorConfig;
 use crate::cpu::CPU;
-use crate::mode::GBMode;
-use crate::mbc::mode::{CartTypes, MBCMode};
+use crate::mode::{AudioPacer, ClockSpeed, Model, SyncMode};
+use crate::mbc::mode::CartTypes;
+use crate::mbc::loader;
+use crate::mbc::header::CartridgeHeader;
+use crate::mbc::save::Autosave;
 use clap::Parser;
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use tokio::time::{Duration, Instant, sleep};
@@ -19,67 +24,122 @@ use winit::{event_loop::EventLoop, window::WindowBuilder};
 use winit::event_loop::ControlFlow;
 use num_traits::FromPrimitive;
 use crate::joypad::JoypadButton;
+use crate::ppu::DmgPalette;
 
 mod context;
+mod config;
+mod boot;
 mod cpu;
 mod mmu;
 mod mode;
 mod registers;
 mod ppu;
+mod scale2x;
 mod serial;
+mod infrared;
+mod printer;
+mod fnv;
+mod gbs;
+mod input_script;
 mod timer;
+mod timing;
 mod mbc;
 mod memory;
 mod joypad;
 mod sound;
 
-pub const CLOCK_FREQUENCY: u32 = 4_194_304;
 pub const STEP_TIME: u32 = 16;
-// STEP_CYCLES = 67108
-pub const STEP_CYCLES: u32 = (STEP_TIME as f64 / (1000_f64 / CLOCK_FREQUENCY as f64)) as u32;
+// How far emulation may run ahead of the audio device in SyncMode::Audio
+pub const AUDIO_LATENCY: f64 = 0.05;
 
 #[derive(Parser)]
 struct Args {
     rom_path: String,
     boot_rom: Option<String>,
     #[arg(short, long)]
-    print_serial: bool
+    print_serial: bool,
+    #[arg(long)]
+    printer: bool,
+    // Defaults to the selected model's LCD tint
+    #[arg(long)]
+    palette: Option<u8>,
+    #[arg(long)]
+    sgb_timing: bool,
+    #[arg(long, default_value_t = 0)]
+    model: u8,
+    #[arg(long)]
+    audio_sync: bool,
+    #[arg(long)]
+    skip_boot: bool,
+    // Defaults to the output device's preferred rate
+    #[arg(long)]
+    sample_rate: Option<u32>,
+    #[arg(long)]
+    no_audio: bool,
+    // Smooths the image with Scale2x, doubling its size
+    #[arg(long)]
+    upscale: bool,
+    // Seeds DIV and the RTC so runs are reproducible
+    #[arg(long)]
+    seed: Option<u16>
 }
 
 #[tokio::main]
 async fn main() -> Result<(), impl std::error::Error> {
     let args = Args::parse();
-    let mut file = File::open(args.rom_path).expect("No ROM found!");
+    let mut file = File::open(&args.rom_path).expect("No ROM found!");
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer).expect("Failed to read ROM!");
 
-    let cart_type: CartTypes = FromPrimitive::from_u8(buffer[0x0147]).expect("Failed to get Cart Type!");
-    let mbc_mode = match cart_type.get_mbc() {
-        MBCMode::Unsupported => panic!("Unsupported Cart Type! {:}", cart_type),
-        v => {
-            println!("Cart Type: {:}, MBC Type: {:}", cart_type, v);
-            v
-        }
-    };
+    let header = CartridgeHeader::new(&buffer);
+    let verification = header.verify(&buffer);
+    if !verification.header_checksum {
+        println!("Header checksum mismatch!");
+    }
+    if !verification.global_checksum {
+        println!("Global checksum mismatch!");
+    }
+
+    let cart_type: Option<CartTypes> = FromPrimitive::from_u8(buffer[0x0147]);
+    let mbc = loader::from_reader(buffer.as_slice()).expect("Failed to load ROM!");
+    match cart_type {
+        Some(t) => println!("Cart Type: {:}, MBC Type: {:}", t, t.get_mbc()),
+        None => println!("Cart Type: {:#04x}", buffer[0x0147]),
+    }
+
+    let palette: Option<DmgPalette> = args.palette.map(|p| FromPrimitive::from_u8(p).expect("Unknown palette!"));
 
-    let mut booting = true;
+    let model: Model = FromPrimitive::from_u8(args.model).expect("Unknown model!");
 
-    match args.boot_rom {
-        Some(path) => {
-            let mut boot_rom = Vec::new();
-            let mut boot = File::open(path).expect("No Boot ROM found!");
-            boot.read_to_end(&mut boot_rom).expect("Failed to read Boot ROM!");
+    let clock = if args.sgb_timing { ClockSpeed::SGB } else { ClockSpeed::Standard };
+    // Without an audio device there's nothing to sync to
+    let sync_mode = if args.audio_sync && !args.no_audio { SyncMode::Audio } else { SyncMode::Video };
 
-            // Display Nintendo Logo
-            buffer[0..=0x00FF].copy_from_slice(boot_rom.as_slice());
-        },
-        None => booting = false
+    let mut config = EmulatorConfig::new()
+        .model(model)
+        .clock(clock)
+        .print_serial(args.print_serial)
+        .audio(!args.no_audio);
+    if let Some(palette) = palette {
+        config = config.palette(palette);
+    }
+    if let Some(sample_rate) = args.sample_rate {
+        config = config.sample_rate(sample_rate);
+    }
+    if let Some(seed) = args.seed {
+        config = config.deterministic(seed);
     }
 
-    // Get game name
-    let name_data = &buffer[0x0134..=0x0143];
-    let index = name_data.iter().position(|&r| r == 0x00).unwrap();
-    let game_name = std::str::from_utf8(&name_data[0..index]).expect("Failed to get game name!");
+    if let Some(path) = args.boot_rom {
+        let mut boot_rom = Vec::new();
+        let mut boot = File::open(path).expect("No Boot ROM found!");
+        boot.read_to_end(&mut boot_rom).expect("Failed to read Boot ROM!");
+
+        // Display Nintendo Logo
+        config = config.boot_rom(boot_rom);
+    }
+
+    let game_name = header.title;
     println!("Starting \"{game_name}\"...");
 
     let event_loop = EventLoop::new().unwrap();
@@ -97,27 +157,65 @@ async fn main() -> Result<(), impl std::error::Error> {
         .build(&event_loop)
         .unwrap();
 
-    let context = Arc::new(Mutex::new(Context::new(window).await));
+    let mut cpu = CPU::with_cartridge(mbc, config);
+    let (width, height) = if args.upscale {
+        cpu.mem.ppu.set_upscale(true);
+        (ppu::SCREEN_W * 2, ppu::SCREEN_H * 2)
+    } else {
+        cpu.mem.ppu.output_size()
+    };
+    let context = Arc::new(Mutex::new(Context::new(window, width, height).await));
     let (input_tx, mut input_rx) = mpsc::unbounded_channel::<(JoypadButton, bool)>();
+    let (pause_tx, mut pause_rx) = mpsc::unbounded_channel::<()>();
 
     {
         let context = Arc::clone(&context);
         // Start CPU
         tokio::spawn(async move {
-            let mut cpu = CPU::new(GBMode::Classic, mbc_mode, args.print_serial, buffer, booting);
+            if args.skip_boot {
+                cpu.skip_boot();
+            }
+            if cart_type.is_some_and(|t| t.has_battery()) {
+                let save_path = Path::new(&args.rom_path).with_extension("sav");
+                if let Ok(save) = std::fs::read(&save_path) {
+                    if let Err(e) = cpu.mem.load_save(&save) {
+                        println!("Failed to load save! {}", e);
+                    }
+                }
+
+                match File::options().read(true).write(true).create(true).truncate(false).open(&save_path) {
+                    Ok(file) => cpu.mem.set_autosave(Autosave::new(Box::new(file), Duration::from_secs(1))),
+                    Err(e) => println!("Failed to open save file! {}", e),
+                }
+            }
+            if args.printer {
+                cpu.mem.serial.attach_printer();
+            }
             let mut step_cycles = 0;
+            // 67108 cycles at the standard clock
+            let step_cycles_limit = clock.step_cycles();
             let mut step_zero = Instant::now();
+            let mut audio_pacer = AudioPacer::new(clock, cpu.mem.apu.sample_rate(), cpu.mem.apu.samples_played());
 
             loop {
-                // https://github.com/mohanson/gameboy/blob/master/src/cpu.rs#L13
-                if step_cycles > STEP_CYCLES {
-                    step_cycles -= STEP_CYCLES;
-                    let now = Instant::now();
-                    let duration = now.duration_since(step_zero);
-                    let milliseconds = STEP_TIME.saturating_sub(duration.as_millis() as u32);
-                    // println!("[CPU] Sleeping {}ms", milliseconds);
-                    sleep(Duration::from_millis(milliseconds as u64)).await;
-                    step_zero = now;
+                if step_cycles > step_cycles_limit {
+                    step_cycles -= step_cycles_limit;
+                    match sync_mode {
+                        // https://github.com/mohanson/gameboy/blob/master/src/cpu.rs#L13
+                        SyncMode::Video => {
+                            let now = Instant::now();
+                            let duration = now.duration_since(step_zero);
+                            let milliseconds = STEP_TIME.saturating_sub(duration.as_millis() as u32);
+                            // println!("[CPU] Sleeping {}ms", milliseconds);
+                            sleep(Duration::from_millis(milliseconds as u64)).await;
+                            step_zero = now;
+                        },
+                        SyncMode::Audio => {
+                            while audio_pacer.should_wait(cpu.mem.apu.samples_played()) {
+                                sleep(Duration::from_millis(1)).await;
+                            }
+                        }
+                    }
                 }
 
                 match input_rx.try_recv() {
@@ -131,15 +229,38 @@ async fn main() -> Result<(), impl std::error::Error> {
                     Err(_) => {}
                 }
 
+                if pause_rx.try_recv().is_ok() {
+                    if cpu.is_paused() {
+                        cpu.resume();
+                    } else {
+                        cpu.pause();
+                    }
+                }
+
+                if cpu.is_paused() {
+                    sleep(Duration::from_millis(STEP_TIME as u64)).await;
+                    step_zero = Instant::now();
+                    audio_pacer.restart(cpu.mem.apu.samples_played());
+                    continue;
+                }
+
                 let cycles = cpu.cycle();
                 step_cycles += cycles;
+                audio_pacer.add_cycles(cycles);
                 let did_draw = cpu.mem.cycle(cycles);
-                if did_draw {
-                    let frame_buffer = cpu.mem.ppu.frame_buffer.clone();
+                if did_draw && cpu.mem.ppu.take_frame_dirty() {
+                    let frame_buffer = match cpu.mem.ppu.upscaled_framebuffer() {
+                        Some(upscaled) => upscaled.to_vec(),
+                        None => cpu.mem.ppu.frame_buffer.clone(),
+                    };
                     let mut context = context.lock().unwrap();
                     context.update(frame_buffer);
                     drop(context);
                 }
+
+                if let Some(printout) = cpu.mem.serial.take_printout() {
+                    println!("[Printer] Printed {}x{} image", printer::PRINTER_W, printout.len() / (4 * printer::PRINTER_W));
+                }
             }
         });
     }
@@ -184,6 +305,7 @@ async fn main() -> Result<(), impl std::error::Error> {
                                         Key::Character("x") => input_tx.send((JoypadButton::B, true)).unwrap(),
                                         Key::Character("c") => input_tx.send((JoypadButton::SELECT, true)).unwrap(),
                                         Key::Character("v") => input_tx.send((JoypadButton::START, true)).unwrap(),
+                                        Key::Character("p") => pause_tx.send(()).unwrap(),
                                         _ => (),
                                     }
                                 } else if event.state == ElementState::Released {
@@ -0,0 +1,101 @@
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cart_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct HeaderVerification {
+    pub header_checksum: bool,
+    pub global_checksum: bool
+}
+
+impl CartridgeHeader {
+    pub fn new(rom: &[u8]) -> Self {
+        let title_data = &rom[0x0134..=0x0143];
+        let end = title_data.iter().position(|&r| r == 0x00).unwrap_or(title_data.len());
+        let title = String::from_utf8_lossy(&title_data[0..end]).into_owned();
+
+        Self {
+            title,
+            cart_type: rom[0x0147],
+            rom_size: rom[0x0148],
+            ram_size: rom[0x0149],
+            header_checksum: rom[0x014D],
+            global_checksum: (rom[0x014E] as u16) << 8 | rom[0x014F] as u16
+        }
+    }
+
+    // ROM size in bytes declared by $0148, 32KB shifted by the value
+    pub fn rom_size(&self) -> usize {
+        match self.rom_size {
+            0x00..=0x08 => 0x8000 << self.rom_size,
+            _ => 0
+        }
+    }
+
+    // RAM size in bytes declared by $0149
+    pub fn ram_size(&self) -> usize {
+        match self.ram_size {
+            0x02 => 8_192,
+            0x03 => 32_768,
+            0x04 => 131_072,
+            0x05 => 65_536,
+            _ => 0
+        }
+    }
+
+    // Checks both checksums without rejecting the ROM,
+    // the boot ROM only enforces the header checksum
+    pub fn verify(&self, rom: &[u8]) -> HeaderVerification {
+        let header_checksum = rom[0x0134..=0x014C].iter()
+            .fold(0_u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+
+        let global_checksum = rom.iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0_u16, |x, (_, &b)| x.wrapping_add(b as u16));
+
+        HeaderVerification {
+            header_checksum: header_checksum == self.header_checksum,
+            global_checksum: global_checksum == self.global_checksum
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_checksums() -> Vec<u8> {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0134..0x0139].copy_from_slice(b"TETRA");
+        rom[0x0147] = 0x01;
+        rom[0x014D] = rom[0x0134..=0x014C].iter()
+            .fold(0_u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+        let global = rom.iter().fold(0_u16, |x, &b| x.wrapping_add(b as u16));
+        rom[0x014E] = (global >> 8) as u8;
+        rom[0x014F] = global as u8;
+        rom
+    }
+
+    #[test]
+    fn verify_passes_a_correct_header() {
+        let rom = rom_with_checksums();
+        let verification = CartridgeHeader::new(&rom).verify(&rom);
+        assert!(verification.header_checksum);
+        assert!(verification.global_checksum);
+    }
+
+    #[test]
+    fn verify_reports_a_corrupted_header() {
+        let mut rom = rom_with_checksums();
+        rom[0x0140] ^= 0x01;
+        let verification = CartridgeHeader::new(&rom).verify(&rom);
+        assert!(!verification.header_checksum);
+        assert!(!verification.global_checksum);
+    }
+}
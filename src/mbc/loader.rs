@@ -0,0 +1,161 @@
+use std::io;
+use std::io::Read;
+use num_traits::FromPrimitive;
+use crate::mbc::header::CartridgeHeader;
+use crate::mbc::mode::{CartTypes, MBC, MBCMode};
+use crate::mbc::rom_only::ROMOnly;
+use crate::mbc::mbc1::MBC1;
+use crate::mbc::mbc2::MBC2;
+use crate::mbc::mbc3::MBC3;
+use crate::mbc::mbc5::MBC5;
+
+// Everything up to and including the global checksum
+const HEADER_SIZE: usize = 0x0150;
+
+pub fn new_mbc(mbc_mode: MBCMode, rom: Vec<u8>) -> Box<dyn MBC> {
+    let ram_size = ram_size(&CartridgeHeader::new(&rom));
+    match mbc_mode {
+        MBCMode::RomOnly => Box::new(ROMOnly::new(rom)),
+        MBCMode::MBC1 => Box::new(MBC1::new(rom, ram_size)),
+        // MBC2's 512x4 bits of RAM are built into the mapper, whatever the header declares
+        MBCMode::MBC2 => Box::new(MBC2::new(rom)),
+        MBCMode::MBC3 => Box::new(MBC3::new(rom, ram_size)),
+        MBCMode::MBC5 => Box::new(MBC5::new(rom, ram_size)),
+        v => panic!("Unsupported MBC type! {:}", v)
+    }
+}
+
+// Some carts declare no RAM in $0149 despite a cart type with RAM, those get a single 8KB bank
+fn ram_size(header: &CartridgeHeader) -> usize {
+    let cart_type: Option<CartTypes> = FromPrimitive::from_u8(header.cart_type);
+    match header.ram_size() {
+        0 if cart_type.is_some_and(|t| t.has_ram()) => 0x2000,
+        n => n,
+    }
+}
+
+// Maps the $0147 cart type to a mapper. Homebrew and flash cart images often leave the type
+// byte zeroed or garbage, so an unrecognised type on a 32KB image is treated as ROM only.
+pub fn detect_mbc(cart_type: u8, rom_size: usize) -> Option<MBCMode> {
+    match FromPrimitive::from_u8(cart_type).map(|t: CartTypes| t.get_mbc()) {
+        Some(MBCMode::Unsupported) | None if rom_size == 0x8000 => {
+            println!("Unknown Cart Type ({:#04x}), loading 32KB image as ROM Only", cart_type);
+            Some(MBCMode::RomOnly)
+        },
+        Some(MBCMode::Unsupported) | None => None,
+        Some(v) => Some(v)
+    }
+}
+
+// Reads the header first and uses it to size the ROM, so the stream has to hold
+// exactly as many bytes as $0148 declares
+pub fn from_reader<R: Read>(mut r: R) -> io::Result<Box<dyn MBC>> {
+    let mut rom = vec![0x00; HEADER_SIZE];
+    r.read_exact(&mut rom)?;
+
+    let header = CartridgeHeader::new(&rom);
+    let rom_size = header.rom_size();
+    if rom_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown ROM size ({:#04x})!", header.rom_size)));
+    }
+
+    let mbc_mode = match detect_mbc(header.cart_type, rom_size) {
+        Some(v) => v,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported Cart Type! {:#04x}", header.cart_type)));
+        }
+    };
+
+    // Read one byte past the declared size to catch streams that are too long
+    rom.reserve_exact(rom_size - HEADER_SIZE);
+    r.take((rom_size - HEADER_SIZE + 1) as u64).read_to_end(&mut rom)?;
+    if rom.len() != rom_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("ROM is {} bytes, header declares {} bytes", rom.len(), rom_size)));
+    }
+
+    Ok(new_mbc(mbc_mode, rom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // 128KB MBC1+RAM cart with every bank starting with its own number
+    fn mbc1_rom() -> Vec<u8> {
+        let mut rom = vec![0x00; 0x20000];
+        for (i, bank) in rom.chunks_exact_mut(0x4000).enumerate() {
+            bank[0] = i as u8;
+        }
+        rom[0x0147] = 0x02;
+        rom[0x0148] = 0x02;
+        rom[0x0149] = 0x02;
+        rom
+    }
+
+    #[test]
+    fn from_reader_matches_the_vec_path() {
+        let mut streamed = from_reader(Cursor::new(mbc1_rom())).unwrap();
+        let mut loaded = new_mbc(MBCMode::MBC1, mbc1_rom());
+        assert_eq!(streamed.ram().len(), 0x2000);
+        assert_eq!(streamed.ram().len(), loaded.ram().len());
+
+        for bank in 1..8 {
+            streamed.write(0x2000, bank);
+            loaded.write(0x2000, bank);
+            assert_eq!(streamed.current_rom_bank(), loaded.current_rom_bank());
+            assert_eq!(streamed.read(0x4000), bank);
+            assert_eq!(streamed.read(0x4000), loaded.read(0x4000));
+        }
+    }
+
+    #[test]
+    fn from_reader_rejects_streams_of_the_wrong_length() {
+        let mut rom = mbc1_rom();
+        rom.push(0x00);
+        assert_eq!(from_reader(Cursor::new(rom)).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        let rom = mbc1_rom()[..0x10000].to_vec();
+        assert_eq!(from_reader(Cursor::new(rom)).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn unknown_cart_types_load_32kb_images_as_rom_only() {
+        assert_eq!(detect_mbc(0xEE, 0x8000), Some(MBCMode::RomOnly));
+        assert_eq!(detect_mbc(0xEE, 0x10000), None);
+        assert_eq!(detect_mbc(0x01, 0x8000), Some(MBCMode::MBC1));
+
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0xEE;
+        rom[0x4000] = 0x42;
+        let mut mbc = from_reader(Cursor::new(rom)).unwrap();
+        assert!(mbc.ram().is_empty());
+        mbc.write(0x2000, 0x02);
+        assert_eq!(mbc.read(0x4000), 0x42);
+    }
+
+    #[test]
+    fn ram_is_allocated_for_ram_carts_declaring_none() {
+        // MBC2+BATTERY, its RAM is inside the mapper
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0x06;
+        let mut mbc = from_reader(Cursor::new(rom)).unwrap();
+        assert_eq!(mbc.ram().len(), 512);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA1FF, 0x0C);
+        assert_eq!(mbc.ram()[0x1FF], 0x0C);
+        assert!(mbc.load_ram(&[0x05; 512]).is_ok());
+        assert_eq!(mbc.read(0xA000) & 0x0F, 0x05);
+
+        // MBC1+RAM gets a single 8KB bank
+        let mut rom = mbc1_rom();
+        rom[0x0149] = 0x00;
+        assert_eq!(from_reader(Cursor::new(rom)).unwrap().ram().len(), 0x2000);
+
+        // Carts without RAM stay without
+        let mut rom = mbc1_rom();
+        rom[0x0147] = 0x01;
+        rom[0x0149] = 0x00;
+        assert!(from_reader(Cursor::new(rom)).unwrap().ram().is_empty());
+    }
+}
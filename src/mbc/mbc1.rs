@@ -1,4 +1,7 @@
+use std::hash::{Hash, Hasher};
 use crate::mbc::mode::MBC;
+use crate::mbc::save;
+use crate::mbc::save::SaveError;
 use crate::memory::Memory;
 
 pub struct MBC1 {
@@ -6,21 +9,31 @@ pub struct MBC1 {
     ram: Vec<u8>,
     ram_enabled: bool,
     bank_mode: BankMode,
-    bank: u8
+    bank: u8,
+    // MBC1M wires the secondary register to ROM A18-A19 instead of A19-A20
+    multicart: bool,
+    // Bank bits past the end of the ROM aren't connected, so banks mirror
+    rom_bank_mask: usize
 }
 
-// TODO: MBC1M Support
+// The Nintendo logo, repeated in the header of every game on a multicart
+const LOGO: [u8; 0x30] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E
+];
 
 impl Memory for MBC1 {
     fn read(&self, a: u16) -> u8 {
         match a {
-            0x0000..=0x3FFF => self.rom[a as usize],
+            0x0000..=0x3FFF => self.rom[a as usize + self.zero_bank() * 0x4000],
             0x4000..=0x7FFF => self.rom[a as usize + self.rom_bank() * 0x4000 - 0x4000],
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    self.ram[a as usize + self.ram_bank() * 0x2000 - 0xA000]
+                if self.ram_enabled && !self.ram.is_empty() {
+                    self.ram[(a as usize + self.ram_bank() * 0x2000 - 0xA000) % self.ram.len()]
                 } else {
-                    0x00
+                    // Open bus while RAM is disabled
+                    0xFF
                 }
             }
             _ => panic!("Read to unsupported MBC1 address ({:#06x})!", a),
@@ -29,7 +42,7 @@ impl Memory for MBC1 {
 
     fn write(&mut self, a: u16, v: u8) {
         match a {
-            0x0000..=0x1FFF => self.ram_enabled = v & 0xF == 0xA,
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
             0x2000..=0x3FFF => {
                 let n = match v & 0x1F {
                     0x00 => 0x01,
@@ -38,15 +51,15 @@ impl Memory for MBC1 {
                 self.bank = (self.bank & 0x60) | n;
             },
             0x4000..=0x5FFF => self.bank = self.bank & 0x9F | ((v & 0x03) << 5),
-            0x6000..=0x7FFF => match v {
-                0x00 => self.bank_mode = BankMode::ROM,
-                0x01 => self.bank_mode = BankMode::RAM,
-                n => panic!("Unknown bank mode ({:#04x})!", n),
+            // Only bit 0 is connected, so menus writing other values still switch modes
+            0x6000..=0x7FFF => {
+                self.bank_mode = if v & 0x01 == 0 { BankMode::ROM } else { BankMode::RAM };
             },
             0xA000..=0xBFFF => {
                 let ram_bank = self.ram_bank();
-                if self.ram_enabled {
-                    self.ram[ a as usize + ram_bank * 0x2000 - 0xA000] = v;
+                if self.ram_enabled && !self.ram.is_empty() {
+                    let ram_size = self.ram.len();
+                    self.ram[(a as usize + ram_bank * 0x2000 - 0xA000) % ram_size] = v;
                 }
             }
             _ => panic!("Write to unsupported MBC1 address ({:#06x})!", a),
@@ -54,30 +67,90 @@ impl Memory for MBC1 {
     }
 }
 
-impl MBC for MBC1 { }
+impl MBC for MBC1 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), SaveError> {
+        save::load_ram(&mut self.ram, data)
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        self.rom_bank() as u16
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        self.ram_bank() as u8
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.ram.hash(&mut state);
+        self.ram_enabled.hash(&mut state);
+        (self.bank_mode as u8).hash(&mut state);
+        self.bank.hash(&mut state);
+        self.multicart.hash(&mut state);
+    }
+}
 
 impl MBC1 {
-    pub fn new(rom: Vec<u8>) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
         let mut padded_rom = vec![0x00; 2_097_152];
         padded_rom[0..rom.len()].copy_from_slice(rom.as_slice());
 
         Self {
+            multicart: Self::is_multicart(&rom),
+            rom_bank_mask: (rom.len() / 0x4000).next_power_of_two().max(2) - 1,
             rom: padded_rom,
-            ram: vec![0x00; 32_768],
+            ram: vec![0x00; ram_size],
             ram_enabled: false,
             bank_mode: BankMode::ROM,
             bank: 0x01
         }
     }
 
+    // Multicarts are 1MB and hold a game (and its logo) at every 256KB boundary
+    fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != 0x100000 {
+            return false;
+        }
+
+        let logos = (0..4)
+            .filter(|i| rom[i * 0x40000 + 0x0104..i * 0x40000 + 0x0134] == LOGO)
+            .count();
+        logos > 1
+    }
+
+    // The secondary register always extends the $4000-$7FFF bank, whatever the mode
     fn rom_bank(&self) -> usize {
+        let n = if self.multicart {
+            ((self.bank & 0x60) >> 1) | (self.bank & 0x0F)
+        } else {
+            self.bank & 0x7F
+        };
+        n as usize & self.rom_bank_mask
+    }
+
+    // In mode 1 the secondary register also applies to $0000-$3FFF, so 1MB+ carts
+    // remap the "fixed" bank (and a multicart switches game)
+    fn zero_bank(&self) -> usize {
         let n = match self.bank_mode {
-            BankMode::ROM => self.bank & 0x7F,
-            BankMode::RAM => self.bank & 0x1F,
+            BankMode::ROM => 0x00,
+            BankMode::RAM if self.multicart => (self.bank & 0x60) >> 1,
+            BankMode::RAM => self.bank & 0x60,
         };
-        n as usize
+        n as usize & self.rom_bank_mask
     }
 
+    // Mode 0 pins RAM to bank 0, mode 1 selects it with the secondary register
     fn ram_bank(&self) -> usize {
         let n = match self.bank_mode {
             BankMode::ROM => 0x00,
@@ -87,7 +160,178 @@ impl MBC1 {
     }
 }
 
+#[derive(Copy, Clone)]
 enum BankMode {
     ROM,
     RAM
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::loader;
+    use crate::mbc::mode::MBCMode;
+
+    #[test]
+    fn ram_is_only_enabled_by_0x0a() {
+        let mut mbc = MBC1::new(vec![0x00; 0x8000], 0x2000);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0x42);
+
+        mbc.write(0x0000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        // Only the low nibble is decoded
+        mbc.write(0x0000, 0x5A);
+        assert_eq!(mbc.read(0xA000), 0x42);
+        mbc.write(0x0000, 0x0B);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    // Every bank starts with its own number
+    fn numbered_rom(size: usize) -> Vec<u8> {
+        let mut rom = vec![0x00; size];
+        for (i, bank) in rom.chunks_exact_mut(0x4000).enumerate() {
+            bank[0] = i as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn mode_1_banks_the_low_area_on_2mb_carts() {
+        let mut mbc = MBC1::new(numbered_rom(0x200000), 0);
+        mbc.write(0x2000, 0x01);
+        mbc.write(0x4000, 0x02);
+        assert_eq!(mbc.read(0x0000), 0x00);
+        assert_eq!(mbc.read(0x4000), 0x41);
+
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x40);
+        assert_eq!(mbc.read(0x4000), 0x41);
+    }
+
+    #[test]
+    fn multicarts_switch_games_in_mode_1() {
+        let mut mbc = MBC1::new(multicart_rom(&[0, 1, 2, 3]), 0);
+        mbc.write(0x6000, 0x01);
+        mbc.write(0x4000, 0x01);
+        mbc.write(0x2000, 0x02);
+        assert_eq!(mbc.read(0x0000), 0x10);
+        assert_eq!(mbc.read(0x4000), 0x12);
+    }
+
+    #[test]
+    fn reports_the_mapped_banks() {
+        let mut mbc = MBC1::new(numbered_rom(0x80000), 0x8000);
+        assert_eq!(mbc.current_rom_bank(), 1);
+        assert_eq!(mbc.current_ram_bank(), 0);
+        assert!(!mbc.ram_enabled());
+
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x2000, 0x05);
+        mbc.write(0x4000, 0x02);
+        assert!(mbc.ram_enabled());
+        assert_eq!(mbc.current_rom_bank(), 0x05);
+        assert_eq!(mbc.read(0x4000), 0x05);
+        // The secondary register only selects the RAM bank in mode 1
+        assert_eq!(mbc.current_ram_bank(), 0);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.current_ram_bank(), 2);
+    }
+
+    #[test]
+    fn banking_mode_picks_the_low_rom_and_ram_banks() {
+        let mut mbc = MBC1::new(numbered_rom(0x100000), 0x8000);
+        mbc.write(0x0000, 0x0A);
+        for bank in 0..4 {
+            mbc.write(0x6000, 0x01);
+            mbc.write(0x4000, bank);
+            mbc.write(0xA000, bank + 0x10);
+        }
+
+        // Mode 0: only $4000-$7FFF sees the secondary register
+        mbc.write(0x6000, 0x00);
+        mbc.write(0x4000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x00);
+        assert_eq!(mbc.read(0x4000), 0x21);
+        assert_eq!(mbc.read(0xA000), 0x10);
+
+        // Mode 1: it also selects the $0000-$3FFF bank and the RAM bank
+        mbc.write(0x6000, 0x01);
+        assert_eq!(mbc.read(0x0000), 0x20);
+        assert_eq!(mbc.read(0x4000), 0x21);
+        assert_eq!(mbc.read(0xA000), 0x11);
+    }
+
+    #[test]
+    fn rom_bank_writes_switch_the_upper_area() {
+        // 512KB, 32 banks
+        let mut mbc = MBC1::new(numbered_rom(0x80000), 0);
+        assert_eq!(mbc.read(0x4000), 0x01);
+        for bank in 1..0x20 {
+            mbc.write(0x2000, bank);
+            assert_eq!(mbc.read(0x4000), bank);
+            assert_eq!(mbc.read(0x0000), 0x00);
+        }
+
+        // Only 5 bits are decoded, and a zero there selects bank 1
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 0x01);
+        mbc.write(0x3FFF, 0xE3);
+        assert_eq!(mbc.read(0x4000), 0x03);
+        mbc.write(0x2000, 0x20);
+        assert_eq!(mbc.read(0x4000), 0x01);
+
+        // Banks past the end of a smaller ROM mirror
+        let mut mbc = MBC1::new(numbered_rom(0x20000), 0);
+        mbc.write(0x2000, 0x0A);
+        assert_eq!(mbc.read(0x4000), 0x02);
+    }
+
+    #[test]
+    fn mbc1_cart_types_load_as_mbc1() {
+        for cart_type in 0x01..=0x03 {
+            assert_eq!(loader::detect_mbc(cart_type, 0x80000), Some(MBCMode::MBC1));
+        }
+    }
+
+    fn multicart_rom(logos: &[usize]) -> Vec<u8> {
+        let mut rom = numbered_rom(0x100000);
+        for &game in logos {
+            rom[game * 0x40000 + 0x0104..game * 0x40000 + 0x0134].copy_from_slice(&LOGO);
+        }
+        rom
+    }
+
+    #[test]
+    fn multicarts_are_detected_by_repeated_logos() {
+        assert!(!MBC1::is_multicart(&multicart_rom(&[0])));
+        assert!(MBC1::is_multicart(&multicart_rom(&[0, 1])));
+        assert!(MBC1::is_multicart(&multicart_rom(&[0, 1, 2, 3])));
+        // Only 1MB carts are wired as MBC1M
+        let mut rom = numbered_rom(0x200000);
+        for game in 0..4 {
+            rom[game * 0x40000 + 0x0104..game * 0x40000 + 0x0134].copy_from_slice(&LOGO);
+        }
+        assert!(!MBC1::is_multicart(&rom));
+    }
+
+    #[test]
+    fn multicarts_skip_bank_bit_4() {
+        // Boots into the menu in game 0, bit 4 of the bank register isn't connected
+        let mut mbc = MBC1::new(multicart_rom(&[0, 1, 2, 3]), 0);
+        mbc.write(0x2000, 0x12);
+        assert_eq!(mbc.read(0x0000), 0x00);
+        assert_eq!(mbc.read(0x4000), 0x02);
+
+        // The secondary register picks the game, and mode 1 maps its first bank low.
+        // The mode register only decodes bit 0.
+        mbc.write(0x4000, 0x03);
+        assert_eq!(mbc.read(0x4000), 0x32);
+        mbc.write(0x6000, 0xFF);
+        assert_eq!(mbc.read(0x0000), 0x30);
+        mbc.write(0x6000, 0xFE);
+        assert_eq!(mbc.read(0x0000), 0x00);
+    }
+}
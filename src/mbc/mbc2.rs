@@ -1,4 +1,7 @@
+use std::hash::{Hash, Hasher};
 use crate::mbc::mode::MBC;
+use crate::mbc::save;
+use crate::mbc::save::SaveError;
 use crate::memory::Memory;
 
 pub struct MBC2 {
@@ -17,7 +20,8 @@ impl Memory for MBC2 {
                 if self.ram_enabled {
                     self.ram[(a - 0xA000) as usize]
                 } else {
-                    0x00
+                    // Open bus while RAM is disabled
+                    0xFF
                 }
             }
             _ => panic!("Read to unsupported MBC2 address ({:#06x})!", a),
@@ -47,7 +51,38 @@ impl Memory for MBC2 {
     }
 }
 
-impl MBC for MBC2 { }
+impl MBC for MBC2 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), SaveError> {
+        save::load_ram(&mut self.ram, data)
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        self.rom_bank as u16
+    }
+
+    // The built-in RAM isn't banked
+    fn current_ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.ram.hash(&mut state);
+        self.ram_enabled.hash(&mut state);
+        self.rom_bank.hash(&mut state);
+    }
+}
 
 impl MBC2 {
     pub fn new(rom: Vec<u8>) -> Self {
@@ -60,3 +95,41 @@ impl MBC2 {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_is_only_enabled_by_0x0a() {
+        let mut mbc = MBC2::new(vec![0x00; 0x8000]);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x07);
+        assert_eq!(mbc.read(0xA000), 0x07);
+
+        mbc.write(0x0000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        // Only the low nibble is decoded
+        mbc.write(0x0000, 0x5A);
+        assert_eq!(mbc.read(0xA000), 0x07);
+        mbc.write(0x0000, 0x0B);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn reports_the_mapped_banks() {
+        let mut mbc = MBC2::new(vec![0x00; 0x40000]);
+        assert_eq!(mbc.current_rom_bank(), 1);
+        assert!(!mbc.ram_enabled());
+
+        // Bit 8 of the address picks ROM bank select over RAM enable
+        mbc.write(0x2100, 0x07);
+        assert_eq!(mbc.current_rom_bank(), 7);
+        mbc.write(0x2000, 0x03);
+        assert_eq!(mbc.current_rom_bank(), 7);
+        mbc.write(0x0000, 0x0A);
+        assert!(mbc.ram_enabled());
+        assert_eq!(mbc.current_ram_bank(), 0);
+    }
+}
@@ -1,5 +1,8 @@
 use std::time::SystemTime;
+use std::hash::{Hash, Hasher};
 use crate::mbc::mode::MBC;
+use crate::mbc::save;
+use crate::mbc::save::SaveError;
 use crate::memory::Memory;
 
 pub struct MBC3 {
@@ -19,12 +22,17 @@ impl Memory for MBC3 {
             0xA000..=0xBFFF => {
                 if self.ram_enabled {
                     if self.ram_bank <= 0x03 {
-                        self.ram[a as usize + self.ram_bank * 0x2000 - 0xA000]
+                        if self.ram.is_empty() {
+                            0xFF
+                        } else {
+                            self.ram[(a as usize + self.ram_bank * 0x2000 - 0xA000) % self.ram.len()]
+                        }
                     } else {
                         self.rtc.read(self.ram_bank as u16)
                     }
                 } else {
-                    0x00
+                    // Open bus while RAM is disabled
+                    0xFF
                 }
             }
             _ => panic!("Read to unsupported MBC3 address ({:#06x})!", a),
@@ -50,7 +58,10 @@ impl Memory for MBC3 {
             0xA000..=0xBFFF => {
                 if self.ram_enabled {
                     if self.ram_bank <= 0x03 {
-                        self.ram[a as usize + self.ram_bank * 0x2000 - 0xA000] = v;
+                        if !self.ram.is_empty() {
+                            let ram_size = self.ram.len();
+                            self.ram[(a as usize + self.ram_bank * 0x2000 - 0xA000) % ram_size] = v;
+                        }
                     } else {
                         self.rtc.write(self.ram_bank as u16, v);
                     }
@@ -61,13 +72,73 @@ impl Memory for MBC3 {
     }
 }
 
-impl MBC for MBC3 { }
+impl MBC for MBC3 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // Saves may carry a 44 or 48 byte RTC trailer after the RAM contents,
+    // starting with the S, M, H, DL, DH registers as little-endian u32s
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), SaveError> {
+        let ram_size = self.ram.len();
+        let (ram, trailer) = match data.len().checked_sub(ram_size) {
+            Some(0) => (data, None),
+            Some(44) | Some(48) => (&data[..ram_size], Some(&data[ram_size..])),
+            _ => return Err(SaveError::SizeMismatch { expected: ram_size, actual: data.len() }),
+        };
+
+        if !ram.is_empty() {
+            save::load_ram(&mut self.ram, ram)?;
+        }
+
+        if let Some(trailer) = trailer {
+            for (i, register) in (0x08..=0x0C).enumerate() {
+                self.rtc.write(register, trailer[i * 4]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        self.rom_bank as u16
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        self.ram_bank as u8
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    // Banks $08-$0C map the RTC registers instead
+    fn ram_writable(&self) -> bool {
+        self.ram_enabled && self.ram_bank <= 0x03 && !self.ram.is_empty()
+    }
+
+    fn set_deterministic(&mut self) {
+        self.rtc.host_time = false;
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.ram.hash(&mut state);
+        self.rtc.hash(&mut state);
+        self.ram_enabled.hash(&mut state);
+        self.rom_bank.hash(&mut state);
+        self.ram_bank.hash(&mut state);
+    }
+}
 
 impl MBC3 {
-    pub fn new(rom: Vec<u8>) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
         Self {
             rom,
-            ram: vec![0x00; 32_768],
+            ram: vec![0x00; ram_size],
             rtc: RTC::new(),
             ram_enabled: false,
             rom_bank: 1,
@@ -81,7 +152,19 @@ struct RTC {
     m: u8,
     h: u8,
     dl: u8,
-    dh: u8
+    dh: u8,
+    // Latching copies the host clock, when off the registers only change through writes
+    host_time: bool
+}
+
+impl Hash for RTC {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.s.hash(state);
+        self.m.hash(state);
+        self.h.hash(state);
+        self.dl.hash(state);
+        self.dh.hash(state);
+    }
 }
 
 impl RTC {
@@ -91,11 +174,16 @@ impl RTC {
             m: 0,
             h: 0,
             dl: 0,
-            dh: 0
+            dh: 0,
+            host_time: true
         }
     }
 
     pub fn tick(&mut self) {
+        if !self.host_time {
+            return;
+        }
+
         let d = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -141,4 +229,47 @@ impl Memory for RTC {
             _ => panic!("Write to unsupported RTC address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_is_only_enabled_by_0x0a() {
+        let mut mbc = MBC3::new(vec![0x00; 0x8000], 0x2000);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0x42);
+
+        mbc.write(0x0000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        // Only the low nibble is decoded
+        mbc.write(0x0000, 0x5A);
+        assert_eq!(mbc.read(0xA000), 0x42);
+        mbc.write(0x0000, 0x0B);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn reports_the_mapped_banks() {
+        let mut mbc = MBC3::new(vec![0x00; 0x200000], 0x8000);
+        assert_eq!(mbc.current_rom_bank(), 1);
+        assert_eq!(mbc.current_ram_bank(), 0);
+        assert!(!mbc.ram_enabled());
+
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x2000, 0x42);
+        mbc.write(0x4000, 0x03);
+        assert!(mbc.ram_enabled());
+        assert_eq!(mbc.current_rom_bank(), 0x42);
+        assert_eq!(mbc.current_ram_bank(), 3);
+
+        // Bank 0 maps bank 1, and $08-$0C select the RTC registers
+        mbc.write(0x2000, 0x00);
+        mbc.write(0x4000, 0x08);
+        assert_eq!(mbc.current_rom_bank(), 1);
+        assert_eq!(mbc.current_ram_bank(), 0x08);
+    }
+}
@@ -1,4 +1,7 @@
+use std::hash::{Hash, Hasher};
 use crate::mbc::mode::MBC;
+use crate::mbc::save;
+use crate::mbc::save::SaveError;
 use crate::memory::Memory;
 
 pub struct MBC5 {
@@ -15,10 +18,11 @@ impl Memory for MBC5 {
             0x0000..=0x3FFF => self.rom[a as usize],
             0x4000..=0x7FFF => self.rom[a as usize + self.rom_bank * 0x4000 - 0x4000],
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    self.ram[a as usize + self.ram_bank * 0x2000 - 0xA000]
+                if self.ram_enabled && !self.ram.is_empty() {
+                    self.ram[(a as usize + self.ram_bank * 0x2000 - 0xA000) % self.ram.len()]
                 } else {
-                    0x00
+                    // Open bus while RAM is disabled
+                    0xFF
                 }
             }
             _ => panic!("Read to unsupported MBC5 address ({:#06x})!", a),
@@ -34,8 +38,9 @@ impl Memory for MBC5 {
             // Unknown writes
             0x6000..=0x7FFF => {},
             0xA000..=0xBFFF => {
-                if self.ram_enabled {
-                    self.ram[a as usize + self.ram_bank * 0x2000 - 0xA000] = v;
+                if self.ram_enabled && !self.ram.is_empty() {
+                    let ram_size = self.ram.len();
+                    self.ram[(a as usize + self.ram_bank * 0x2000 - 0xA000) % ram_size] = v;
                 }
             }
             _ => panic!("Write to unsupported MBC5 address ({:#06x})!", a),
@@ -43,16 +48,88 @@ impl Memory for MBC5 {
     }
 }
 
-impl MBC for MBC5 { }
+impl MBC for MBC5 {
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), SaveError> {
+        save::load_ram(&mut self.ram, data)
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        self.rom_bank as u16
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        self.ram_bank as u8
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn hash_state(&self, mut state: &mut dyn Hasher) {
+        self.ram.hash(&mut state);
+        self.ram_enabled.hash(&mut state);
+        self.rom_bank.hash(&mut state);
+        self.ram_bank.hash(&mut state);
+    }
+}
 
 impl MBC5 {
-    pub fn new(rom: Vec<u8>) -> Self {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
         Self {
             rom,
-            ram: vec![0x00; 131_072],
+            ram: vec![0x00; ram_size],
             ram_enabled: false,
             rom_bank: 0,
             ram_bank: 0
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_is_only_enabled_by_0x0a() {
+        let mut mbc = MBC5::new(vec![0x00; 0x8000], 0x2000);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0x42);
+
+        mbc.write(0x0000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+
+        // Only the low nibble is decoded
+        mbc.write(0x0000, 0x5A);
+        assert_eq!(mbc.read(0xA000), 0x42);
+        mbc.write(0x0000, 0x0B);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn reports_the_mapped_banks() {
+        let mut mbc = MBC5::new(vec![0x00; 0x800000], 0x20000);
+        assert!(!mbc.ram_enabled());
+
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x2000, 0x34);
+        mbc.write(0x3000, 0x01);
+        mbc.write(0x4000, 0x0F);
+        assert!(mbc.ram_enabled());
+        assert_eq!(mbc.current_rom_bank(), 0x134);
+        assert_eq!(mbc.current_ram_bank(), 0x0F);
+
+        // Unlike the other mappers bank 0 can be mapped at $4000
+        mbc.write(0x2000, 0x00);
+        mbc.write(0x3000, 0x00);
+        assert_eq!(mbc.current_rom_bank(), 0);
+    }
+}
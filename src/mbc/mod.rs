@@ -3,4 +3,7 @@ pub mod rom_only;
 pub mod mbc1;
 pub mod mbc3;
 pub mod mbc5;
-pub mod mbc2;
\ No newline at end of file
+pub mod mbc2;
+pub mod save;
+pub mod header;
+pub mod loader;
\ No newline at end of file
@@ -1,6 +1,8 @@
 use std::fmt;
+use std::hash::Hasher;
 use std::fmt::{Formatter};
 use crate::memory::Memory;
+use crate::mbc::save::SaveError;
 
 #[derive(Clone, Copy, PartialEq, FromPrimitive, Debug)]
 pub enum CartTypes {
@@ -72,6 +74,42 @@ impl CartTypes {
     }
 }
 
+impl CartTypes {
+    pub fn has_ram(&self) -> bool {
+        matches!(self,
+            CartTypes::MBC1Ram |
+            CartTypes::MBC1RamBat |
+            CartTypes::RomRam |
+            CartTypes::RomRamBat |
+            CartTypes::MMM01Ram |
+            CartTypes::MMM01RamBat |
+            CartTypes::MBC3TimerRamBat |
+            CartTypes::MBC3Ram |
+            CartTypes::MBC3RamBat |
+            CartTypes::MBC5Ram |
+            CartTypes::MBC5RamBat |
+            CartTypes::MBC5RumbleRam |
+            CartTypes::MBC5RumbleRamBat |
+            CartTypes::MBC7SensorRumbleRamBat |
+            CartTypes::HuC1RamBat)
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self,
+            CartTypes::MBC1RamBat |
+            CartTypes::MBC2Bat |
+            CartTypes::RomRamBat |
+            CartTypes::MMM01RamBat |
+            CartTypes::MBC3TimerBat |
+            CartTypes::MBC3TimerRamBat |
+            CartTypes::MBC3RamBat |
+            CartTypes::MBC5RamBat |
+            CartTypes::MBC5RumbleRamBat |
+            CartTypes::MBC7SensorRumbleRamBat |
+            CartTypes::HuC1RamBat)
+    }
+}
+
 impl fmt::Display for CartTypes {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -130,4 +168,24 @@ impl fmt::Display for MBCMode {
     }
 }
 
-pub trait MBC : Memory + Send { }
\ No newline at end of file
+pub trait MBC : Memory + Send {
+    // Full cartridge RAM contents, empty if the cart has none
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+    // Replaces cartridge RAM with a save, rejecting saves of the wrong size
+    fn load_ram(&mut self, data: &[u8]) -> Result<(), SaveError>;
+    // ROM bank currently mapped at $4000-$7FFF
+    fn current_rom_bank(&self) -> u16;
+    // RAM bank currently mapped at $A000-$BFFF, on MBC3 this can be an RTC register
+    fn current_ram_bank(&self) -> u8;
+    fn ram_enabled(&self) -> bool;
+    // Whether a write to $A000-$BFFF would land in cartridge RAM
+    fn ram_writable(&self) -> bool {
+        self.ram_enabled() && !self.ram().is_empty()
+    }
+    // Stops the cartridge reading host time, so runs only depend on emulated input.
+    // Only carts with a clock have anything to do.
+    fn set_deterministic(&mut self) { }
+    // Hashes RAM and banking registers, the ROM is constant so is left out
+    fn hash_state(&self, state: &mut dyn Hasher);
+}
\ No newline at end of file
@@ -1,4 +1,6 @@
+use std::hash::Hasher;
 use crate::mbc::mode::MBC;
+use crate::mbc::save::SaveError;
 use crate::memory::Memory;
 
 pub struct ROMOnly {
@@ -16,7 +18,33 @@ impl Memory for ROMOnly {
     fn write(&mut self, a: u16, v: u8) { }
 }
 
-impl MBC for ROMOnly { }
+impl MBC for ROMOnly {
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn load_ram(&mut self, _: &[u8]) -> Result<(), SaveError> {
+        Err(SaveError::NoRam)
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        false
+    }
+
+    fn hash_state(&self, _: &mut dyn Hasher) { }
+}
 
 impl ROMOnly {
     pub fn new(rom: Vec<u8>) -> Self {
@@ -24,4 +52,20 @@ impl ROMOnly {
             rom
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_fixed_banks_and_no_ram() {
+        let mut mbc = ROMOnly::new(vec![0x00; 0x8000]);
+        mbc.write(0x2000, 0x03);
+        mbc.write(0x0000, 0x0A);
+        assert_eq!(mbc.current_rom_bank(), 1);
+        assert_eq!(mbc.current_ram_bank(), 0);
+        assert!(!mbc.ram_enabled());
+        assert!(mbc.ram().is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::{Result, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum SaveError {
+    // The save doesn't match the cartridge's RAM size
+    SizeMismatch { expected: usize, actual: usize },
+    NoRam
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::SizeMismatch { expected, actual } => write!(f, "Save is {} bytes, expected {} bytes", actual, expected),
+            SaveError::NoRam => write!(f, "Cartridge has no RAM to load a save into"),
+        }
+    }
+}
+
+impl std::error::Error for SaveError { }
+
+// Checks a save against the expected RAM size before copying it in
+pub fn load_ram(ram: &mut [u8], data: &[u8]) -> std::result::Result<(), SaveError> {
+    if ram.is_empty() {
+        return Err(SaveError::NoRam);
+    }
+    if data.len() != ram.len() {
+        return Err(SaveError::SizeMismatch { expected: ram.len(), actual: data.len() });
+    }
+
+    ram.copy_from_slice(data);
+    Ok(())
+}
+
+pub trait SaveWriter: Write + Seek + Send { }
+
+impl<T: Write + Seek + Send> SaveWriter for T { }
+
+// Flushes battery-backed cartridge RAM once it has been idle for `interval`
+pub struct Autosave {
+    writer: Box<dyn SaveWriter>,
+    interval: Duration,
+    dirty: bool,
+    last_write: Instant
+}
+
+impl Autosave {
+    pub fn new(writer: Box<dyn SaveWriter>, interval: Duration) -> Self {
+        Self {
+            writer,
+            interval,
+            dirty: false,
+            last_write: Instant::now()
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.last_write = Instant::now();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.dirty && self.last_write.elapsed() >= self.interval
+    }
+
+    pub fn flush(&mut self, ram: &[u8]) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(ram)?;
+        self.writer.flush()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
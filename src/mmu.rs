@@ -1,23 +1,33 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::ops::RangeInclusive;
 use bitflags::bitflags;
+use crate::boot::BootValues;
 use crate::sound::apu::APU;
 use crate::joypad::Joypad;
 use crate::mbc::mode::{MBC, MBCMode};
-use crate::mbc::rom_only::ROMOnly;
-use crate::mbc::mbc1::MBC1;
-use crate::mbc::mbc2::MBC2;
-use crate::mbc::mbc3::MBC3;
-use crate::mbc::mbc5::MBC5;
+use crate::mbc::loader;
+use crate::mbc::save::{Autosave, SaveError};
 use crate::memory::Memory;
 use crate::ppu::PPU;
 use crate::timer::Timer;
-use crate::mode::GBMode;
+use crate::mode::{ClockSpeed, GBMode};
 use crate::serial::Serial;
+use crate::infrared::Infrared;
 
 pub struct MMU {
+    mode: GBMode,
     mbc: Box<dyn MBC+'static>,
+    autosave: Option<Autosave>,
+    // Overlays the cartridge until $FF50 is written
+    boot_rom: Option<Vec<u8>>,
+    // Pushed the finished frame buffer at each VBlank
+    on_vblank: Option<VBlankCallback>,
     pub ppu: PPU,
-    apu: APU,
-    serial: Serial,
+    pub apu: APU,
+    pub serial: Serial,
+    pub infrared: Infrared,
     timer: Timer,
     pub joypad: Joypad,
     wram: [u8; 0x8000],
@@ -25,10 +35,43 @@ pub struct MMU {
     intf: Interrupts,
     inte: Interrupts,
     wram_bank: usize,
+    // Undocumented CGB registers $FF72-$FF75, no known function
+    undocumented: [u8; 4],
+    double_speed: bool,
+    // Next OAM DMA source address while a transfer is running
+    dma_source: Option<u16>,
+    dma_register: u8,
+    dma_byte: u8,
+    dma_cycles: u32,
+    // Setup cycles left before the first byte is copied, the bus stays free until then
+    dma_delay: u32,
+    // Set by a $FF46 write. The write is the last M-cycle of its instruction, so all the cycles
+    // the CPU reports for that instruction come before the setup and are skipped.
+    dma_written: bool,
+    speed_switch_armed: bool,
+    write_watch: Option<u16>,
+    write_watch_hit: bool,
+    // Total cycles elapsed, used to timestamp logged accesses
+    total_cycles: u64,
+    // CPU cycles between the last two frames, and when the current one started
+    last_frame_cycles: u64,
+    frame_start_cycles: u64,
+    log_regions: Vec<RangeInclusive<u16>>,
+    // Reads only borrow the MMU, so the log needs interior mutability
+    access_log: RefCell<VecDeque<(u64, bool, u16, u8)>>,
 }
 
+// Receives the finished frame buffer
+pub type VBlankCallback = Box<dyn FnMut(&[u8]) + Send>;
+
+// One M-cycle between the $FF46 write and the first byte being copied
+const DMA_SETUP_CYCLES: u32 = 4;
+
+// Oldest entries are dropped once the access log is full
+const ACCESS_LOG_SIZE: usize = 0x10000;
+
 bitflags! {
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, Hash)]
     pub struct Interrupts: u8 {
         const JOYPAD = 0b0001_0000;
         const SERIAL = 0b0000_1000;
@@ -39,111 +82,860 @@ bitflags! {
 }
 
 impl MMU {
-    pub fn new(mode: GBMode,  mbc_mode: MBCMode, print_serial: bool, rom: Vec<u8>) -> Self {
-        let mbc: Box<dyn MBC> = match mbc_mode {
-            MBCMode::RomOnly => Box::new(ROMOnly::new(rom)),
-            MBCMode::MBC1 => Box::new(MBC1::new(rom)),
-            MBCMode::MBC2 => Box::new(MBC2::new(rom)),
-            MBCMode::MBC3 => Box::new(MBC3::new(rom)),
-            MBCMode::MBC5 => Box::new(MBC5::new(rom)),
-            v => panic!("Unsupported MBC type! {:}", v)
-        };
+    pub fn new(mode: GBMode, clock: ClockSpeed, mbc_mode: MBCMode, print_serial: bool, sample_rate: Option<u32>, audio: bool, rom: Vec<u8>) -> Self {
+        MMU::with_cartridge(mode, clock, loader::new_mbc(mbc_mode, rom), print_serial, sample_rate, audio)
+    }
 
+    // Takes an already loaded cartridge, e.g. from loader::from_reader
+    pub fn with_cartridge(mode: GBMode, clock: ClockSpeed, mbc: Box<dyn MBC>, print_serial: bool, sample_rate: Option<u32>, audio: bool) -> Self {
         Self {
-            mbc: mbc,
-            apu: APU::new(),
+            mode,
+            mbc,
+            autosave: None,
+            boot_rom: None,
+            on_vblank: None,
+            apu: APU::new(mode, clock, sample_rate, audio),
             ppu: PPU::new(mode),
-            serial: Serial::new(print_serial),
+            serial: Serial::new(mode, print_serial),
+            infrared: Infrared::new(),
             joypad: Joypad::new(),
             timer: Timer::new(),
             wram: [0; 0x8000],
             hram: [0; 0x7f],
             intf: Interrupts::empty(),
             inte: Interrupts::empty(),
-            wram_bank: 0x01
+            wram_bank: 0x01,
+            undocumented: [0x00; 4],
+            double_speed: false,
+            dma_source: None,
+            dma_register: 0xFF,
+            dma_byte: 0xFF,
+            dma_cycles: 0,
+            dma_delay: 0,
+            dma_written: false,
+            speed_switch_armed: false,
+            write_watch: None,
+            write_watch_hit: false,
+            total_cycles: 0,
+            last_frame_cycles: 0,
+            frame_start_cycles: 0,
+            log_regions: Vec::new(),
+            access_log: RefCell::new(VecDeque::new())
         }
     }
 
+    // `cycles` is in CPU clocks, which run twice as fast in double speed mode.
+    // The timer and OAM DMA follow the CPU clock, but the PPU and APU stay at the normal rate.
     pub fn cycle(&mut self, cycles: u32) -> bool {
+        let normal_cycles = if self.double_speed { cycles / 2 } else { cycles };
+        self.total_cycles += cycles as u64;
+
         self.timer.cycle(cycles);
+        for _ in 0..self.timer.apu_ticks {
+            self.apu.clock_frame_sequencer();
+        }
+        self.timer.apu_ticks = 0;
+        self.cycle_dma(cycles);
         self.intf |= self.timer.interrupts;
         self.timer.interrupts = Interrupts::empty();
 
         self.intf |= self.joypad.interrupts;
         self.joypad.interrupts = Interrupts::empty();
 
-        let did_draw = self.ppu.cycle(cycles);
+        let did_draw = self.ppu.cycle(normal_cycles);
+        // The APU catches up first, so a frame ending here counts this instruction's samples
+        self.apu.cycle(normal_cycles);
+        if did_draw {
+            self.apu.end_frame();
+            if let Some(on_vblank) = self.on_vblank.as_mut() {
+                on_vblank(&self.ppu.frame_buffer);
+            }
+            self.last_frame_cycles = self.total_cycles - self.frame_start_cycles;
+            self.frame_start_cycles = self.total_cycles;
+        }
         self.intf |= self.ppu.interrupts;
         self.ppu.interrupts = Interrupts::empty();
 
-        self.apu.cycle(cycles);
-
+        self.serial.cycle(cycles);
         self.intf |= self.serial.interrupts;
         self.serial.interrupts = Interrupts::empty();
 
+        if self.autosave.as_ref().is_some_and(|s| s.is_idle()) {
+            self.save();
+        }
+
         did_draw
     }
 
+    // Puts I/O into the state the boot ROM would have left it in
+    pub fn apply_boot_values(&mut self, values: &BootValues) {
+        self.timer.set_div(values.div);
+        for &(a, v) in values.io {
+            self.write(a, v);
+        }
+    }
+
+    // Swaps in a new cartridge and resets everything else to power-on state. The outgoing
+    // cart's RAM is saved first, and its autosave is dropped since it points at that cart's file.
+    pub fn insert_cartridge(&mut self, mbc: Box<dyn MBC>) {
+        self.save();
+        self.autosave = None;
+        self.mbc = mbc;
+        self.boot_rom = None;
+
+        self.ppu.reset();
+        self.apu.reset();
+        self.timer = Timer::new();
+        self.wram = [0; 0x8000];
+        self.hram = [0; 0x7F];
+        self.intf = Interrupts::empty();
+        self.inte = Interrupts::empty();
+        self.wram_bank = 0x01;
+        self.undocumented = [0x00; 4];
+        self.double_speed = false;
+        self.dma_source = None;
+        self.dma_register = 0xFF;
+        self.dma_byte = 0xFF;
+        self.dma_cycles = 0;
+        self.dma_delay = 0;
+        self.dma_written = false;
+        self.speed_switch_armed = false;
+        self.last_frame_cycles = 0;
+        self.frame_start_cycles = self.total_cycles;
+    }
+
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn unmap_boot_rom(&mut self) {
+        self.boot_rom = None;
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    // CGB boot ROMs run past $00FF, leaving a hole at $0100-$01FF for the cartridge header
+    fn boot_rom_covers(&self, a: u16) -> bool {
+        match &self.boot_rom {
+            Some(boot_rom) => !(0x0100..0x0200).contains(&a) && (a as usize) < boot_rom.len(),
+            None => false,
+        }
+    }
+
+    // Should only be set for carts with battery-backed RAM
+    pub fn set_autosave(&mut self, autosave: Autosave) {
+        self.autosave = Some(autosave);
+    }
+
+    // An alternative to checking the return value of cycle, both keep working
+    pub fn set_vblank_callback(&mut self, on_vblank: VBlankCallback) {
+        self.on_vblank = Some(on_vblank);
+    }
+
+    // Called by STOP, only switches if armed through KEY1
+    pub fn switch_speed(&mut self) {
+        if self.speed_switch_armed {
+            self.double_speed = !self.double_speed;
+            self.timer.double_speed = self.double_speed;
+            self.speed_switch_armed = false;
+        }
+    }
+
+    pub fn set_write_watch(&mut self, a: Option<u16>) {
+        self.write_watch = a;
+        self.write_watch_hit = false;
+    }
+
+    pub fn take_write_watch_hit(&mut self) -> bool {
+        std::mem::take(&mut self.write_watch_hit)
+    }
+
+    // Debug accessors that skip PPU mode and DMA locks, write watches and access logs
+    pub fn peek(&self, a: u16) -> u8 {
+        match a {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.ppu.peek(a),
+            0xE000..=0xFDFF => self.peek(a - 0x2000),
+            _ => self.bus_read(a),
+        }
+    }
+
+    pub fn poke(&mut self, a: u16, v: u8) {
+        match a {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.ppu.poke(a, v),
+            0xE000..=0xFDFF => self.poke(a - 0x2000, v),
+            _ => self.bus_write(a, v),
+        }
+    }
+
+    pub fn set_deterministic(&mut self, div_seed: u16) {
+        self.timer.set_divider(div_seed);
+        self.mbc.set_deterministic();
+    }
+
+    pub fn last_frame_cycles(&self) -> u64 {
+        self.last_frame_cycles
+    }
+
+    // CPU clocks run since power on
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn rom_bank(&self) -> u16 {
+        self.mbc.current_rom_bank()
+    }
+
+    // Lets debuggers inspect the live banking state without knowing the cartridge type
+    pub fn mbc(&self) -> &dyn MBC {
+        self.mbc.as_ref()
+    }
+
+    // Records (cycle, is_write, address, value) for every CPU access inside `range`
+    pub fn log_region(&mut self, range: RangeInclusive<u16>) {
+        self.log_regions.push(range);
+    }
+
+    pub fn take_access_log(&mut self) -> Vec<(u64, bool, u16, u8)> {
+        self.access_log.get_mut().drain(..).collect()
+    }
+
+    fn log_access(&self, is_write: bool, a: u16, v: u8) {
+        if !self.log_regions.iter().any(|r| r.contains(&a)) {
+            return;
+        }
+
+        let mut log = self.access_log.borrow_mut();
+        if log.len() >= ACCESS_LOG_SIZE {
+            log.pop_front();
+        }
+        log.push_back((self.total_cycles, is_write, a, v));
+    }
+
+    pub fn load_save(&mut self, data: &[u8]) -> Result<(), SaveError> {
+        self.mbc.load_ram(data)
+    }
+
+    // Live cartridge RAM for save editors, None if the cart has none
+    pub fn cartridge_ram(&self) -> Option<&[u8]> {
+        Some(self.mbc.ram()).filter(|ram| !ram.is_empty())
+    }
+
+    // Edits are assumed, so the autosave is marked dirty
+    pub fn cartridge_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.mbc.ram().is_empty() {
+            return None;
+        }
+        if let Some(autosave) = self.autosave.as_mut() {
+            autosave.mark_dirty();
+        }
+        Some(self.mbc.ram_mut())
+    }
+
+    pub fn save(&mut self) {
+        if let Some(autosave) = self.autosave.as_mut() {
+            if let Err(e) = autosave.flush(self.mbc.ram()) {
+                println!("Failed to save cartridge RAM! {:?}", e);
+            }
+        }
+    }
+
     fn oamdma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
-        for i in 0 .. 0xA0 {
-            let b = self.read_word(base + i);
-            self.write_word(0xFE00 + i, b);
+        self.dma_register = value;
+        self.dma_source = Some((value as u16) << 8);
+        self.dma_cycles = 0;
+        self.dma_delay = DMA_SETUP_CYCLES;
+        self.dma_written = true;
+        self.ppu.oam_dma_active = false;
+    }
+
+    // After a one M-cycle setup, OAM DMA copies one byte per M-cycle, so OAM is fully
+    // written 161 M-cycles after the $FF46 write
+    fn cycle_dma(&mut self, cycles: u32) {
+        if std::mem::take(&mut self.dma_written) {
+            return;
+        }
+        self.dma_cycles += cycles;
+
+        if self.dma_source.is_some() && self.dma_delay > 0 {
+            let elapsed = self.dma_delay.min(self.dma_cycles);
+            self.dma_delay -= elapsed;
+            self.dma_cycles -= elapsed;
+            if self.dma_delay > 0 {
+                return;
+            }
+            self.ppu.oam_dma_active = true;
+        }
+
+        while let Some(source) = self.dma_source {
+            if self.dma_cycles < 4 {
+                break;
+            }
+            self.dma_cycles -= 4;
+
+            // Sources above $DFFF read from WRAM through echo RAM
+            let address = if source >= 0xE000 { source - 0x2000 } else { source };
+            self.dma_byte = self.bus_read(address);
+            self.ppu.write_oam((source & 0xFF) as usize, self.dma_byte);
+
+            self.dma_source = if source & 0xFF == 0x9F { None } else { Some(source + 1) };
+        }
+
+        if self.dma_source.is_none() {
+            self.dma_cycles = 0;
+            self.ppu.oam_dma_active = false;
         }
     }
-}
 
-impl Memory for MMU {
-    fn read(&self, a: u16) -> u8 {
+    // The CPU is locked out of everything below $FF00 once the transfer proper starts
+    fn dma_blocking(&self) -> bool {
+        self.dma_source.is_some() && self.dma_delay == 0
+    }
+
+    fn bus_read(&self, a: u16) -> u8 {
         match a {
+            0x0000..=0x08FF if self.boot_rom_covers(a) => self.boot_rom.as_ref().unwrap()[a as usize],
             0x0000..=0x7FFF => self.mbc.read(a),
             0x8000..=0x9FFF => self.ppu.read(a),
             0xA000..=0xBFFF => self.mbc.read(a),
             0xC000..=0xCFFF => self.wram[a as usize - 0xC000],
             0xD000..=0xDFFF => self.wram[a as usize - 0xD000 + 0x1000 * self.wram_bank],
-            0xE000..=0xEFFF => self.wram[a as usize - 0xE000],
-            0xF000..=0xFDFF => self.wram[a as usize - 0xF000 + 0x1000 * self.wram_bank],
+            // Echo RAM mirrors $C000-$DDFF
+            0xE000..=0xFDFF => self.bus_read(a - 0x2000),
             0xFE00..=0xFE9F => self.ppu.read(a),
+            0xFF46 => self.dma_register,
+            0xFF4D => {
+                if self.mode == GBMode::Color {
+                    ((self.double_speed as u8) << 7) | (self.speed_switch_armed as u8) | 0x7E
+                } else {
+                    0xFF
+                }
+            },
             0xFF40..=0xFF4F => self.ppu.read(a),
             0xFF68..=0xFF6B => self.ppu.read(a),
+            0xFF56 => {
+                if self.mode == GBMode::Color {
+                    self.infrared.read(a)
+                } else {
+                    0xFF
+                }
+            },
             0xFF80..=0xFFFE => self.hram[a as usize - 0xFF80],
             0xFF00 => self.joypad.read(a),
             0xFF01..=0xFF02 => self.serial.read(a),
             0xFF04..=0xFF07 => self.timer.read(a),
             0xFF10..=0xFF3F => self.apu.read(a),
-            0xFF0F => self.intf.bits(),
+            // Only 5 sources, the upper bits always read as 1
+            0xFF0F => self.intf.bits() | 0xE0,
+            0xFF50 => 0xFF,
             0xFF70 => self.wram_bank as u8,
+            0xFF72..=0xFF73 => self.undocumented[a as usize - 0xFF72],
+            0xFF74 => {
+                if self.mode == GBMode::Color {
+                    self.undocumented[2]
+                } else {
+                    0xFF
+                }
+            },
+            // Only bits 4-6 are implemented
+            0xFF75 => self.undocumented[3] | 0x8F,
             0xFEA0..=0xFEFF => 0xFF,
             0xFFFF => self.inte.bits(),
             _ => panic!("Read to unsupported address ({:#06x})!", a),
         }
     }
 
-    fn write(&mut self, a: u16, v: u8) {
+    fn bus_write(&mut self, a: u16, v: u8) {
         match a {
             0x0000..=0x7FFF => self.mbc.write(a, v),
             0x8000..=0x9FFF => self.ppu.write(a, v),
-            0xA000..=0xBFFF => self.mbc.write(a, v),
+            0xA000..=0xBFFF => {
+                // Writes with RAM disabled or an RTC register mapped leave the save alone
+                let ram_written = self.mbc.ram_writable();
+                self.mbc.write(a, v);
+                if ram_written {
+                    if let Some(autosave) = self.autosave.as_mut() {
+                        autosave.mark_dirty();
+                    }
+                }
+            },
             0xC000..=0xCFFF => self.wram[a as usize - 0xC000] = v,
             0xD000..=0xDFFF => self.wram[a as usize - 0xD000 + 0x1000 * self.wram_bank] = v,
-            0xE000..=0xEFFF => self.wram[a as usize - 0xE000] = v,
-            0xF000..=0xFDFF => self.wram[a as usize - 0xF000 + 0x1000 * self.wram_bank] = v,
+            // Echo RAM mirrors $C000-$DDFF
+            0xE000..=0xFDFF => self.bus_write(a - 0x2000, v),
             0xFE00..=0xFE9F => self.ppu.write(a, v),
             0xFF46 => self.oamdma(v),
+            // KEY0, only writable by the boot ROM, which sets bit 2 for DMG-only games
+            0xFF4C => {
+                if self.mode == GBMode::Color && self.boot_rom.is_some() {
+                    self.ppu.dmg_compat = v & 0x04 != 0;
+                }
+            },
+            0xFF4D => {
+                if self.mode == GBMode::Color {
+                    self.speed_switch_armed = v & 0x01 != 0;
+                }
+            },
             0xFF40..=0xFF4F => self.ppu.write(a, v),
             0xFF68..=0xFF6B => self.ppu.write(a, v),
+            0xFF56 => {
+                if self.mode == GBMode::Color {
+                    self.infrared.write(a, v);
+                }
+            },
             0xFF80..=0xFFFE => self.hram[a as usize - 0xFF80] = v,
             0xFF00 => self.joypad.write(a, v),
             0xFF01..=0xFF02 => self.serial.write(a, v),
             0xFF04..=0xFF07 => self.timer.write(a, v),
             0xFF10..=0xFF3F => self.apu.write(a, v),
             0xFF0F => self.intf = Interrupts::from_bits_truncate(v),
-            0xFF50..=0xFF5F => {},
+            // Unmapping the boot ROM can't be undone until reset
+            0xFF50 => {
+                if v & 0x01 != 0 {
+                    self.boot_rom = None;
+                }
+            },
+            0xFF51..=0xFF5F => {},
             0xFF70 => self.wram_bank = match v & 0x07 { 0 => 1, n => n as usize },
+            0xFF72..=0xFF73 => self.undocumented[a as usize - 0xFF72] = v,
+            0xFF74 => {
+                if self.mode == GBMode::Color {
+                    self.undocumented[2] = v;
+                }
+            },
+            0xFF75 => self.undocumented[3] = v & 0x70,
             0xFEA0..=0xFEFF => {},
             0xFF7F => {},
             0xFFFF => self.inte = Interrupts::from_bits_truncate(v),
             _ => panic!("Write to unsupported address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Hash for MMU {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.mbc.hash_state(state);
+        self.boot_rom.is_some().hash(state);
+        self.ppu.hash(state);
+        self.apu.hash(state);
+        self.serial.hash(state);
+        self.infrared.hash(state);
+        self.timer.hash(state);
+        self.joypad.hash(state);
+        self.wram.hash(state);
+        self.hram.hash(state);
+        self.intf.bits().hash(state);
+        self.inte.bits().hash(state);
+        self.wram_bank.hash(state);
+        self.undocumented.hash(state);
+        self.double_speed.hash(state);
+        self.speed_switch_armed.hash(state);
+        self.dma_source.hash(state);
+        self.dma_register.hash(state);
+        self.dma_byte.hash(state);
+        self.dma_cycles.hash(state);
+        self.dma_delay.hash(state);
+        self.dma_written.hash(state);
+    }
+}
+
+impl Drop for MMU {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+impl Memory for MMU {
+    // During OAM DMA the CPU can only reach HRAM and the IO registers,
+    // anything else sees the byte currently being transferred
+    fn read(&self, a: u16) -> u8 {
+        let v = if self.dma_blocking() && a < 0xFF00 {
+            self.dma_byte
+        } else {
+            self.bus_read(a)
+        };
+
+        self.log_access(false, a, v);
+        v
+    }
+
+    fn write(&mut self, a: u16, v: u8) {
+        if self.write_watch == Some(a) {
+            self.write_watch_hit = true;
+        }
+        self.log_access(true, a, v);
+
+        if self.dma_blocking() && a < 0xFF00 {
+            return;
+        }
+
+        self.bus_write(a, v);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn mmu(mode: GBMode) -> MMU {
+        MMU::new(mode, ClockSpeed::Standard, MBCMode::RomOnly, false, None, false, vec![0x00; 0x8000])
+    }
+
+    // 32KB MBC1+RAM+BATTERY cart with one 8KB RAM bank
+    fn battery_mmu() -> MMU {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        MMU::new(GBMode::Classic, ClockSpeed::Standard, MBCMode::MBC1, false, None, false, rom)
+    }
+
+    // Keeps hold of what an Autosave writes after it takes ownership of the writer
+    #[derive(Clone)]
+    struct SharedWriter(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl SharedWriter {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Cursor::new(Vec::new()))))
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().get_ref().clone()
+        }
+    }
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SharedWriter {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.lock().unwrap().seek(pos)
+        }
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut mmu = mmu(GBMode::Classic);
+        mmu.write(0xC123, 0x42);
+        assert_eq!(mmu.read(0xE123), 0x42);
+        mmu.write(0xE456, 0x24);
+        assert_eq!(mmu.read(0xC456), 0x24);
+    }
+
+    #[test]
+    fn echo_ram_follows_the_wram_bank() {
+        let mut mmu = mmu(GBMode::Color);
+        mmu.write(0xFF70, 0x02);
+        mmu.write(0xD123, 0x42);
+        assert_eq!(mmu.read(0xF123), 0x42);
+
+        mmu.write(0xFF70, 0x03);
+        assert_ne!(mmu.read(0xF123), 0x42);
+        mmu.write(0xF123, 0x24);
+        assert_eq!(mmu.read(0xD123), 0x24);
+    }
+
+    #[test]
+    fn autosave_flushes_once_idle() {
+        let writer = SharedWriter::new();
+        let mut mmu = battery_mmu();
+        mmu.set_autosave(Autosave::new(Box::new(writer.clone()), Duration::ZERO));
+
+        // Nothing is written until the RAM changes
+        mmu.cycle(4);
+        assert!(writer.contents().is_empty());
+
+        mmu.write(0x0000, 0x0A);
+        mmu.write(0xA001, 0x42);
+        mmu.cycle(4);
+
+        let save = writer.contents();
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[1], 0x42);
+    }
+
+    #[test]
+    fn autosave_flushes_on_drop() {
+        let writer = SharedWriter::new();
+        let mut mmu = battery_mmu();
+        mmu.set_autosave(Autosave::new(Box::new(writer.clone()), Duration::from_secs(3600)));
+
+        mmu.write(0x0000, 0x0A);
+        mmu.write(0xA000, 0x42);
+        mmu.cycle(4);
+        assert!(writer.contents().is_empty());
+
+        drop(mmu);
+        assert_eq!(writer.contents()[0], 0x42);
+    }
+
+    #[test]
+    fn autosave_ignores_writes_that_miss_ram() {
+        let writer = SharedWriter::new();
+        let mut mmu = battery_mmu();
+        mmu.set_autosave(Autosave::new(Box::new(writer.clone()), Duration::ZERO));
+        // RAM is disabled at power on
+        mmu.write(0xA000, 0x42);
+        mmu.cycle(4);
+        assert!(writer.contents().is_empty());
+
+        // MBC3+TIMER+RAM+BATTERY with the seconds register mapped
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x02;
+        let writer = SharedWriter::new();
+        let mut mmu = MMU::new(GBMode::Classic, ClockSpeed::Standard, MBCMode::MBC3, false, None, false, rom);
+        mmu.set_autosave(Autosave::new(Box::new(writer.clone()), Duration::ZERO));
+        mmu.write(0x0000, 0x0A);
+        mmu.write(0x4000, 0x08);
+        mmu.write(0xA000, 0x12);
+        mmu.cycle(4);
+        assert!(writer.contents().is_empty());
+
+        mmu.write(0x4000, 0x00);
+        mmu.write(0xA000, 0x42);
+        mmu.cycle(4);
+        assert_eq!(writer.contents()[0], 0x42);
+    }
+
+    #[test]
+    fn load_save_accepts_a_matching_save() {
+        let mut mmu = battery_mmu();
+        let mut save = vec![0x00; 0x2000];
+        save[0] = 0x42;
+        assert!(mmu.load_save(&save).is_ok());
+        assert_eq!(mmu.cartridge_ram().unwrap()[0], 0x42);
+    }
+
+    #[test]
+    fn load_save_rejects_an_undersized_save() {
+        let mut mmu = battery_mmu();
+        assert!(matches!(
+            mmu.load_save(&[0x00; 0x1000]),
+            Err(SaveError::SizeMismatch { expected: 0x2000, actual: 0x1000 })
+        ));
+    }
+
+    #[test]
+    fn load_save_applies_the_mbc3_rtc_trailer() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x02;
+        let mut mmu = MMU::new(GBMode::Classic, ClockSpeed::Standard, MBCMode::MBC3, false, None, false, rom);
+
+        let mut save = vec![0x00; 0x2000 + 48];
+        save[0x2000] = 0x17;
+        save[0x2000 + 4] = 0x2A;
+        assert!(mmu.load_save(&save).is_ok());
+
+        mmu.write(0x0000, 0x0A);
+        mmu.write(0x4000, 0x08);
+        assert_eq!(mmu.read(0xA000), 0x17);
+        mmu.write(0x4000, 0x09);
+        assert_eq!(mmu.read(0xA000), 0x2A);
+    }
+
+    #[test]
+    fn double_speed_keeps_apu_pitch() {
+        let mut mmu = mmu(GBMode::Color);
+        mmu.write(0xFF26, 0x80);
+        mmu.write(0xFF13, 0x00);
+        mmu.write(0xFF14, 0x07);
+        let hz = mmu.apu.channel_frequency_hz(1);
+
+        mmu.write(0xFF4D, 0x01);
+        mmu.switch_speed();
+        assert!(mmu.double_speed());
+        assert_eq!(mmu.apu.channel_frequency_hz(1), hz);
+    }
+
+    fn start_dma(mmu: &mut MMU) {
+        for i in 0..0xA0 {
+            mmu.write(0xC000 + i, i as u8 ^ 0x5A);
+        }
+        mmu.write(0xFF80, 0x42);
+        mmu.write(0xFF46, 0xC0);
+        // The write's own M-cycle, which the CPU reports once the instruction is done
+        mmu.cycle(4);
+    }
+
+    #[test]
+    fn dma_conflicts_with_reads_outside_hram() {
+        let mut mmu = mmu(GBMode::Classic);
+        start_dma(&mut mmu);
+        mmu.cycle(DMA_SETUP_CYCLES + 10 * 4);
+
+        assert_eq!(mmu.read(0x0150), 9 ^ 0x5A);
+        assert_eq!(mmu.read(0xC000), 9 ^ 0x5A);
+        assert_eq!(mmu.read(0xFF80), 0x42);
+    }
+
+    #[test]
+    fn dma_runs_on_the_cpu_clock_in_double_speed() {
+        let mut mmu = mmu(GBMode::Color);
+        mmu.write(0xFF4D, 0x01);
+        mmu.switch_speed();
+        start_dma(&mut mmu);
+        mmu.cycle(DMA_SETUP_CYCLES + 160 * 4);
+
+        assert_eq!(mmu.read(0xC000), 0x5A);
+        assert_eq!(mmu.ppu.peek(0xFE9F), 0x9F ^ 0x5A);
+    }
+
+    #[test]
+    fn undocumented_registers_apply_their_masks() {
+        let mut color = mmu(GBMode::Color);
+        for a in [0xFF72, 0xFF73, 0xFF74] {
+            color.write(a, 0xA5);
+            assert_eq!(color.read(a), 0xA5);
+        }
+        color.write(0xFF75, 0xFF);
+        assert_eq!(color.read(0xFF75), 0xFF);
+        color.write(0xFF75, 0x00);
+        assert_eq!(color.read(0xFF75), 0x8F);
+
+        // $FF74 is locked outside CGB mode
+        let mut classic = mmu(GBMode::Classic);
+        classic.write(0xFF74, 0xA5);
+        assert_eq!(classic.read(0xFF74), 0xFF);
+    }
+
+    #[test]
+    fn div_writes_clock_the_frame_sequencer() {
+        for (write_div, length) in [(false, 0x30), (true, 0x31)] {
+            let mut mmu = mmu(GBMode::Classic);
+            mmu.write(0xFF12, 0xF0);
+            mmu.write(0xFF11, 0x30);
+            mmu.write(0xFF14, 0xC0);
+
+            // DIV bit 4 is set, so resetting DIV is a falling edge
+            mmu.timer.set_divider(0x1000);
+            if write_div {
+                mmu.write(0xFF04, 0x00);
+            }
+            mmu.cycle(4);
+            assert_eq!(mmu.apu.channel_state(1).length, length, "DIV written: {}", write_div);
+        }
+
+        // The write resets the low byte of the divider too, so the next length clock is a full
+        // 64 DIV increments (two falling edges) away however far the old divider had counted
+        let mut mmu = mmu(GBMode::Classic);
+        mmu.write(0xFF12, 0xF0);
+        mmu.write(0xFF11, 0x30);
+        mmu.write(0xFF14, 0xC0);
+        mmu.timer.set_divider(0x10F0);
+        mmu.write(0xFF04, 0x00);
+        mmu.cycle(0xFC);
+        assert_eq!(mmu.read(0xFF04), 0x00);
+        mmu.cycle(0x4000 - 0x100);
+        assert_eq!(mmu.apu.channel_state(1).length, 0x31);
+        mmu.cycle(4);
+        assert_eq!(mmu.read(0xFF04), 0x40);
+        assert_eq!(mmu.apu.channel_state(1).length, 0x32);
+    }
+
+    #[test]
+    fn access_log_captures_ppu_register_writes_in_order() {
+        let mut mmu = mmu(GBMode::Classic);
+        mmu.log_region(0xFF40..=0xFF4B);
+
+        mmu.write(0xFF47, 0xE4);
+        mmu.cycle(4);
+        mmu.write(0xFF42, 0x10);
+        // Outside the region
+        mmu.write(0xFF26, 0x80);
+        mmu.write(0xFF40, 0x91);
+        mmu.read(0xFF42);
+
+        assert_eq!(mmu.take_access_log(), vec![
+            (0, true, 0xFF47, 0xE4),
+            (4, true, 0xFF42, 0x10),
+            (4, true, 0xFF40, 0x91),
+            (4, false, 0xFF42, 0x10)
+        ]);
+        assert!(mmu.take_access_log().is_empty());
+    }
+
+    #[test]
+    fn oam_writes_during_dma_are_dropped() {
+        let mut mmu = mmu(GBMode::Classic);
+        start_dma(&mut mmu);
+        mmu.cycle(DMA_SETUP_CYCLES + 10 * 4);
+        mmu.write(0xFE05, 0x77);
+        assert!(mmu.ppu.oam_dma_active);
+
+        mmu.cycle(150 * 4);
+        assert!(!mmu.ppu.oam_dma_active);
+        assert_eq!(mmu.ppu.peek(0xFE05), 0x05 ^ 0x5A);
+    }
+
+    #[test]
+    fn peek_and_poke_reach_vram_during_mode_3() {
+        let mut mmu = mmu(GBMode::Classic);
+        mmu.write(0xFF40, 0x91);
+        while mmu.read(0xFF41) & 0x03 != 3 {
+            mmu.cycle(4);
+        }
+
+        mmu.write(0x8000, 0x12);
+        assert_eq!(mmu.read(0x8000), 0xFF);
+        assert_eq!(mmu.peek(0x8000), 0x00);
+
+        mmu.poke(0x8000, 0x34);
+        mmu.poke(0xFE00, 0x56);
+        assert_eq!(mmu.read(0x8000), 0xFF);
+        assert_eq!(mmu.peek(0x8000), 0x34);
+        assert_eq!(mmu.peek(0xFE00), 0x56);
+    }
+
+    #[test]
+    fn vblank_callback_fires_once_per_frame() {
+        let mut mmu = mmu(GBMode::Classic);
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let pushed = frames.clone();
+        mmu.set_vblank_callback(Box::new(move |frame| pushed.lock().unwrap().push(frame.len())));
+        mmu.write(0xFF40, 0x91);
+
+        while !mmu.cycle(4) {}
+        // Another line in, still inside the same VBlank
+        for _ in 0..114 {
+            assert!(!mmu.cycle(4));
+        }
+        assert_eq!(*frames.lock().unwrap(), vec![4 * crate::ppu::SCREEN_W * crate::ppu::SCREEN_H]);
+    }
+
+    #[test]
+    fn dma_completes_exactly_160_m_cycles_after_setup() {
+        let mut mmu = mmu(GBMode::Classic);
+        // No source byte is $FF, so untouched OAM can't pass as copied
+        for i in 0..0xA0 {
+            mmu.ppu.poke(0xFE00 + i, 0xFF);
+        }
+        start_dma(&mut mmu);
+        let copied = |mmu: &MMU| (0..0xA0).take_while(|&i| mmu.ppu.peek(0xFE00 + i) == i as u8 ^ 0x5A).count();
+
+        // The setup M-cycle copies nothing and leaves OAM unlocked
+        mmu.cycle(DMA_SETUP_CYCLES);
+        assert_eq!(copied(&mmu), 0);
+        for m_cycle in 1..=160 {
+            assert!(mmu.ppu.oam_dma_active, "M-cycle {}", m_cycle);
+            mmu.cycle(4);
+            assert_eq!(copied(&mmu), m_cycle);
+        }
+        assert!(!mmu.ppu.oam_dma_active);
+        assert_eq!(mmu.read(0xC000), 0x5A);
+    }
+}
@@ -1,5 +1,143 @@
+use num_derive::FromPrimitive;
+use crate::{AUDIO_LATENCY, STEP_TIME};
+use crate::timing::{CPU_HZ, SGB_CPU_HZ};
+use crate::ppu::DmgPalette;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum GBMode {
     Classic,
     Color,
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Copy, PartialEq, FromPrimitive)]
+pub enum Model {
+    DMG = 0,
+    // Game Boy Pocket
+    MGB,
+    SGB,
+    CGB,
+    // Game Boy Advance running in CGB mode
+    AGB,
+}
+
+impl Model {
+    pub fn mode(&self) -> GBMode {
+        match self {
+            Model::DMG | Model::MGB | Model::SGB => GBMode::Classic,
+            Model::CGB | Model::AGB => GBMode::Color,
+        }
+    }
+
+    // The Pocket's LCD is true greyscale, the rest get the original DMG's green tint
+    pub fn default_palette(&self) -> DmgPalette {
+        match self {
+            Model::MGB => DmgPalette::Grayscale,
+            _ => DmgPalette::Green,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClockSpeed {
+    Standard,
+    // The SGB derives its clock from the SNES, running about 2.4% faster
+    SGB,
+}
+
+impl ClockSpeed {
+    pub fn frequency(&self) -> u32 {
+        match self {
+            ClockSpeed::Standard => CPU_HZ,
+            ClockSpeed::SGB => SGB_CPU_HZ,
+        }
+    }
+
+    // Cycles emulated per STEP_TIME ms of real time
+    pub fn step_cycles(&self) -> u32 {
+        (STEP_TIME as f64 / (1000_f64 / self.frequency() as f64)) as u32
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    // Pace emulation to real time in STEP_TIME slices (~59.7 fps)
+    Video,
+    // Pace emulation to how much audio the output device has consumed
+    Audio,
+}
+
+// SyncMode::Audio bookkeeping: compares emulated time against the frames the device has played
+pub struct AudioPacer {
+    clock: ClockSpeed,
+    sample_rate: u32,
+    // Cycles run and audio frames played since pacing last (re)started
+    cycles: u64,
+    played_zero: u64
+}
+
+impl AudioPacer {
+    pub fn new(clock: ClockSpeed, sample_rate: u32, played: u64) -> Self {
+        Self {
+            clock,
+            sample_rate,
+            cycles: 0,
+            played_zero: played
+        }
+    }
+
+    // Forgets everything run so far, e.g. after a pause
+    pub fn restart(&mut self, played: u64) {
+        self.cycles = 0;
+        self.played_zero = played;
+    }
+
+    pub fn add_cycles(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    // Whether emulation is more than AUDIO_LATENCY ahead of `played`, the device's total frames
+    pub fn should_wait(&mut self, played: u64) -> bool {
+        let emulated = self.cycles as f64 / self.clock.frequency() as f64;
+        let elapsed = played.saturating_sub(self.played_zero) as f64 / self.sample_rate as f64;
+        // Start over rather than racing to catch up after falling far behind
+        if elapsed - emulated > AUDIO_LATENCY * 4.0 {
+            self.restart(played);
+            return false;
+        }
+        emulated - elapsed > AUDIO_LATENCY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_pacing_follows_the_device() {
+        let sample_rate = 48_000;
+        let mut pacer = AudioPacer::new(ClockSpeed::Standard, sample_rate, 0);
+
+        // A device consuming 48 frames every simulated millisecond, for one second
+        let mut emulated = 0_u64;
+        for ms in 1..=1000 {
+            let played = ms * sample_rate as u64 / 1000;
+            while !pacer.should_wait(played) {
+                pacer.add_cycles(4);
+                emulated += 4;
+            }
+        }
+
+        // Frames the emulated time would have produced, at most AUDIO_LATENCY ahead
+        let produced = emulated as f64 * sample_rate as f64 / CPU_HZ as f64;
+        assert!(produced >= sample_rate as f64);
+        assert!(produced <= sample_rate as f64 * (1.0 + AUDIO_LATENCY) + 1.0);
+    }
+
+    #[test]
+    fn a_device_counter_behind_the_start_counts_as_nothing_played() {
+        let mut pacer = AudioPacer::new(ClockSpeed::Standard, 48_000, 1000);
+        assert!(!pacer.should_wait(500));
+        pacer.add_cycles(CPU_HZ);
+        assert!(pacer.should_wait(500));
+    }
+}
@@ -1,10 +1,22 @@
+use std::hash::{Hash, Hasher};
 use bitflags::{bitflags, Flags};
 use crate::memory::Memory;
 use crate::mmu::Interrupts;
 use crate::mode::GBMode;
+use crate::scale2x::scale2x;
+use crate::timing::{MODE3_MIN_DOTS, OAM_SCAN_DOTS, SCANLINE_DOTS, VBLANK_LINES, VISIBLE_LINES};
 
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
+// dump_tiles output size in pixels
+pub const TILES_W: usize = 16 * 8;
+pub const TILES_H: usize = 24 * 8;
+// Cycles into line 153 before LY reads 0
+const LY_153_CYCLES: u32 = 4;
+// Every sprite fetch stalls the pixel pipeline for at least this long
+const SPRITE_FETCH_CYCLES: u32 = 6;
+// Sprites at OAM X 0 wait out a full background fetch instead
+const SPRITE_X0_CYCLES: u32 = 11;
 
 pub struct PPU {
     mode: GBMode,
@@ -17,19 +29,119 @@ pub struct PPU {
     lc: u8,
     wy: u8,
     wx: u8,
+    // Internal window row, only advances on lines the window was drawn on
+    window_line: u8,
+    // Set once LY == WY on a frame, WY changes after that don't hide the window
+    window_triggered: bool,
+    // The first line after the LCD is enabled skips Mode 2, reading as Mode 0 instead
+    first_line: bool,
     bgp: u8,
     op0: u8,
     op1: u8,
     lcdc: LCDC,
     lcds: LCDS,
+    stat_line: bool,
     ram: [u8; 0x4000],
     ram_bank: usize,
     oam: [u8; 0xA0],
     bgprio: [Priority; SCREEN_W],
+    // Sprites picked during mode 2 for the current line, as (OAM index, OAM entry)
+    line_sprites: Vec<(usize, [u8; 4])>,
+    // Length of Mode 3 on the current line, latched with line_sprites
+    mode_3_cycles: u32,
+    dmg_palette: [(u8, u8, u8); 4],
+    // CGB palette RAM, 8 palettes of 4 RGB555 colours each
+    bcps: u8,
+    bg_palette_ram: [u8; 0x40],
+    ocps: u8,
+    obj_palette_ram: [u8; 0x40],
+    // CGB running a DMG-only game. Tile attributes are ignored and colours go through
+    // BGP, OBP0 and OBP1 into BG palette 0 and OBJ palettes 0-1.
+    pub dmg_compat: bool,
+    pub sprite_limit_enabled: bool,
+    // Shown over the game image while the LCD is off
+    pub disabled_color: (u8, u8, u8),
+    // Fixed colours for dump_tiles and dump_tilemap, None uses the live BG palette
+    pub viewer_palette: Option<[(u8, u8, u8); 4]>,
+    // Set by the MMU while OAM DMA runs, locking the CPU out of OAM
+    pub oam_dma_active: bool,
     pub interrupts: Interrupts,
+    // Frame buffer dimensions, the game image is centered inside them
+    output_w: usize,
+    output_h: usize,
+    // Set when the frame buffer changes, cleared by take_frame_dirty
+    frame_dirty: bool,
+    // Set on any VRAM write, cleared by take_vram_dirty
+    vram_dirty: bool,
+    // Scale2x copy of the game image, only produced when enabled
+    upscaled_buffer: Option<Vec<u8>>,
+    // What drew each pixel of the game image, only tracked when enabled
+    pixel_sources: Option<Vec<PixelSource>>,
+    // Lines are drawn here, the game image is copied to frame_buffer once the frame is complete
+    back_buffer: Vec<u8>,
+    // Last complete frame
     pub frame_buffer: Vec<u8>
 }
 
+#[derive(PartialEq, Copy, Clone, FromPrimitive, Debug)]
+pub enum DmgPalette {
+    Green = 0,
+    Grayscale = 1,
+    Light = 2,
+    Kirokaze = 3,
+    IceCream = 4
+}
+
+impl DmgPalette {
+    // Shades from lightest to darkest
+    pub fn colors(&self) -> [(u8, u8, u8); 4] {
+        match self {
+            DmgPalette::Green => [(175, 203, 70), (121, 170, 109), (34, 111, 95), (8, 41, 85)],
+            DmgPalette::Grayscale => [(255, 255, 255), (170, 170, 170), (85, 85, 85), (0, 0, 0)],
+            DmgPalette::Light => [(0, 181, 129), (0, 154, 113), (0, 105, 74), (0, 79, 59)],
+            DmgPalette::Kirokaze => [(226, 243, 228), (148, 227, 68), (70, 135, 143), (51, 44, 80)],
+            DmgPalette::IceCream => [(255, 246, 211), (249, 168, 117), (235, 107, 111), (124, 63, 88)],
+        }
+    }
+}
+
+// The CGB boot ROM's manually selectable palettes for DMG games, as BG, OBJ0 and OBJ1.
+// Indices follow the button combos: Up, Up+A, Up+B, Left, Left+A, Left+B, Down, Down+A, Down+B,
+// Right, Right+A, Right+B
+const COMPAT_PALETTES: [[[u32; 4]; 3]; 12] = [
+    [[0xFFFFFF, 0xFFAD63, 0x843100, 0x000000], [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000], [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]],
+    [[0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000], [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000], [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]],
+    [[0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108], [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000], [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]],
+    [[0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000], [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000], [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000]],
+    [[0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000], [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000], [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]],
+    [[0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000], [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000], [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]],
+    [[0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000], [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000], [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]],
+    [[0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000], [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000], [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]],
+    [[0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000], [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000], [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000]],
+    [[0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000], [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000], [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000]],
+    [[0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000], [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000], [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000]],
+    [[0x000000, 0x008484, 0xFFDE00, 0xFFFFFF], [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF], [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]]
+];
+
+// What the CGB boot ROM gives DMG games it doesn't recognise, the same as Right+A. Its
+// table of per-title palettes for Nintendo's own games isn't reproduced.
+pub const DEFAULT_COMPAT_PALETTE: u8 = 10;
+
+// Decoded palettes as RGB888, lightest to darkest on DMG
+#[derive(Clone, Debug)]
+pub struct Palettes {
+    pub bg: Vec<[(u8, u8, u8); 4]>,
+    pub obj: Vec<[(u8, u8, u8); 4]>
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum PixelSource {
+    BG,
+    Window,
+    // OAM index of the sprite
+    Sprite(u8)
+}
+
 #[derive(PartialEq, Copy, Clone)]
 enum Priority {
     Color0,
@@ -38,7 +150,7 @@ enum Priority {
 }
 
 #[derive(PartialEq, Copy, Clone)]
-enum PPUMode {
+pub enum PPUMode {
     OAMScan = 2,
     Draw = 3,
     HBlank = 0,
@@ -107,20 +219,62 @@ impl PPU {
             lc: 0x00,
             wy: 0x00,
             wx: 0x00,
-            bgp: 0x00,
-            op0: 0x00,
-            op1: 0x01,
+            window_line: 0x00,
+            window_triggered: false,
+            first_line: false,
+            // The boot ROM sets BGP, OBP0 and OBP1 are left uninitialised
+            bgp: 0xFC,
+            op0: 0xFF,
+            op1: 0xFF,
             lcdc: LCDC::empty(),
             lcds: LCDS::empty(),
+            stat_line: false,
             ram: [0; 0x4000],
             ram_bank: 0,
             oam: [0; 0xA0],
             bgprio: [Priority::Normal; SCREEN_W],
+            line_sprites: Vec::with_capacity(10),
+            mode_3_cycles: MODE3_MIN_DOTS,
+            dmg_palette: DmgPalette::Green.colors(),
+            bcps: 0x00,
+            bg_palette_ram: [0; 0x40],
+            ocps: 0x00,
+            obj_palette_ram: [0; 0x40],
+            dmg_compat: false,
+            sprite_limit_enabled: true,
+            disabled_color: (0xFF, 0xFF, 0xFF),
+            viewer_palette: None,
+            oam_dma_active: false,
             interrupts: Interrupts::empty(),
+            output_w: SCREEN_W,
+            output_h: SCREEN_H,
+            frame_dirty: true,
+            vram_dirty: true,
+            upscaled_buffer: None,
+            pixel_sources: None,
+            back_buffer: vec![0x00; 4 * SCREEN_W * SCREEN_H],
             frame_buffer: vec![0x00; 4 * SCREEN_W * SCREEN_H]
         }
     }
 
+    // Back to power-on state, keeping frontend settings like the palette and output size
+    pub fn reset(&mut self) {
+        *self = Self {
+            dmg_palette: self.dmg_palette,
+            sprite_limit_enabled: self.sprite_limit_enabled,
+            disabled_color: self.disabled_color,
+            viewer_palette: self.viewer_palette,
+            output_w: self.output_w,
+            output_h: self.output_h,
+            upscaled_buffer: self.upscaled_buffer.take(),
+            pixel_sources: self.pixel_sources.take(),
+            back_buffer: std::mem::take(&mut self.back_buffer),
+            frame_buffer: std::mem::take(&mut self.frame_buffer),
+            ..Self::new(self.mode)
+        };
+        self.clear_screen();
+    }
+
     pub fn cycle(&mut self, cycles: u32) -> bool {
         if !self.lcdc.contains(LCDC::LCD_ENABLE) {
             return false;
@@ -128,33 +282,31 @@ impl PPU {
 
         self.cycle_count += cycles;
 
-        if self.ly == self.lc {
-            if self.lcds.contains(LCDS::LYC_SELECT) {
-                self.interrupts |= Interrupts::LCD;
-            }
-        }
-
         return match self.ppu_mode {
             PPUMode::OAMScan => {
-                if self.cycle_count > 80 {
-                    self.cycle_count -= 80;
-                    self.ppu_mode = PPUMode::Draw;
+                if self.cycle_count > OAM_SCAN_DOTS {
+                    self.cycle_count -= OAM_SCAN_DOTS;
+                    self.start_draw();
                     // println!("[PPU] Switching to Draw!");
                 }
                 false
             },
+            PPUMode::HBlank if self.first_line => {
+                if self.cycle_count > OAM_SCAN_DOTS {
+                    self.cycle_count -= OAM_SCAN_DOTS;
+                    self.first_line = false;
+                    self.start_draw();
+                }
+                false
+            },
             PPUMode::Draw => {
-                // TODO: Allow variable length Mode 3
-                if self.cycle_count > 172 {
+                // Sprite fetches lengthen Mode 3, and HBlank is shortened to match
+                if self.cycle_count > self.mode_3_cycles {
                     self.ppu_mode = PPUMode::HBlank;
-                    if self.lcds.contains(LCDS::MODE_0_SELECT) {
-                        self.interrupts |= Interrupts::LCD;
-                    }
-                    if self.mode == GBMode::Color || self.lcdc.contains(LCDC::WINDOW_PRIORITY) {
-                        self.draw_bg();
-                    }
-                    if self.lcdc.contains(LCDC::OBJ_ENABLE) {
-                        self.draw_sprites();
+                    self.update_stat();
+                    self.draw_line();
+                    if self.window_visible() {
+                        self.window_line = self.window_line.wrapping_add(1);
                     }
                     // println!("[PPU] Switching to HBlank!");
                     false
@@ -163,23 +315,22 @@ impl PPU {
                 }
             },
             PPUMode::HBlank => {
-                if self.cycle_count > 456 {
+                // The count restarted when Mode 3 began, so the rest of the line is what's left
+                if self.cycle_count > SCANLINE_DOTS - OAM_SCAN_DOTS {
                     self.ly += 1;
-                    self.cycle_count -= 456;
+                    self.cycle_count -= SCANLINE_DOTS - OAM_SCAN_DOTS;
 
-                    return if self.ly > 143 {
+                    return if self.ly as u32 >= VISIBLE_LINES {
                         self.ppu_mode = PPUMode::VBlank;
                         self.interrupts |= Interrupts::V_BLANK;
-                        if self.lcds.contains(LCDS::MODE_1_SELECT) {
-                            self.interrupts |= Interrupts::LCD;
-                        }
+                        self.update_stat();
+                        self.present();
+                        self.upscale();
                         true
                         // println!("[PPU] Switching to VBlank!");
                     } else {
                         self.ppu_mode = PPUMode::OAMScan;
-                        if self.lcds.contains(LCDS::MODE_2_SELECT) {
-                            self.interrupts |= Interrupts::LCD;
-                        }
+                        self.update_stat();
                         false
                         // println!("[PPU] Switching to OAMScan!");
                     }
@@ -187,48 +338,441 @@ impl PPU {
                 false
             },
             PPUMode::VBlank => {
-                if self.cycle_count > 456 {
-                    self.cycle_count -= 456;
+                // LY only reads 153 for the first few cycles of the last line, then 0 until
+                // the frame wraps, so LYC=0 matches early
+                if self.ly == 153 && self.cycle_count >= LY_153_CYCLES {
+                    self.ly = 0;
+                    self.update_stat();
+                }
+
+                if self.cycle_count > SCANLINE_DOTS {
+                    self.cycle_count -= SCANLINE_DOTS;
                     self.vblanked_lines += 1;
 
-                    if self.vblanked_lines >= 10 {
+                    if self.vblanked_lines >= VBLANK_LINES {
                         self.vblanked_lines = 0;
                         self.ly = 0;
+                        self.window_line = 0;
+                        self.window_triggered = false;
                         self.ppu_mode = PPUMode::OAMScan;
-                        if self.lcds.contains(LCDS::MODE_2_SELECT) {
-                            self.interrupts |= Interrupts::LCD;
-                        }
                         // println!("[PPU] Switching to OAMScan!");
                     } else {
                         self.ly += 1;
                     }
+                    self.update_stat();
                 }
                 false
             }
         }
     }
 
-    fn grey_to_l(v: u8, i: usize) -> (u8, u8, u8) {
-        match v >> (2 * i) & 0x03 {
-            0x00 => (175, 203, 70),
-            0x01 => (121, 170, 109),
-            0x02 => (34, 111, 95),
-            _ => (8, 41, 85)
+    fn start_draw(&mut self) {
+        self.ppu_mode = PPUMode::Draw;
+        self.update_stat();
+        self.scan_oam();
+        if self.lcdc.contains(LCDC::WINDOW_ENABLE) && self.wy == self.ly {
+            self.window_triggered = true;
+        }
+        self.mode_3_cycles = MODE3_MIN_DOTS + self.sprite_penalty();
+    }
+
+    // Whether any of the window was on screen for the current line
+    fn window_visible(&self) -> bool {
+        self.lcdc.contains(LCDC::WINDOW_ENABLE) && self.window_triggered && self.wx <= 166
+    }
+
+    // The PPU fetches from both banks during Mode 3, so the lock covers all of VRAM
+    // whichever bank $FF4F selects. $FF4F itself stays readable and writable.
+    fn vram_locked(&self) -> bool {
+        self.ppu_mode == PPUMode::Draw
+    }
+
+    // Combined STAT condition, interrupts are only raised on its rising edge
+    pub fn stat_line(&self) -> bool {
+        self.stat_line
+    }
+
+    // Test hook that jumps straight to the start of a mode on any line. The STAT line is
+    // re-evaluated as if the PPU got there itself, so a new match raises the interrupt.
+    pub fn set_registers_for_test(&mut self, mode: PPUMode, ly: u8, lc: u8) {
+        self.ppu_mode = mode;
+        self.ly = ly;
+        self.lc = lc;
+        self.cycle_count = 0;
+        self.first_line = false;
+        self.update_stat();
+    }
+
+    // Debug access to VRAM (current bank) and OAM, ignoring mode and DMA locks
+    pub fn peek(&self, a: u16) -> u8 {
+        match a {
+            0x8000..=0x9FFF => self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000],
+            0xFE00..=0xFE9F => self.oam[a as usize - 0xFE00],
+            _ => self.read(a),
+        }
+    }
+
+    pub fn poke(&mut self, a: u16, v: u8) {
+        match a {
+            0x8000..=0x9FFF => {
+                self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000] = v;
+                self.vram_dirty = true;
+            },
+            0xFE00..=0xFE9F => self.oam[a as usize - 0xFE00] = v,
+            _ => self.write(a, v),
+        }
+    }
+
+    // OAM DMA writes bypass the mode 2/3 access restrictions
+    pub fn write_oam(&mut self, i: usize, v: u8) {
+        self.oam[i] = v;
+    }
+
+    // Renders into a larger w x h frame buffer (e.g. 256x224 for SGB borders),
+    // leaving everything outside the centered game image to the frontend
+    pub fn set_output_size(&mut self, w: usize, h: usize) {
+        assert!(w >= SCREEN_W && h >= SCREEN_H, "Output must be at least SCREEN_W x SCREEN_H!");
+        self.output_w = w;
+        self.output_h = h;
+        self.back_buffer = vec![0x00; 4 * w * h];
+        self.frame_buffer = vec![0x00; 4 * w * h];
+        self.frame_dirty = true;
+    }
+
+    // Enables a 2x Scale2x copy of each finished frame, off by default
+    pub fn set_upscale(&mut self, enabled: bool) {
+        self.upscaled_buffer = if enabled {
+            Some(vec![0x00; 16 * SCREEN_W * SCREEN_H])
+        } else {
+            None
+        };
+    }
+
+    pub fn upscaled_framebuffer(&self) -> Option<&[u8]> {
+        self.upscaled_buffer.as_deref()
+    }
+
+    // Tracks which layer drew each pixel for debug overlays, off by default
+    pub fn set_pixel_sources(&mut self, enabled: bool) {
+        self.pixel_sources = if enabled {
+            Some(vec![PixelSource::BG; SCREEN_W * SCREEN_H])
+        } else {
+            None
+        };
+    }
+
+    // Always BG while tracking is off
+    pub fn pixel_source(&self, x: usize, y: usize) -> PixelSource {
+        self.pixel_sources.as_ref().map_or(PixelSource::BG, |sources| sources[y * SCREEN_W + x])
+    }
+
+    fn set_pixel_source(&mut self, x: usize, source: PixelSource) {
+        let ly = self.ly as usize;
+        if let Some(sources) = self.pixel_sources.as_mut() {
+            sources[ly * SCREEN_W + x] = source;
+        }
+    }
+
+    // Never shows a partially drawn frame
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.frame_buffer
+    }
+
+    // Copies the game image from the back buffer, leaving the border to the frontend
+    fn present(&mut self) {
+        for ly in 0..SCREEN_H {
+            self.present_line(ly);
+        }
+    }
+
+    fn present_line(&mut self, ly: usize) {
+        let left = (self.output_w - SCREEN_W) / 2;
+        let top = (self.output_h - SCREEN_H) / 2;
+        let start = 4 * ((top + ly) * self.output_w + left);
+        let end = start + 4 * SCREEN_W;
+        self.frame_buffer[start..end].copy_from_slice(&self.back_buffer[start..end]);
+    }
+
+    // Upscales only the game image, ignoring any border from set_output_size
+    fn upscale(&mut self) {
+        let Some(upscaled) = self.upscaled_buffer.as_mut() else {
+            return;
+        };
+
+        let left = (self.output_w - SCREEN_W) / 2;
+        let top = (self.output_h - SCREEN_H) / 2;
+        let mut screen = Vec::with_capacity(4 * SCREEN_W * SCREEN_H);
+        for y in top..top + SCREEN_H {
+            let start = 4 * (y * self.output_w + left);
+            screen.extend_from_slice(&self.frame_buffer[start..start + 4 * SCREEN_W]);
+        }
+
+        scale2x(&screen, SCREEN_W, SCREEN_H, upscaled);
+    }
+
+    // Whether the frame buffer changed since the last call, so frontends can skip uploads
+    pub fn take_frame_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.frame_dirty)
+    }
+
+    // Whether VRAM was written since the last call, so tile and map viewers can skip redraws
+    pub fn take_vram_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.vram_dirty)
+    }
+
+    pub fn output_size(&self) -> (usize, usize) {
+        (self.output_w, self.output_h)
+    }
+
+    // Packs the frame buffer as RRRRRGGGGGGBBBBB, rounding to the nearest value
+    pub fn copy_framebuffer_rgb565(&self, out: &mut [u16]) {
+        assert_eq!(out.len(), self.output_w * self.output_h, "RGB565 buffer must match the output size!");
+
+        for (pixel, rgba) in out.iter_mut().zip(self.frame_buffer.chunks_exact(4)) {
+            let r = (rgba[0] as u16 * 31 + 127) / 255;
+            let g = (rgba[1] as u16 * 63 + 127) / 255;
+            let b = (rgba[2] as u16 * 31 + 127) / 255;
+            *pixel = r << 11 | g << 5 | b;
+        }
+    }
+
+    // The STAT interrupt is raised on the rising edge of the OR of all
+    // selected sources, so a source held high blocks the others, and sources
+    // becoming true on the same transition (e.g. HBlank on the LYC line) raise it once.
+    // Callers must change LY and the mode before calling this, never in between.
+    fn update_stat(&mut self) {
+        let line = (self.lcds.contains(LCDS::LYC_SELECT) && self.ly == self.lc) ||
+            (self.lcds.contains(LCDS::MODE_0_SELECT) && self.ppu_mode == PPUMode::HBlank && !self.first_line) ||
+            (self.lcds.contains(LCDS::MODE_1_SELECT) && self.ppu_mode == PPUMode::VBlank) ||
+            (self.lcds.contains(LCDS::MODE_2_SELECT) && self.ppu_mode == PPUMode::OAMScan);
+
+        if line && !self.stat_line {
+            self.interrupts |= Interrupts::LCD;
+        }
+        self.stat_line = line;
+    }
+
+    pub fn set_dmg_palette(&mut self, colors: [(u8, u8, u8); 4]) {
+        self.dmg_palette = colors;
+    }
+
+    pub fn set_dmg_palette_preset(&mut self, preset: DmgPalette) {
+        self.set_dmg_palette(preset.colors());
+    }
+
+    // Loads one of COMPAT_PALETTES into BG palette 0 and OBJ palettes 0-1, the palettes
+    // a DMG game uses on CGB, overriding DEFAULT_COMPAT_PALETTE.
+    // Only meaningful in CGB mode.
+    pub fn force_compat_palette(&mut self, index: u8) {
+        if self.mode != GBMode::Color {
+            return;
+        }
+
+        let [bg, obj0, obj1] = COMPAT_PALETTES[index as usize % COMPAT_PALETTES.len()];
+        for (i, &color) in bg.iter().enumerate() {
+            let c = Self::rgb888_to_rgb555(color);
+            self.bg_palette_ram[i * 2] = c as u8;
+            self.bg_palette_ram[i * 2 + 1] = (c >> 8) as u8;
+        }
+        for (i, &color) in obj0.iter().chain(obj1.iter()).enumerate() {
+            let c = Self::rgb888_to_rgb555(color);
+            self.obj_palette_ram[i * 2] = c as u8;
+            self.obj_palette_ram[i * 2 + 1] = (c >> 8) as u8;
+        }
+    }
+
+    // CGB: all 8 BG and 8 OBJ palettes from palette RAM.
+    // DMG: BGP, OBP0 and OBP1 mapped through the current DMG palette.
+    pub fn dump_palettes(&self) -> Palettes {
+        if self.mode == GBMode::Color {
+            let decode = |ram: &[u8; 0x40]| -> Vec<[(u8, u8, u8); 4]> {
+                ram.chunks_exact(8)
+                    .map(|palette| {
+                        let mut colors = [(0, 0, 0); 4];
+                        for (i, color) in colors.iter_mut().enumerate() {
+                            *color = Self::rgb555_to_rgb888(palette[i * 2] as u16 | (palette[i * 2 + 1] as u16) << 8);
+                        }
+                        colors
+                    })
+                    .collect()
+            };
+
+            Palettes {
+                bg: decode(&self.bg_palette_ram),
+                obj: decode(&self.obj_palette_ram)
+            }
+        } else {
+            let shades = |v: u8| [0, 1, 2, 3].map(|i| self.grey_to_l(v, i));
+
+            Palettes {
+                bg: vec![shades(self.bgp)],
+                obj: vec![shades(self.op0), shades(self.op1)]
+            }
+        }
+    }
+
+    // All 384 tiles of a VRAM bank as RGBA, 16 tiles wide and 24 tall
+    pub fn dump_tiles(&self, bank: usize) -> Vec<u8> {
+        let colors = self.viewer_colors();
+        let mut out = vec![0x00; 4 * TILES_W * TILES_H];
+        for tile in 0..384 {
+            let (tile_x, tile_y) = ((tile % 16) * 8, (tile / 16) * 8);
+            for y in 0..8 {
+                let address = 0x8000 + (tile * 16 + y * 2) as u16;
+                let data = [self.read_vram(bank, address), self.read_vram(bank, address + 1)];
+                for x in 0..8 {
+                    let color = ((data[1] >> (7 - x)) & 0x01) << 1 | ((data[0] >> (7 - x)) & 0x01);
+                    let (r, g, b) = colors[color as usize];
+                    let i = 4 * ((tile_y + y) * TILES_W + tile_x + x);
+                    out[i..i + 4].copy_from_slice(&[r, g, b, 0xFF]);
+                }
+            }
         }
+        out
+    }
+
+    // The full 256x256 BG map at $9800 or $9C00 as RGBA, using the current tile data area
+    pub fn dump_tilemap(&self, high_map: bool) -> Vec<u8> {
+        let colors = self.viewer_colors();
+        let map_base: u16 = if high_map { 0x9C00 } else { 0x9800 };
+        let mut out = vec![0x00; 4 * 256 * 256];
+        for y in 0..256 {
+            for x in 0..256 {
+                let tile_index = self.read_vram(0, map_base + (y / 8 * 32 + x / 8) as u16);
+                let tile_address = if self.lcdc.contains(LCDC::TILE_DATA_AREA) {
+                    0x8000 + tile_index as u16 * 16
+                } else {
+                    (0x9000 + (tile_index as i8) as i32 * 16) as u16
+                };
+                let address = tile_address + (y % 8 * 2) as u16;
+                let data = [self.read_vram(0, address), self.read_vram(0, address + 1)];
+                let bit = 7 - x % 8;
+                let color = ((data[1] >> bit) & 0x01) << 1 | ((data[0] >> bit) & 0x01);
+                let (r, g, b) = colors[color as usize];
+                let i = 4 * (y * 256 + x);
+                out[i..i + 4].copy_from_slice(&[r, g, b, 0xFF]);
+            }
+        }
+        out
+    }
+
+    // viewer_palette if set, otherwise BGP on DMG or BG palette 0 on CGB
+    fn viewer_colors(&self) -> [(u8, u8, u8); 4] {
+        match self.viewer_palette {
+            Some(colors) => colors,
+            None => self.dump_palettes().bg[0],
+        }
+    }
+
+    fn rgb555_to_rgb888(color: u16) -> (u8, u8, u8) {
+        let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        (expand(color & 0x1F), expand((color >> 5) & 0x1F), expand((color >> 10) & 0x1F))
+    }
+
+    // Colour `color` (0-3) of CGB palette `palette` (0-7), from BG or OBJ palette RAM
+    fn cgb_color(palette_ram: &[u8; 0x40], palette: u8, color: usize) -> (u8, u8, u8) {
+        let i = (palette as usize * 4 + color) * 2;
+        Self::rgb555_to_rgb888(palette_ram[i] as u16 | (palette_ram[i + 1] as u16) << 8)
+    }
+
+    fn rgb888_to_rgb555(color: u32) -> u16 {
+        let r = (color >> 19) & 0x1F;
+        let g = (color >> 11) & 0x1F;
+        let b = (color >> 3) & 0x1F;
+        (r | g << 5 | b << 10) as u16
+    }
+
+    fn grey_to_l(&self, v: u8, i: usize) -> (u8, u8, u8) {
+        self.dmg_palette[(v >> (2 * i) & 0x03) as usize]
+    }
+
+    // Whether CGB-only features like tile attributes and VRAM bank 1 tiles are in use
+    fn cgb_features(&self) -> bool {
+        self.mode == GBMode::Color && !self.dmg_compat
+    }
+
+    // Colour `i` mapped through DMG palette register `v`. In compatibility mode the shade
+    // picks a colour from CGB palette `palette`, otherwise it's looked up in dmg_palette.
+    fn dmg_shade(&self, palette_ram: &[u8; 0x40], palette: u8, v: u8, i: usize) -> (u8, u8, u8) {
+        if self.dmg_compat {
+            Self::cgb_color(palette_ram, palette, (v >> (2 * i) & 0x03) as usize)
+        } else {
+            self.grey_to_l(v, i)
+        }
+    }
+
+    // Fills the game image with disabled_color without touching the border. There's no VBlank
+    // while the LCD is off, so this is presented straight away.
+    fn clear_screen(&mut self) {
+        let left = (self.output_w - SCREEN_W) / 2;
+        let top = (self.output_h - SCREEN_H) / 2;
+        let (r, g, b) = self.disabled_color;
+        let pixel = [r, g, b, 0xFF];
+
+        for y in top..top + SCREEN_H {
+            let start = 4 * (y * self.output_w + left);
+            for rgba in self.back_buffer[start..start + 4 * SCREEN_W].chunks_exact_mut(4) {
+                if rgba != pixel {
+                    rgba.copy_from_slice(&pixel);
+                    self.frame_dirty = true;
+                }
+            }
+        }
+        self.present();
     }
 
     fn set_rgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
         // TODO: Color mapping from CGB -> sRGB
         let bytes_per_pixel = 4;
-        let bytes_per_row = bytes_per_pixel * SCREEN_W;
-        let vertical_offset = self.ly as usize * bytes_per_row;
-        let horizontal_offset = x * bytes_per_pixel;
+        let bytes_per_row = bytes_per_pixel * self.output_w;
+        let vertical_offset = (self.ly as usize + (self.output_h - SCREEN_H) / 2) * bytes_per_row;
+        let horizontal_offset = (x + (self.output_w - SCREEN_W) / 2) * bytes_per_pixel;
         let total_offset = vertical_offset + horizontal_offset;
 
-        self.frame_buffer[total_offset + 0] = r;
-        self.frame_buffer[total_offset + 1] = g;
-        self.frame_buffer[total_offset + 2] = b;
-        self.frame_buffer[total_offset + 3] = 0xFF;
+        let pixel = [r, g, b, 0xFF];
+        if self.back_buffer[total_offset..total_offset + 4] != pixel {
+            self.back_buffer[total_offset..total_offset + 4].copy_from_slice(&pixel);
+            self.frame_dirty = true;
+        }
+    }
+
+    // Draws line `ly` into the frame buffer from the current registers and VRAM
+    fn draw_line(&mut self) {
+        if self.cgb_features() || self.lcdc.contains(LCDC::WINDOW_PRIORITY) {
+            self.draw_bg();
+        } else {
+            self.clear_bg();
+        }
+        if self.lcdc.contains(LCDC::OBJ_ENABLE) {
+            self.draw_sprites();
+        }
+    }
+
+    // Debug helper that draws any line outside the cycle loop, LY and the mode are left alone.
+    // The line goes straight to the frame buffer, so it shows up even with the LCD off.
+    pub fn render_scanline(&mut self, ly: u8) {
+        if ly as usize >= SCREEN_H {
+            return;
+        }
+
+        let real_ly = self.ly;
+        let latched = std::mem::take(&mut self.line_sprites);
+        self.ly = ly;
+        self.scan_oam();
+        self.draw_line();
+        self.present_line(ly as usize);
+        self.ly = real_ly;
+        self.line_sprites = latched;
+    }
+
+    // DMG with LCDC bit 0 clear shows neither BG nor window, just colour 0 through BGP
+    fn clear_bg(&mut self) {
+        let (r, g, b) = self.dmg_shade(&self.bg_palette_ram, 0, self.bgp, 0);
+        for x in 0..SCREEN_W {
+            self.bgprio[x] = Priority::Color0;
+            self.set_pixel_source(x, PixelSource::BG);
+            self.set_rgb(x, r, g, b);
+        }
     }
 
     fn draw_bg(&mut self) {
@@ -245,18 +789,18 @@ impl PPU {
         let wx = self.wx.wrapping_sub(7);
 
         // Only show window if it's enabled and it intersects current scanline
-        let in_window_y = self.lcdc.contains(LCDC::WINDOW_ENABLE) && self.wy <= self.ly;
-
-        // Pixel Y
-        let py = if in_window_y {
-            self.ly.wrapping_sub(self.wy)
-        } else {
-            self.sy.wrapping_add(self.ly)
-        };
+        let in_window_y = self.lcdc.contains(LCDC::WINDOW_ENABLE) && self.window_triggered;
 
         for x in 0..SCREEN_W {
             let in_window_x = x as u8 >= wx;
 
+            // Pixel Y, the window uses its own line counter rather than LY - WY
+            let py = if in_window_y && in_window_x {
+                self.window_line
+            } else {
+                self.sy.wrapping_add(self.ly)
+            };
+
             // Pixel X
             let px = if in_window_y && in_window_x {
                 x as u8 - wx
@@ -282,7 +826,7 @@ impl PPU {
 
             // Location of Tile Attributes
             let tile_address = tile_map_base + tile_index_y * 32 + tile_index_x;
-            let tile_index = self.read_ram0(tile_address);
+            let tile_index = self.read_vram(0, tile_address);
 
             // If we're using the secondary address mode,
             // we need to interpret this tile index as signed
@@ -293,20 +837,21 @@ impl PPU {
             } as u16 * 16;
 
             let tile_data_location = tile_data_base + tile_offset;
-            let tile_attributes = Attributes::from_bits(self.read_ram1(tile_address)).unwrap();
+            // Attributes sit in bank 1 alongside the tile map, and only exist on CGB
+            let tile_attributes = if self.cgb_features() {
+                Attributes::from_bits_truncate(self.read_vram(1, tile_address))
+            } else {
+                Attributes::empty()
+            };
 
             let tile_y = if tile_attributes.contains(Attributes::Y_FLIP) { 7 - py % 8 } else { py % 8 };
             let tile_x = if tile_attributes.contains(Attributes::X_FLIP) { 7 - px % 8 } else { px % 8 };
 
-            let tile_y_data = if self.mode == GBMode::Color && tile_attributes.contains(Attributes::BANK) {
-                let a = self.read_ram1(tile_data_location + ((tile_y * 2) as u16));
-                let b = self.read_ram1(tile_data_location + ((tile_y * 2) as u16) + 1);
-                [a, b]
-            } else {
-                let a = self.read_ram0(tile_data_location + ((tile_y * 2) as u16));
-                let b = self.read_ram0(tile_data_location + ((tile_y * 2) as u16) + 1);
-                [a, b]
-            };
+            let tile_bank = if tile_attributes.contains(Attributes::BANK) { 1 } else { 0 };
+            let tile_y_data = [
+                self.read_vram(tile_bank, tile_data_location + ((tile_y * 2) as u16)),
+                self.read_vram(tile_bank, tile_data_location + ((tile_y * 2) as u16) + 1)
+            ];
 
             let color_l = if tile_y_data[0] & (0x80 >> tile_x) != 0 { 1 } else { 0 };
             let color_h = if tile_y_data[1] & (0x80 >> tile_x) != 0 { 2 } else { 0 };
@@ -322,27 +867,27 @@ impl PPU {
                 }
             };
 
-            if self.mode == GBMode::Color {
-                let r = 0;
-                let g = 0;
-                let b = 0;
+            self.set_pixel_source(x, if in_window_y && in_window_x { PixelSource::Window } else { PixelSource::BG });
+
+            if self.cgb_features() {
+                let (r, g, b) = Self::cgb_color(&self.bg_palette_ram, tile_attributes.bits() & 0x07, color);
                 self.set_rgb(x, r, g, b);
             } else {
-                let (r, g, b) = Self::grey_to_l(self.bgp, color);
+                let (r, g, b) = self.dmg_shade(&self.bg_palette_ram, 0, self.bgp, color);
                 self.set_rgb(x, r, g, b);
             }
         }
     }
 
-    fn draw_sprites(&mut self) {
+    // Mode 2: picks the sprites overlapping this line, latching their OAM entries for Draw
+    fn scan_oam(&mut self) {
         let sprite_size = if self.lcdc.contains(LCDC::OBJ_SIZE) { 16 } else { 8 };
+        self.line_sprites.clear();
 
         for i in 0..40 {
-            let sprite_address = 0xFE00 + (i as u16) * 4;
-            let py = self.read(sprite_address).wrapping_sub(16);
-            let px = self.read(sprite_address + 1).wrapping_sub(8);
-            let tile_number = self.read(sprite_address + 2) & if self.lcdc.contains(LCDC::OBJ_SIZE) { 0xFE } else { 0xFF };
-            let tile_attributes = Attributes::from_bits_truncate(self.read(sprite_address + 3));
+            // Read OAM directly, the CPU-side access restrictions don't apply to the PPU
+            let entry: [u8; 4] = self.oam[i * 4..i * 4 + 4].try_into().unwrap();
+            let py = entry[0].wrapping_sub(16);
 
             if py <= 0xFF - sprite_size + 1 {
                 if self.ly < py || self.ly > py + sprite_size - 1 {
@@ -354,7 +899,72 @@ impl PPU {
                 }
             }
 
-            if px >= (SCREEN_W as u8) && px <= (0xFF - 7) {
+            // Hardware only selects the first 10 sprites on a line,
+            // even if some of them are off-screen horizontally
+            if self.sprite_limit_enabled && self.line_sprites.len() >= 10 {
+                break;
+            }
+            self.line_sprites.push((i, entry));
+        }
+    }
+
+    // Extra Mode 3 cycles spent fetching the latched sprites, going by the rules in
+    // https://gbdev.io/pandocs/Rendering.html#mode-3-length. Each sprite costs 6 cycles, plus
+    // however long the background fetch of the tile under its leftmost pixel still needs,
+    // which only the first sprite over each tile pays.
+    fn sprite_penalty(&self) -> u32 {
+        if !self.lcdc.contains(LCDC::OBJ_ENABLE) {
+            return 0;
+        }
+
+        let mut xs: Vec<u8> = self.line_sprites.iter().map(|(_, entry)| entry[1]).collect();
+        // The fetcher reaches sprites from left to right
+        xs.sort();
+
+        let window_x = if self.window_visible() { Some(self.wx as i16 - 7) } else { None };
+        let mut fetched_tiles: Vec<(bool, u8)> = Vec::with_capacity(10);
+        let mut penalty = 0;
+
+        for x in xs {
+            // Past the right edge, never fetched
+            if x >= 168 {
+                continue;
+            }
+            if x == 0 {
+                penalty += SPRITE_X0_CYCLES;
+                continue;
+            }
+
+            let px = x as i16 - 8;
+            let tile = match window_x {
+                Some(wx) if px >= wx => (true, (px - wx) as u8),
+                _ => (false, (px as u8).wrapping_add(self.sx)),
+            };
+            // Only the first sprite over a tile waits for its fetch
+            if !fetched_tiles.contains(&(tile.0, tile.1 / 8)) {
+                fetched_tiles.push((tile.0, tile.1 / 8));
+                penalty += 5_u32.saturating_sub((tile.1 % 8) as u32);
+            }
+            penalty += SPRITE_FETCH_CYCLES;
+        }
+
+        penalty
+    }
+
+    fn draw_sprites(&mut self) {
+        let sprite_size = if self.lcdc.contains(LCDC::OBJ_SIZE) { 16 } else { 8 };
+        let line_sprites = std::mem::take(&mut self.line_sprites);
+
+        for &(i, entry) in &line_sprites {
+            let py = entry[0].wrapping_sub(16);
+            // Signed so sprites hanging off the left edge keep their position
+            let px = entry[1] as i16 - 8;
+            let tile_number = entry[2] & if self.lcdc.contains(LCDC::OBJ_SIZE) { 0xFE } else { 0xFF };
+            let tile_attributes = Attributes::from_bits_truncate(entry[3]);
+
+            // Only sprites with no columns on screen are skipped here, partially visible
+            // ones at either edge are clipped per pixel below
+            if px <= -8 || px >= SCREEN_W as i16 {
                 continue;
             }
 
@@ -363,63 +973,110 @@ impl PPU {
             } else {
                 self.ly.wrapping_sub(py)
             };
-            let tile_y_address: u16 = 0x8000_u16 + tile_number as u16 * 16 + tile_y as u16 * 2;
-            let tile_y_data = if self.mode == GBMode::Color && tile_attributes.contains(Attributes::BANK) {
-                let b1 = self.read_ram1(tile_y_address);
-                let b2 = self.read_ram1(tile_y_address + 1);
-                [b1, b2]
+            // In 8x16 mode, rows 8-15 (after flipping) come from the odd tile of the pair and rows
+            // 0-7 from the even one. Flipping covers all 16 rows, so Y-flip also swaps the tiles.
+            let (tile_number, tile_y) = if tile_y >= 8 {
+                (tile_number | 0x01, tile_y - 8)
             } else {
-                let b1 = self.read_ram0(tile_y_address);
-                let b2 = self.read_ram0(tile_y_address + 1);
-                [b1, b2]
+                (tile_number, tile_y)
             };
+            let tile_y_address: u16 = 0x8000_u16 + tile_number as u16 * 16 + tile_y as u16 * 2;
+            let tile_bank = if self.cgb_features() && tile_attributes.contains(Attributes::BANK) { 1 } else { 0 };
+            let tile_y_data = [
+                self.read_vram(tile_bank, tile_y_address),
+                self.read_vram(tile_bank, tile_y_address + 1)
+            ];
 
             for x in 0..8 {
-                if px.wrapping_add(x) >= (SCREEN_W as u8) {
+                let screen_x = px + x as i16;
+                if screen_x < 0 || screen_x >= SCREEN_W as i16 {
                     continue;
                 }
+                let screen_x = screen_x as usize;
                 let tile_x = if tile_attributes.contains(Attributes::X_FLIP) { 7 - x } else { x };
 
                 let color_low = if tile_y_data[0] & (0x80 >> tile_x) != 0 { 1 } else { 0 };
                 let color_high = if tile_y_data[1] & (0x80 >> tile_x) != 0 { 2 } else { 0 };
                 let color = color_high | color_low;
+                // Colour index 0 is transparent regardless of the palette contents
                 if color == 0 {
                     continue;
                 }
 
-                let prio = self.bgprio[px.wrapping_add(x) as usize];
-                let skip = if self.mode == GBMode::Color && !self.lcdc.contains(LCDC::WINDOW_PRIORITY) {
-                    prio == Priority::Priority
-                } else if prio == Priority::Priority {
-                    prio != Priority::Color0
+                let prio = self.bgprio[screen_x];
+                // With LCDC bit 0 clear, objects always win: on DMG the BG is blank, on CGB it
+                // removes BG master priority. Otherwise BG colours 1-3 win if either the
+                // BG attribute or the OAM priority bit asks for it.
+                let skip = if !self.lcdc.contains(LCDC::WINDOW_PRIORITY) {
+                    false
                 } else {
-                    tile_attributes.contains(Attributes::PRIORITY) && prio != Priority::Color0
+                    prio != Priority::Color0 && (prio == Priority::Priority || tile_attributes.contains(Attributes::PRIORITY))
                 };
                 if skip {
                     continue;
                 }
 
-                if self.mode == GBMode::Color {
+                self.set_pixel_source(screen_x, PixelSource::Sprite(i as u8));
 
+                if self.cgb_features() {
+                    let (r, g, b) = Self::cgb_color(&self.obj_palette_ram, tile_attributes.bits() & 0x07, color);
+                    self.set_rgb(screen_x, r, g, b);
                 } else {
+                    // OBP0/OBP1 are read as the line is drawn, so changes between scanlines
+                    // are honoured, but changes made mid-scanline are not
                     let (r, g, b) = if tile_attributes.contains(Attributes::PALLETE_NO_0) {
-                        Self::grey_to_l(self.op1, color)
+                        self.dmg_shade(&self.obj_palette_ram, 1, self.op1, color)
                     } else {
-                        Self::grey_to_l(self.op0, color)
+                        self.dmg_shade(&self.obj_palette_ram, 0, self.op0, color)
                     };
 
-                    self.set_rgb(px.wrapping_add(x) as usize, r, g, b);
+                    self.set_rgb(screen_x, r, g, b);
                 }
             }
         }
+
+        self.line_sprites = line_sprites;
     }
 
-    fn read_ram0(&self, a: u16) -> u8 {
-        self.ram[a as usize - 0x8000]
+    // The PPU addresses VRAM banks directly, independent of the CPU's VBK selection
+    fn read_vram(&self, bank: usize, a: u16) -> u8 {
+        self.ram[bank * 0x2000 + a as usize - 0x8000]
     }
+}
 
-    fn read_ram1(&self, a: u16) -> u8 {
-        self.ram[a as usize - 0x6000]
+impl Hash for PPU {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.ppu_mode as u8).hash(state);
+        self.cycle_count.hash(state);
+        self.vblanked_lines.hash(state);
+        self.sy.hash(state);
+        self.sx.hash(state);
+        self.ly.hash(state);
+        self.lc.hash(state);
+        self.wy.hash(state);
+        self.wx.hash(state);
+        self.window_line.hash(state);
+        self.window_triggered.hash(state);
+        self.first_line.hash(state);
+        self.bgp.hash(state);
+        self.op0.hash(state);
+        self.op1.hash(state);
+        self.lcdc.bits().hash(state);
+        self.lcds.bits().hash(state);
+        self.stat_line.hash(state);
+        self.ram.hash(state);
+        self.ram_bank.hash(state);
+        self.oam.hash(state);
+        self.line_sprites.hash(state);
+        self.mode_3_cycles.hash(state);
+        self.bcps.hash(state);
+        self.bg_palette_ram.hash(state);
+        self.ocps.hash(state);
+        self.obj_palette_ram.hash(state);
+        self.dmg_compat.hash(state);
+        self.interrupts.bits().hash(state);
+        self.back_buffer.hash(state);
+        self.frame_buffer.hash(state);
     }
 }
 
@@ -427,14 +1084,14 @@ impl Memory for PPU {
     fn read(&self, a: u16) -> u8 {
         match a {
             0x8000..=0x9FFF => {
-                if self.ppu_mode != PPUMode::Draw {
+                if !self.vram_locked() {
                     self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000]
                 } else {
                     0xFF
                 }
             },
             0xFE00..=0xFE9F => {
-                if self.ppu_mode != PPUMode::Draw && self.ppu_mode != PPUMode::OAMScan {
+                if !self.oam_dma_active && self.ppu_mode != PPUMode::Draw && self.ppu_mode != PPUMode::OAMScan {
                     self.oam[a as usize - 0xFE00]
                 } else {
                     0xFF
@@ -446,7 +1103,8 @@ impl Memory for PPU {
                 if self.ly == self.lc {
                     lcds |= LCDS::LYC_EQUALS;
                 }
-                lcds.bits() | self.ppu_mode as u8
+                // Bit 7 is unused and always set
+                0x80 | lcds.bits() | self.ppu_mode as u8
             },
             0xFF42 => self.sy,
             0xFF43 => self.sx,
@@ -457,8 +1115,18 @@ impl Memory for PPU {
             0xFF49 => self.op1,
             0xFF4A => self.wy,
             0xFF4B => self.wx,
-            0xFF4D => 0x00,
-            0xFF4F => 0xFE | self.ram_bank as u8,
+            0xFF4F => {
+                if self.mode == GBMode::Color {
+                    0xFE | self.ram_bank as u8
+                } else {
+                    0xFF
+                }
+            },
+            0xFF68..=0xFF6B if self.mode != GBMode::Color => 0xFF,
+            0xFF68 => self.bcps | 0x40,
+            0xFF69 => self.bg_palette_ram[(self.bcps & 0x3F) as usize],
+            0xFF6A => self.ocps | 0x40,
+            0xFF6B => self.obj_palette_ram[(self.ocps & 0x3F) as usize],
             0xFF60..=0xFF6F => 0x00,
             _ => panic!("Read to unsupported PPU address ({:#06x})!", a),
         }
@@ -467,42 +1135,962 @@ impl Memory for PPU {
     fn write(&mut self, a: u16, v: u8) {
         match a {
             0x8000..=0x9FFF => {
-                if self.ppu_mode != PPUMode::Draw {
-                    self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000] = v
+                if !self.vram_locked() {
+                    self.ram[self.ram_bank * 0x2000 + a as usize - 0x8000] = v;
+                    self.vram_dirty = true;
                 }
             },
             0xFE00..=0xFE9F => {
-                if self.ppu_mode != PPUMode::Draw && self.ppu_mode != PPUMode::OAMScan {
+                if !self.oam_dma_active && self.ppu_mode != PPUMode::Draw && self.ppu_mode != PPUMode::OAMScan {
                     self.oam[a as usize - 0xFE00] = v
                 }
             },
             0xFF40 => {
+                let was_enabled = self.lcdc.contains(LCDC::LCD_ENABLE);
                 self.lcdc = LCDC::from_bits(v).unwrap();
                 if !self.lcdc.contains(LCDC::LCD_ENABLE) {
                     self.ly = 0;
                     self.ppu_mode = PPUMode::HBlank;
-                    self.frame_buffer = vec![0x00; 4 * SCREEN_W * SCREEN_H];
+                    self.stat_line = false;
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.first_line = false;
+                    self.clear_screen();
+                } else if !was_enabled {
+                    // Line 0 starts over, without a Mode 2 STAT interrupt
+                    self.ly = 0;
+                    self.ppu_mode = PPUMode::HBlank;
+                    self.cycle_count = 0;
+                    self.vblanked_lines = 0;
+                    self.first_line = true;
                 }
             },
             0xFF41 => {
-                let sanitised = v & 0b1111_1100;
-                self.lcds = LCDS::from_bits(sanitised).unwrap()
+                // Only the interrupt selects are writable, mode and LYC=LY are read-only
+                let sanitised = v & 0b0111_1000;
+                self.lcds = LCDS::from_bits(sanitised).unwrap();
+                if self.lcdc.contains(LCDC::LCD_ENABLE) {
+                    self.update_stat();
+                }
             },
             0xFF42 => self.sy = v,
             0xFF43 => self.sx = v,
-            0xFF44 => print!("Attempted to write to LY!"),
-            0xFF45 => self.lc = v,
+            // LY is read-only, it only resets when the LCD is disabled or the frame wraps
+            0xFF44 => {},
+            0xFF45 => {
+                self.lc = v;
+                if self.lcdc.contains(LCDC::LCD_ENABLE) {
+                    self.update_stat();
+                }
+            },
             0xFF47 => self.bgp = v,
             0xFF48 => self.op0 = v,
             0xFF49 => self.op1 = v,
             0xFF4A => self.wy = v,
             0xFF4B => self.wx = v,
-            // TODO: Handle PPU speed switching
-            0xFF4D => {}
-            0xFF4F => self.ram_bank = (v & 0x01) as usize,
-            // TODO: Handle CBG PAL
+            // VRAM banking only exists on CGB
+            0xFF4F => {
+                if self.mode == GBMode::Color {
+                    self.ram_bank = (v & 0x01) as usize
+                }
+            },
+            0xFF68..=0xFF6B if self.mode != GBMode::Color => {},
+            0xFF68 => self.bcps = v & 0xBF,
+            0xFF69 => {
+                self.bg_palette_ram[(self.bcps & 0x3F) as usize] = v;
+                // Bit 7 auto-increments the index after each write
+                if self.bcps & 0x80 != 0 {
+                    self.bcps = 0x80 | (self.bcps + 1) & 0x3F;
+                }
+            },
+            0xFF6A => self.ocps = v & 0xBF,
+            0xFF6B => {
+                self.obj_palette_ram[(self.ocps & 0x3F) as usize] = v;
+                if self.ocps & 0x80 != 0 {
+                    self.ocps = 0x80 | (self.ocps + 1) & 0x3F;
+                }
+            },
             0xFF60..=0xFF6F => {},
             _ => panic!("Write to unsupported PPU address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::FRAME_DOTS;
+
+    // LCD on, BG on with tile data at $8000
+    fn enabled_ppu(mode: GBMode) -> PPU {
+        let mut ppu = PPU::new(mode);
+        ppu.write(0xFF40, 0x91);
+        ppu
+    }
+
+    // Runs up to the start of the next VBlank
+    fn run_frame(ppu: &mut PPU) {
+        while !ppu.cycle(4) {}
+    }
+
+    // Runs until LY reads `ly`
+    fn run_to_line(ppu: &mut PPU, ly: u8) {
+        while ppu.read(0xFF44) != ly {
+            ppu.cycle(4);
+        }
+    }
+
+    fn set_sprite(ppu: &mut PPU, i: u16, y: u8, x: u8, tile: u8, attributes: u8) {
+        for (n, v) in [y, x, tile, attributes].into_iter().enumerate() {
+            ppu.poke(0xFE00 + i * 4 + n as u16, v);
+        }
+    }
+
+    // Fills all 8 rows of a tile in the current VRAM bank with one colour
+    fn fill_tile(ppu: &mut PPU, tile: u16, color: u8) {
+        for row in 0..8 {
+            let address = 0x8000 + tile * 16 + row * 2;
+            ppu.poke(address, if color & 0x01 != 0 { 0xFF } else { 0x00 });
+            ppu.poke(address + 1, if color & 0x02 != 0 { 0xFF } else { 0x00 });
+        }
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> (u8, u8, u8) {
+        let i = 4 * (y * SCREEN_W + x);
+        let rgba = &ppu.framebuffer()[i..i + 4];
+        (rgba[0], rgba[1], rgba[2])
+    }
+
+    #[test]
+    fn palette_presets_change_the_frame() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Green);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), DmgPalette::Green.colors()[0]);
+
+        ppu.set_dmg_palette_preset(DmgPalette::Kirokaze);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), DmgPalette::Kirokaze.colors()[0]);
+    }
+
+    #[test]
+    fn sprites_use_obp0_as_of_their_line() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        fill_tile(&mut ppu, 1, 3);
+        set_sprite(&mut ppu, 0, 16, 8, 1, 0x00);
+        set_sprite(&mut ppu, 1, 32, 8, 1, 0x00);
+        set_sprite(&mut ppu, 2, 32, 24, 1, Attributes::PALLETE_NO_0.bits());
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF49, 0xE4);
+        ppu.write(0xFF40, 0x93);
+
+        // Lines are drawn whole at the end of Mode 3, so only changes between lines are
+        // seen, a write in the middle of a line would apply to all of it
+        run_to_line(&mut ppu, 8);
+        ppu.write(0xFF48, 0x1B);
+        run_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 0), DmgPalette::Grayscale.colors()[3]);
+        assert_eq!(pixel(&ppu, 0, 16), DmgPalette::Grayscale.colors()[0]);
+        assert_eq!(pixel(&ppu, 16, 16), DmgPalette::Grayscale.colors()[3]);
+    }
+
+    #[test]
+    fn lyc_interrupt_fires_once_per_frame() {
+        // Including both ends of the 153 -> 0 wrap
+        for lyc in [0x00, 0x40, 0x99] {
+            let mut ppu = enabled_ppu(GBMode::Classic);
+            ppu.write(0xFF45, lyc);
+            ppu.write(0xFF41, LCDS::LYC_SELECT.bits());
+            run_frame(&mut ppu);
+            ppu.interrupts = Interrupts::empty();
+
+            // From the start of one VBlank to the next
+            let mut count = 0;
+            loop {
+                let vblank = ppu.cycle(4);
+                if ppu.interrupts.contains(Interrupts::LCD) {
+                    count += 1;
+                    ppu.interrupts.remove(Interrupts::LCD);
+                }
+                if vblank {
+                    break;
+                }
+            }
+            assert_eq!(count, 1, "LYC = {}", lyc);
+        }
+    }
+
+    // 8x16 sprite at the top left from tiles 2 and 3. Row r of tile 2 is colour 1 at x = r,
+    // row r of tile 3 is colour 2 at x = r, so each pixel shows which tile and row drew it.
+    fn tall_sprite_frame(oam_tile: u8, attributes: u8) -> PPU {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        for row in 0..8 {
+            ppu.poke(0x8020 + row * 2, 0x80 >> row);
+            ppu.poke(0x8030 + row * 2 + 1, 0x80 >> row);
+        }
+        set_sprite(&mut ppu, 0, 16, 8, oam_tile, attributes);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x97);
+        run_frame(&mut ppu);
+        ppu
+    }
+
+    #[test]
+    fn tall_sprite_rows_cross_into_the_second_tile() {
+        let colors = DmgPalette::Grayscale.colors();
+
+        let ppu = tall_sprite_frame(0x02, 0x00);
+        assert_eq!(pixel(&ppu, 7, 7), colors[1]);
+        assert_eq!(pixel(&ppu, 0, 8), colors[2]);
+
+        let ppu = tall_sprite_frame(0x02, Attributes::Y_FLIP.bits());
+        assert_eq!(pixel(&ppu, 0, 7), colors[2]);
+        assert_eq!(pixel(&ppu, 7, 8), colors[1]);
+    }
+
+    #[test]
+    fn sprite_limit_can_be_lifted() {
+        for (limit, drawn) in [(true, 10), (false, 15)] {
+            let mut ppu = PPU::new(GBMode::Classic);
+            ppu.sprite_limit_enabled = limit;
+            ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+            fill_tile(&mut ppu, 1, 3);
+            for i in 0..15 {
+                set_sprite(&mut ppu, i, 16, 8 + i as u8 * 8, 1, 0x00);
+            }
+            ppu.write(0xFF48, 0xE4);
+            ppu.write(0xFF40, 0x93);
+            run_frame(&mut ppu);
+
+            let count = (0..15)
+                .filter(|i| pixel(&ppu, i * 8, 0) == DmgPalette::Grayscale.colors()[3])
+                .count();
+            assert_eq!(count, drawn);
+        }
+    }
+
+    #[test]
+    fn rgb565_rounds_to_the_nearest_level() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.frame_buffer[0..4].copy_from_slice(&[0x06, 0x03, 0xFF, 0xFF]);
+        ppu.frame_buffer[4..8].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let mut out = vec![0; SCREEN_W * SCREEN_H];
+        ppu.copy_framebuffer_rgb565(&mut out);
+        assert_eq!(out[0], 0x083F);
+        assert_eq!(out[1], 0xFFFF);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rgb565_rejects_the_wrong_size() {
+        let ppu = PPU::new(GBMode::Classic);
+        ppu.copy_framebuffer_rgb565(&mut [0; SCREEN_W]);
+    }
+
+    #[test]
+    fn bg_tiles_come_from_the_attribute_bank() {
+        let mut ppu = PPU::new(GBMode::Color);
+        // BG palette 0, colour 0 white and colour 3 black
+        ppu.write(0xFF68, 0x80);
+        for v in [0xFF, 0x7F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00] {
+            ppu.write(0xFF69, v);
+        }
+
+        // Tile 1 is blank in bank 0 and solid in bank 1, the first map entry uses bank 1
+        ppu.write(0xFF4F, 0x01);
+        fill_tile(&mut ppu, 1, 3);
+        ppu.poke(0x9800, Attributes::BANK.bits());
+        ppu.write(0xFF4F, 0x00);
+        ppu.poke(0x9800, 0x01);
+        ppu.poke(0x9801, 0x01);
+
+        // The CPU-visible bank mustn't affect which bank the PPU fetches from
+        for bank in [0x00, 0x01] {
+            ppu.write(0xFF4F, bank);
+            ppu.write(0xFF40, 0x91);
+            run_frame(&mut ppu);
+            assert_eq!(pixel(&ppu, 0, 0), (0x00, 0x00, 0x00), "bank {}", bank);
+            assert_ne!(pixel(&ppu, 8, 0), (0x00, 0x00, 0x00), "bank {}", bank);
+            ppu.write(0xFF40, 0x00);
+        }
+    }
+
+    #[test]
+    fn window_starts_from_its_first_row_after_wy_changes() {
+        let colors = DmgPalette::Grayscale.colors();
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        // Only the window's top row of tiles is solid
+        fill_tile(&mut ppu, 1, 3);
+        for i in 0..20 {
+            ppu.poke(0x9C00 + i, 0x01);
+        }
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF4A, 0xFF);
+        ppu.write(0xFF4B, 0x07);
+        ppu.write(0xFF40, 0xF1);
+
+        run_to_line(&mut ppu, 40);
+        ppu.write(0xFF4A, 50);
+        run_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 49), colors[0]);
+        assert_eq!(pixel(&ppu, 0, 50), colors[3]);
+        assert_eq!(pixel(&ppu, 0, 57), colors[3]);
+        assert_eq!(pixel(&ppu, 0, 58), colors[0]);
+    }
+
+    #[test]
+    fn larger_outputs_center_the_game_image() {
+        let colors = DmgPalette::Grayscale.colors();
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_output_size(256, 224);
+        assert_eq!(ppu.output_size(), (256, 224));
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        fill_tile(&mut ppu, 1, 3);
+        ppu.poke(0x9800, 0x01);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+        run_frame(&mut ppu);
+
+        let rgba = |x: usize, y: usize| {
+            let i = 4 * (y * 256 + x);
+            ppu.framebuffer()[i..i + 4].to_vec()
+        };
+        let (r, g, b) = colors[3];
+        assert_eq!(rgba(48, 40), vec![r, g, b, 0xFF]);
+        let (r, g, b) = colors[0];
+        assert_eq!(rgba(56, 40), vec![r, g, b, 0xFF]);
+        // The border is left alone
+        assert_eq!(rgba(47, 40), vec![0x00; 4]);
+        assert_eq!(rgba(48, 39), vec![0x00; 4]);
+    }
+
+    #[test]
+    fn upscaling_doubles_each_finished_frame() {
+        let colors = DmgPalette::Grayscale.colors();
+        let mut ppu = PPU::new(GBMode::Classic);
+        assert!(ppu.upscaled_framebuffer().is_none());
+        ppu.set_upscale(true);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        // Two tiles touching at a corner, which Scale2x rounds off
+        fill_tile(&mut ppu, 1, 3);
+        ppu.poke(0x9800, 0x01);
+        ppu.poke(0x9821, 0x01);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF40, 0x91);
+        run_frame(&mut ppu);
+
+        let mut expected = vec![0x00; 16 * SCREEN_W * SCREEN_H];
+        scale2x(ppu.framebuffer(), SCREEN_W, SCREEN_H, &mut expected);
+        let upscaled = ppu.upscaled_framebuffer().unwrap();
+        assert_eq!(upscaled, expected.as_slice());
+
+        let rgba = |x: usize, y: usize| upscaled[4 * (y * 2 * SCREEN_W + x)..][..3].to_vec();
+        let dark = colors[3];
+        let light = colors[0];
+        assert_eq!(rgba(0, 0), vec![dark.0, dark.1, dark.2]);
+        assert_eq!(rgba(16, 0), vec![light.0, light.1, light.2]);
+        // The corner pixels either side of the diagonal swap colours
+        assert_eq!(rgba(15, 15), vec![light.0, light.1, light.2]);
+        assert_eq!(rgba(16, 15), vec![dark.0, dark.1, dark.2]);
+    }
+
+    #[test]
+    fn dump_palettes_decodes_cgb_palette_ram() {
+        let mut ppu = PPU::new(GBMode::Color);
+        // BG palette 2, colour 1: pure red, then pure blue
+        ppu.write(0xFF68, 0x80 | (2 * 8 + 2));
+        for v in [0x1F, 0x00, 0x00, 0x7C] {
+            ppu.write(0xFF69, v);
+        }
+
+        let palettes = ppu.dump_palettes();
+        assert_eq!(palettes.bg.len(), 8);
+        assert_eq!(palettes.obj.len(), 8);
+        assert_eq!(palettes.bg[2][1], (0xFF, 0x00, 0x00));
+        assert_eq!(palettes.bg[2][2], (0x00, 0x00, 0xFF));
+    }
+
+    #[test]
+    fn dump_palettes_maps_dmg_registers() {
+        let colors = DmgPalette::Grayscale.colors();
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        ppu.write(0xFF47, 0xE4);
+        ppu.write(0xFF48, 0x1B);
+
+        let palettes = ppu.dump_palettes();
+        assert_eq!(palettes.bg, vec![colors]);
+        assert_eq!(palettes.obj[0], [colors[3], colors[2], colors[1], colors[0]]);
+    }
+
+    #[test]
+    fn ly_ignores_writes_and_resets_with_the_lcd() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        run_to_line(&mut ppu, 40);
+        ppu.write(0xFF44, 0x00);
+        assert_eq!(ppu.read(0xFF44), 40);
+
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(ppu.read(0xFF44), 0);
+    }
+
+    #[test]
+    fn oam_is_locked_while_dma_is_active() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.write(0xFF40, 0x00);
+        ppu.oam_dma_active = true;
+        ppu.write(0xFE00, 0x12);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+
+        ppu.oam_dma_active = false;
+        assert_eq!(ppu.read(0xFE00), 0x00);
+        ppu.write(0xFE00, 0x12);
+        assert_eq!(ppu.read(0xFE00), 0x12);
+    }
+
+    #[test]
+    fn unchanged_frames_are_not_dirty() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        run_frame(&mut ppu);
+        assert!(ppu.take_frame_dirty());
+        assert!(!ppu.take_frame_dirty());
+
+        run_frame(&mut ppu);
+        assert!(!ppu.take_frame_dirty());
+
+        ppu.write(0xFF47, 0x1B);
+        run_frame(&mut ppu);
+        assert!(ppu.take_frame_dirty());
+    }
+
+    #[test]
+    fn disabling_the_lcd_shows_the_disabled_color() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.disabled_color = (0x12, 0x34, 0x56);
+        run_frame(&mut ppu);
+        assert_ne!(pixel(&ppu, 80, 72), (0x12, 0x34, 0x56));
+
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(pixel(&ppu, 0, 0), (0x12, 0x34, 0x56));
+        assert_eq!(pixel(&ppu, 159, 143), (0x12, 0x34, 0x56));
+    }
+
+    #[test]
+    fn sprites_are_clipped_at_both_edges() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        // Right half of the tile is colour 3, the left half transparent
+        for row in 0..8 {
+            ppu.poke(0x8010 + row * 2, 0x0F);
+            ppu.poke(0x8010 + row * 2 + 1, 0x0F);
+        }
+        // Hanging 4 pixels off the left edge on line 0, and flipped 4 pixels off the right on line 8
+        set_sprite(&mut ppu, 0, 16, 4, 1, 0x00);
+        set_sprite(&mut ppu, 1, 24, 164, 1, 0x20);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x93);
+        run_frame(&mut ppu);
+
+        let black = DmgPalette::Grayscale.colors()[3];
+        let white = DmgPalette::Grayscale.colors()[0];
+        assert!((0..4).all(|x| pixel(&ppu, x, 0) == black));
+        assert!((4..160).all(|x| pixel(&ppu, x, 0) == white));
+        assert!((156..160).all(|x| pixel(&ppu, x, 8) == black));
+        // Nothing wraps around to the left
+        assert!((0..156).all(|x| pixel(&ppu, x, 8) == white));
+    }
+
+    #[test]
+    fn forcing_a_compat_palette_loads_palette_ram() {
+        let mut ppu = PPU::new(GBMode::Color);
+        ppu.force_compat_palette(1);
+        let palettes = ppu.dump_palettes();
+        assert_eq!(palettes.bg[0][0], (0xFF, 0xFF, 0xFF));
+        assert_eq!(palettes.bg[0][1], (0xFF, 0x84, 0x84));
+        assert_eq!(palettes.obj[1][2], (0x00, 0x00, 0xFF));
+        assert_eq!(palettes.obj[1][3], (0x00, 0x00, 0x00));
+
+        // DMG mode has no palette RAM to load
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.force_compat_palette(1);
+        assert_eq!(ppu.dump_palettes().bg.len(), 1);
+    }
+
+    #[test]
+    fn dmg_compat_maps_dmg_palettes_into_the_compat_palette() {
+        let mut ppu = PPU::new(GBMode::Color);
+        ppu.dmg_compat = true;
+        ppu.force_compat_palette(1);
+        fill_tile(&mut ppu, 1, 3);
+        fill_tile(&mut ppu, 2, 1);
+        ppu.poke(0x9800, 0x01);
+        // A tile attribute asking for BG palette 2, which compatibility mode ignores
+        ppu.write(0xFF4F, 0x01);
+        ppu.poke(0x9800, 0x02);
+        ppu.write(0xFF4F, 0x00);
+        set_sprite(&mut ppu, 0, 16, 24, 0x02, 0x10);
+        // Inverted BGP, and an OBP1 sending colour 1 to shade 2
+        ppu.write(0xFF47, 0x1B);
+        ppu.write(0xFF49, 0x08);
+        ppu.write(0xFF40, 0x93);
+        run_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(pixel(&ppu, 8, 0), (0x00, 0x00, 0x00));
+        // OBJ palette 1, not 0
+        assert_eq!(pixel(&ppu, 16, 0), (0x00, 0x00, 0xFF));
+
+        // BGP changes show up on the next frame
+        ppu.write(0xFF47, 0xE4);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 0), (0x00, 0x00, 0x00));
+        assert_eq!(pixel(&ppu, 8, 0), (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn vram_writes_mark_vram_dirty() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.write(0xFF40, 0x00);
+        assert!(ppu.take_vram_dirty());
+        assert!(!ppu.take_vram_dirty());
+
+        // Other registers and OAM leave it alone
+        ppu.write(0xFF47, 0x1B);
+        ppu.write(0xFE00, 0x10);
+        assert!(!ppu.take_vram_dirty());
+
+        ppu.write(0x9800, 0x01);
+        assert!(ppu.take_vram_dirty());
+        ppu.poke(0x8000, 0xFF);
+        assert!(ppu.take_vram_dirty());
+        assert!(!ppu.take_vram_dirty());
+    }
+
+    // CGB frame with a colour 1 BG (or colour 0 if `bg_color0`) under a colour 3 sprite at the
+    // top left, returns whether the sprite pixel is the one shown
+    fn sprite_wins(lcdc_bit_0: bool, bg_priority: bool, oam_priority: bool, bg_color0: bool) -> bool {
+        let mut ppu = PPU::new(GBMode::Color);
+        // BG palette 0 colours 0 and 1 white and red, OBJ palette 0 colour 3 blue
+        ppu.write(0xFF68, 0x80);
+        for v in [0xFF, 0x7F, 0x1F, 0x00] {
+            ppu.write(0xFF69, v);
+        }
+        ppu.write(0xFF6A, 0x80 | 6);
+        for v in [0x00, 0x7C] {
+            ppu.write(0xFF6B, v);
+        }
+
+        fill_tile(&mut ppu, 0, if bg_color0 { 0 } else { 1 });
+        fill_tile(&mut ppu, 1, 3);
+        ppu.write(0xFF4F, 0x01);
+        ppu.poke(0x9800, if bg_priority { 0x80 } else { 0x00 });
+        ppu.write(0xFF4F, 0x00);
+        set_sprite(&mut ppu, 0, 16, 8, 1, if oam_priority { 0x80 } else { 0x00 });
+
+        ppu.write(0xFF40, if lcdc_bit_0 { 0x93 } else { 0x92 });
+        run_frame(&mut ppu);
+        pixel(&ppu, 0, 0) == (0x00, 0x00, 0xFF)
+    }
+
+    #[test]
+    fn cgb_sprite_priority_truth_table() {
+        for bg_priority in [false, true] {
+            for oam_priority in [false, true] {
+                // LCDC bit 0 clear: sprites always win
+                assert!(sprite_wins(false, bg_priority, oam_priority, false));
+                // Set: either priority bit puts BG colours 1-3 in front
+                assert_eq!(sprite_wins(true, bg_priority, oam_priority, false), !bg_priority && !oam_priority);
+                // BG colour 0 is always behind
+                assert!(sprite_wins(true, bg_priority, oam_priority, true));
+            }
+        }
+    }
+
+    #[test]
+    fn mid_frame_reads_see_the_previous_frame() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        ppu.write(0xFF47, 0xE4);
+        run_frame(&mut ppu);
+        run_to_line(&mut ppu, 0);
+        let white = DmgPalette::Grayscale.colors()[0];
+        let black = DmgPalette::Grayscale.colors()[3];
+        assert_eq!(pixel(&ppu, 0, 0), white);
+
+        ppu.write(0xFF47, 0xFF);
+        run_to_line(&mut ppu, 72);
+        assert!((0..SCREEN_H).all(|y| pixel(&ppu, 0, y) == white));
+
+        run_frame(&mut ppu);
+        assert!((0..SCREEN_H).all(|y| pixel(&ppu, 0, y) == black));
+    }
+
+    #[test]
+    fn render_scanline_only_changes_that_line() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        ppu.write(0xFF40, 0x11);
+        fill_tile(&mut ppu, 0, 3);
+        ppu.write(0xFF47, 0xE4);
+        let before = ppu.framebuffer().to_vec();
+        let ly = ppu.read(0xFF44);
+
+        ppu.render_scanline(72);
+        assert_eq!(ppu.read(0xFF44), ly);
+        for y in 0..SCREEN_H {
+            let row = 4 * y * SCREEN_W..4 * (y + 1) * SCREEN_W;
+            assert_eq!(ppu.framebuffer()[row.clone()] == before[row], y != 72, "line {}", y);
+        }
+        assert_eq!(pixel(&ppu, 80, 72), DmgPalette::Grayscale.colors()[3]);
+    }
+
+    #[test]
+    fn clearing_lcdc_bit_0_on_dmg_draws_bgp_colour_0() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        fill_tile(&mut ppu, 0, 3);
+        // Colour 0 maps to shade 1, so the fill isn't just the cleared frame buffer
+        ppu.write(0xFF47, 0xE5);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 80, 72), DmgPalette::Grayscale.colors()[3]);
+
+        ppu.write(0xFF40, 0x90);
+        run_frame(&mut ppu);
+        assert!((0..SCREEN_W).all(|x| pixel(&ppu, x, 72) == DmgPalette::Grayscale.colors()[1]));
+    }
+
+    #[test]
+    fn lines_draw_the_sprites_latched_in_mode_2() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        fill_tile(&mut ppu, 1, 3);
+        set_sprite(&mut ppu, 0, 88, 8, 1, 0x00);
+        ppu.write(0xFF48, 0xE4);
+        ppu.write(0xFF40, 0x93);
+        let black = DmgPalette::Grayscale.colors()[3];
+
+        run_to_line(&mut ppu, 72);
+        while ppu.ppu_mode != PPUMode::Draw {
+            ppu.cycle(4);
+        }
+        // Moved after the scan but before the line is drawn
+        set_sprite(&mut ppu, 0, 88, 88, 1, 0x00);
+        run_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 72), black);
+        assert_ne!(pixel(&ppu, 80, 72), black);
+
+        // The next line scans again and sees the move
+        assert_ne!(pixel(&ppu, 0, 73), black);
+        assert_eq!(pixel(&ppu, 80, 73), black);
+    }
+
+    #[test]
+    fn pixel_sources_mark_sprite_pixels() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_pixel_sources(true);
+        fill_tile(&mut ppu, 1, 3);
+        set_sprite(&mut ppu, 3, 16, 8, 1, 0x00);
+        ppu.write(0xFF40, 0x93);
+        run_frame(&mut ppu);
+
+        for y in 0..SCREEN_H {
+            for x in 0..SCREEN_W {
+                let expected = if x < 8 && y < 8 { PixelSource::Sprite(3) } else { PixelSource::BG };
+                assert_eq!(ppu.pixel_source(x, y), expected, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn ly_reads_0_for_most_of_line_153() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.write(0xFF45, 0x00);
+        run_to_line(&mut ppu, 153);
+        assert_eq!(ppu.read(0xFF41) & 0x07, 0x01);
+
+        ppu.cycle(4);
+        assert_eq!(ppu.read(0xFF44), 0);
+        // Still VBlank, but LYC=0 already matches
+        assert_eq!(ppu.read(0xFF41) & 0x07, 0x05);
+
+        for _ in 0..100 {
+            ppu.cycle(4);
+            assert_eq!(ppu.read(0xFF44), 0);
+        }
+        while ppu.read(0xFF41) & 0x03 == 0x01 {
+            ppu.cycle(4);
+            assert_eq!(ppu.read(0xFF44), 0);
+        }
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0x02);
+    }
+
+    #[test]
+    fn dump_tiles_lays_out_16_tiles_per_row() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.set_dmg_palette_preset(DmgPalette::Grayscale);
+        ppu.write(0xFF47, 0xE4);
+        // Tile 17 is the second tile of the second row
+        fill_tile(&mut ppu, 17, 3);
+        let at = |tiles: &[u8], x: usize, y: usize| {
+            let i = 4 * (y * TILES_W + x);
+            (tiles[i], tiles[i + 1], tiles[i + 2])
+        };
+
+        let tiles = ppu.dump_tiles(0);
+        assert_eq!(tiles.len(), 4 * TILES_W * TILES_H);
+        assert_eq!(at(&tiles, 8, 8), DmgPalette::Grayscale.colors()[3]);
+        assert_eq!(at(&tiles, 15, 15), DmgPalette::Grayscale.colors()[3]);
+        assert_eq!(at(&tiles, 16, 8), DmgPalette::Grayscale.colors()[0]);
+        assert_eq!(at(&tiles, TILES_W - 1, TILES_H - 1), DmgPalette::Grayscale.colors()[0]);
+
+        // A fixed viewer palette ignores BGP
+        let viewer = [(1, 1, 1), (2, 2, 2), (3, 3, 3), (4, 4, 4)];
+        ppu.viewer_palette = Some(viewer);
+        ppu.write(0xFF47, 0x00);
+        let tiles = ppu.dump_tiles(0);
+        assert_eq!(at(&tiles, 8, 8), (4, 4, 4));
+        assert_eq!(at(&tiles, 0, 0), (1, 1, 1));
+    }
+
+    #[test]
+    fn dump_tilemap_follows_the_tile_data_area() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.viewer_palette = Some([(0, 0, 0), (1, 1, 1), (2, 2, 2), (3, 3, 3)]);
+        fill_tile(&mut ppu, 1, 3);
+        // Tile 1 at $8000 or $9000, depending on LCDC bit 4
+        ppu.poke(0x9C21, 0x01);
+        ppu.write(0xFF40, 0x18);
+        let map = ppu.dump_tilemap(true);
+        assert_eq!(map.len(), 4 * 256 * 256);
+        assert_eq!(map[4 * (8 * 256 + 8)], 3);
+        assert_eq!(map[4 * (8 * 256 + 16)], 0);
+        assert!(ppu.dump_tilemap(false).chunks_exact(4).all(|p| p[0] == 0));
+
+        ppu.write(0xFF40, 0x08);
+        assert_eq!(ppu.dump_tilemap(true)[4 * (8 * 256 + 8)], 0);
+    }
+
+    // LCD interrupts per line from the start of one VBlank to the next
+    fn stat_interrupts_per_line(lyc: u8, select: LCDS) -> Vec<u32> {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.write(0xFF45, lyc);
+        ppu.write(0xFF41, select.bits());
+        run_frame(&mut ppu);
+        ppu.interrupts = Interrupts::empty();
+
+        let mut counts = vec![0; 154];
+        loop {
+            let vblank = ppu.cycle(4);
+            if ppu.interrupts.contains(Interrupts::LCD) {
+                counts[ppu.read(0xFF44) as usize] += 1;
+                ppu.interrupts.remove(Interrupts::LCD);
+            }
+            if vblank {
+                return counts;
+            }
+        }
+    }
+
+    #[test]
+    fn hblank_and_lyc_on_the_same_line_raise_one_interrupt() {
+        let both = LCDS::LYC_SELECT | LCDS::MODE_0_SELECT;
+        // Line 0 follows VBlank, so LYC raises the line and its HBlank adds nothing
+        let counts = stat_interrupts_per_line(0x00, both);
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts, stat_interrupts_per_line(0x00, LCDS::MODE_0_SELECT));
+
+        // Mid-frame the previous HBlank is still high when LYC matches, so one interrupt
+        // covers both lines instead of one each
+        let counts = stat_interrupts_per_line(0x40, both);
+        assert_eq!(counts[0x3F] + counts[0x40], 1);
+        assert_eq!(counts.iter().sum::<u32>(), 143);
+
+        // Without the LYC match the line drops between HBlanks and each one fires
+        assert!(stat_interrupts_per_line(0xFF, both)[..144].iter().all(|&n| n == 1));
+    }
+
+    #[test]
+    fn both_vram_banks_are_locked_during_mode_3() {
+        let mut ppu = enabled_ppu(GBMode::Color);
+        for bank in 0..2 {
+            ppu.write(0xFF4F, bank);
+            ppu.poke(0x8000, 0x10 + bank);
+        }
+        run_to_line(&mut ppu, 10);
+        while ppu.ppu_mode != PPUMode::Draw {
+            ppu.cycle(4);
+        }
+
+        for bank in 0..2 {
+            ppu.write(0xFF4F, bank);
+            assert_eq!(ppu.read(0xFF4F), 0xFE | bank);
+            assert_eq!(ppu.read(0x8000), 0xFF);
+            ppu.write(0x8000, 0x00);
+        }
+
+        while ppu.ppu_mode == PPUMode::Draw {
+            ppu.cycle(4);
+        }
+        for bank in 0..2 {
+            ppu.write(0xFF4F, bank);
+            assert_eq!(ppu.read(0x8000), 0x10 + bank);
+        }
+    }
+
+    // Dots spent in Mode 3 on line 40 with sprites at each of the given OAM X positions
+    fn mode_3_dots(xs: &[u8]) -> u32 {
+        let mut ppu = PPU::new(GBMode::Classic);
+        for (i, &x) in xs.iter().enumerate() {
+            set_sprite(&mut ppu, i as u16, 56, x, 0, 0x00);
+        }
+        ppu.write(0xFF40, 0x93);
+        run_to_line(&mut ppu, 40);
+        while ppu.read(0xFF41) & 0x03 != 0x03 {
+            ppu.cycle(1);
+        }
+        let mut dots = 0;
+        while ppu.read(0xFF41) & 0x03 == 0x03 {
+            ppu.cycle(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn ten_sprites_lengthen_mode_3() {
+        let base = mode_3_dots(&[]);
+        // Each on its own tile boundary waits 5 dots for the BG fetch plus 6 for its own
+        let spread: Vec<u8> = (0..10).map(|i| 8 + i * 16).collect();
+        assert_eq!(mode_3_dots(&spread) - base, 10 * (5 + 6));
+        // Sharing a tile, only the first waits for the BG fetch
+        assert_eq!(mode_3_dots(&[8; 10]) - base, 5 + 10 * 6);
+        // Hanging fully off the left edge costs 11 each
+        assert_eq!(mode_3_dots(&[0; 10]) - base, 10 * 11);
+        // An 11th sprite isn't latched, so adds nothing
+        let mut eleven = spread.clone();
+        eleven.push(160);
+        assert_eq!(mode_3_dots(&eleven), mode_3_dots(&spread));
+    }
+
+    #[test]
+    fn first_line_after_lcd_enable_has_no_mode_2_interrupt() {
+        let mut ppu = PPU::new(GBMode::Classic);
+        ppu.write(0xFF41, LCDS::MODE_2_SELECT.bits());
+        ppu.write(0xFF40, 0x91);
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0x00);
+
+        let mut modes = vec![];
+        while ppu.read(0xFF44) == 0 {
+            let mode = ppu.read(0xFF41) & 0x03;
+            if modes.last() != Some(&mode) {
+                modes.push(mode);
+            }
+            assert!(!ppu.interrupts.contains(Interrupts::LCD));
+            ppu.cycle(4);
+        }
+        assert_eq!(modes, vec![0, 3, 0]);
+
+        // Line 1 starts with a normal Mode 2
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0x02);
+        assert!(ppu.interrupts.contains(Interrupts::LCD));
+    }
+
+    #[test]
+    fn stat_reads_bit_7_set_with_read_only_low_bits() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.write(0xFF45, 0x05);
+        // Bits 0-2 ignore writes
+        ppu.write(0xFF41, 0xFF);
+        assert_eq!(ppu.read(0xFF41) & 0x78, 0x78);
+        ppu.write(0xFF41, 0x07);
+        assert_eq!(ppu.read(0xFF41) & 0x78, 0x00);
+
+        let mut seen = [false; 4];
+        let mut lyc_seen = false;
+        for _ in 0..(FRAME_DOTS / 4) {
+            let stat = ppu.read(0xFF41);
+            assert_eq!(stat & 0x80, 0x80);
+            assert_eq!(stat & 0x03, ppu.ppu_mode as u8);
+            assert_eq!(stat & 0x04 != 0, ppu.read(0xFF44) == 0x05);
+            seen[ppu.ppu_mode as usize] = true;
+            lyc_seen |= stat & 0x04 != 0;
+            ppu.cycle(4);
+        }
+        assert_eq!(seen, [true; 4]);
+        assert!(lyc_seen);
+    }
+
+    #[test]
+    fn tall_sprites_draw_each_half_from_its_own_tile() {
+        let colors = DmgPalette::Grayscale.colors();
+        // (colour, x) drawn on each of the 16 rows, the rest of the row is transparent
+        let upright: Vec<(usize, usize)> = (0..16).map(|y| if y < 8 { (1, y) } else { (2, y - 8) }).collect();
+        let flipped: Vec<(usize, usize)> = (0..16).map(|y| if y < 8 { (2, 7 - y) } else { (1, 15 - y) }).collect();
+
+        // Bit 0 of the OAM tile number is ignored, so 3 draws the same pair as 2
+        for (oam_tile, attributes, rows) in [
+            (0x02, 0x00, &upright),
+            (0x03, 0x00, &upright),
+            (0x02, Attributes::Y_FLIP.bits(), &flipped),
+            (0x03, Attributes::Y_FLIP.bits(), &flipped),
+        ] {
+            let ppu = tall_sprite_frame(oam_tile, attributes);
+            for (y, &(color, x)) in rows.iter().enumerate() {
+                for sx in 0..8 {
+                    let expected = if sx == x { colors[color] } else { colors[0] };
+                    assert_eq!(pixel(&ppu, sx, y), expected, "tile {} attributes {:#04x} at ({}, {})", oam_tile, attributes, sx, y);
+                }
+            }
+            assert_eq!(pixel(&ppu, 0, 16), colors[0]);
+        }
+    }
+
+    #[test]
+    fn palettes_power_on_with_post_boot_values() {
+        for mode in [GBMode::Classic, GBMode::Color] {
+            let ppu = PPU::new(mode);
+            assert_eq!(ppu.read(0xFF47), 0xFC);
+            assert_eq!(ppu.read(0xFF48), 0xFF);
+            assert_eq!(ppu.read(0xFF49), 0xFF);
+        }
+    }
+
+    #[test]
+    fn stat_line_rises_once_as_ly_crosses_lyc() {
+        let mut ppu = enabled_ppu(GBMode::Classic);
+        ppu.write(0xFF41, LCDS::LYC_SELECT.bits());
+        ppu.set_registers_for_test(PPUMode::OAMScan, 0x3E, 0x40);
+        assert!(!ppu.stat_line());
+        ppu.interrupts = Interrupts::empty();
+
+        let mut rises = vec![];
+        let mut falls = vec![];
+        let mut line = ppu.stat_line();
+        while ppu.read(0xFF44) != 0x43 {
+            ppu.cycle(4);
+            if ppu.stat_line() != line {
+                line = ppu.stat_line();
+                let ly = ppu.read(0xFF44);
+                if line {
+                    rises.push(ly);
+                } else {
+                    falls.push(ly);
+                }
+            }
+        }
+        assert_eq!(rises, [0x40]);
+        assert_eq!(falls, [0x41]);
+        assert!(ppu.interrupts.contains(Interrupts::LCD));
+
+        // Landing on a matching line counts as a rising edge too
+        ppu.interrupts = Interrupts::empty();
+        ppu.set_registers_for_test(PPUMode::HBlank, 0x60, 0x60);
+        assert!(ppu.stat_line());
+        assert!(ppu.interrupts.contains(Interrupts::LCD));
+    }
+}
@@ -0,0 +1,304 @@
+use bitflags::bitflags;
+
+pub const PRINTER_W: usize = 160;
+// The printer holds up to 8KiB of tile data (9 data packets)
+const BUFFER_SIZE: usize = 0x2000;
+
+bitflags! {
+    #[derive(Copy, Clone)]
+    pub struct PrinterStatus: u8 {
+        const LOW_BATTERY      = 0b1000_0000;
+        const OTHER_ERROR      = 0b0100_0000;
+        const PAPER_JAM        = 0b0010_0000;
+        const PACKET_ERROR     = 0b0001_0000;
+        const UNPROCESSED_DATA = 0b0000_1000;
+        const IMAGE_DATA_FULL  = 0b0000_0100;
+        const PRINTING         = 0b0000_0010;
+        const CHECKSUM_ERROR   = 0b0000_0001;
+    }
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum PacketState {
+    MagicHigh,
+    MagicLow,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status
+}
+
+pub struct Printer {
+    state: PacketState,
+    status: PrinterStatus,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    checksum: u16,
+    received_checksum: u16,
+    packet: Vec<u8>,
+    buffer: Vec<u8>,
+    printout: Option<Vec<u8>>
+}
+
+impl Printer {
+    pub fn new() -> Self {
+        Self {
+            state: PacketState::MagicHigh,
+            status: PrinterStatus::empty(),
+            command: 0,
+            compressed: false,
+            length: 0,
+            checksum: 0,
+            received_checksum: 0,
+            packet: Vec::new(),
+            buffer: Vec::new(),
+            printout: None
+        }
+    }
+
+    // Takes the last printed image as an RGBA buffer PRINTER_W pixels wide
+    pub fn take_printout(&mut self) -> Option<Vec<u8>> {
+        self.printout.take()
+    }
+
+    // Shifts one byte in from the Game Boy and returns the byte shifted out
+    pub fn exchange(&mut self, v: u8) -> u8 {
+        match self.state {
+            PacketState::MagicHigh => {
+                if v == 0x88 {
+                    self.state = PacketState::MagicLow;
+                }
+                0x00
+            },
+            PacketState::MagicLow => {
+                self.state = if v == 0x33 { PacketState::Command } else { PacketState::MagicHigh };
+                0x00
+            },
+            PacketState::Command => {
+                self.command = v;
+                self.checksum = v as u16;
+                self.state = PacketState::Compression;
+                0x00
+            },
+            PacketState::Compression => {
+                self.compressed = v & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                self.state = PacketState::LengthLow;
+                0x00
+            },
+            PacketState::LengthLow => {
+                self.length = v as u16;
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                self.state = PacketState::LengthHigh;
+                0x00
+            },
+            PacketState::LengthHigh => {
+                self.length |= (v as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                self.packet.clear();
+                self.state = if self.length == 0 { PacketState::ChecksumLow } else { PacketState::Data };
+                0x00
+            },
+            PacketState::Data => {
+                self.packet.push(v);
+                self.checksum = self.checksum.wrapping_add(v as u16);
+                if self.packet.len() >= self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+                0x00
+            },
+            PacketState::ChecksumLow => {
+                self.received_checksum = v as u16;
+                self.state = PacketState::ChecksumHigh;
+                0x00
+            },
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (v as u16) << 8;
+                self.process_packet();
+                self.state = PacketState::Alive;
+                0x00
+            },
+            PacketState::Alive => {
+                self.state = PacketState::Status;
+                0x81
+            },
+            PacketState::Status => {
+                self.state = PacketState::MagicHigh;
+                let status = self.status.bits();
+                // Printing finishes instantly, so only report it once
+                self.status.remove(PrinterStatus::PRINTING);
+                status
+            }
+        }
+    }
+
+    fn process_packet(&mut self) {
+        if self.received_checksum != self.checksum {
+            self.status |= PrinterStatus::CHECKSUM_ERROR;
+            return;
+        }
+        self.status.remove(PrinterStatus::CHECKSUM_ERROR);
+
+        match self.command {
+            // Initialize
+            0x01 => {
+                self.buffer.clear();
+                self.status = PrinterStatus::empty();
+            },
+            // Print
+            0x02 => {
+                // Byte 2 holds the palette, 0x00 behaves like the default 0xE4
+                let palette = match self.packet.get(2) {
+                    Some(0x00) | None => 0xE4,
+                    Some(&p) => p
+                };
+                self.printout = Some(self.render(palette));
+                self.buffer.clear();
+                self.status.remove(PrinterStatus::UNPROCESSED_DATA | PrinterStatus::IMAGE_DATA_FULL);
+                self.status |= PrinterStatus::PRINTING;
+            },
+            // Data
+            0x04 => {
+                let data = if self.compressed {
+                    Self::decompress(&self.packet)
+                } else {
+                    self.packet.clone()
+                };
+                let space = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(space)]);
+
+                if !data.is_empty() {
+                    self.status |= PrinterStatus::UNPROCESSED_DATA;
+                }
+                if self.buffer.len() >= BUFFER_SIZE {
+                    self.status |= PrinterStatus::IMAGE_DATA_FULL;
+                }
+            },
+            // Status inquiry
+            0x0F => {},
+            _ => self.status |= PrinterStatus::PACKET_ERROR
+        }
+    }
+
+    // Run-length decoding: bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+    // otherwise the next n + 1 bytes are copied as-is
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+
+        while i < data.len() {
+            let n = data[i];
+            i += 1;
+
+            if n & 0x80 != 0 {
+                if let Some(&b) = data.get(i) {
+                    out.resize(out.len() + (n & 0x7F) as usize + 2, b);
+                }
+                i += 1;
+            } else {
+                let end = (i + n as usize + 1).min(data.len());
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+        }
+
+        out
+    }
+
+    fn render(&self, palette: u8) -> Vec<u8> {
+        // 20 tiles per row, 16 bytes per tile
+        let tile_rows = self.buffer.len() / (16 * 20);
+        let height = tile_rows * 8;
+        let mut image = vec![0x00; 4 * PRINTER_W * height];
+
+        for y in 0..height {
+            for x in 0..PRINTER_W {
+                let tile = (y / 8) * 20 + x / 8;
+                let address = tile * 16 + (y % 8) * 2;
+                let bit = 0x80 >> (x % 8);
+
+                let color_l = if self.buffer[address] & bit != 0 { 1 } else { 0 };
+                let color_h = if self.buffer[address + 1] & bit != 0 { 2 } else { 0 };
+                let color = color_h | color_l;
+
+                let shade = match palette >> (2 * color) & 0x03 {
+                    0x00 => 0xFF,
+                    0x01 => 0xAA,
+                    0x02 => 0x55,
+                    _ => 0x00
+                };
+
+                let offset = 4 * (y * PRINTER_W + x);
+                image[offset] = shade;
+                image[offset + 1] = shade;
+                image[offset + 2] = shade;
+                image[offset + 3] = 0xFF;
+            }
+        }
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sends a whole packet and returns the two bytes shifted back during the alive and status bytes
+    fn send_packet(printer: &mut Printer, command: u8, data: &[u8]) -> (u8, u8) {
+        let mut checksum = command as u16 + (data.len() as u16 & 0xFF) + (data.len() as u16 >> 8);
+        for &b in data {
+            checksum = checksum.wrapping_add(b as u16);
+        }
+
+        let mut bytes = vec![0x88, 0x33, command, 0x00, data.len() as u8, (data.len() >> 8) as u8];
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8]);
+        for b in bytes {
+            assert_eq!(printer.exchange(b), 0x00);
+        }
+        (printer.exchange(0x00), printer.exchange(0x00))
+    }
+
+    #[test]
+    fn prints_two_tile_rows() {
+        let mut printer = Printer::new();
+        send_packet(&mut printer, 0x01, &[]);
+
+        // Two rows of 20 tiles, all colour 3
+        let (alive, status) = send_packet(&mut printer, 0x04, &[0xFF; 2 * 20 * 16]);
+        assert_eq!(alive, 0x81);
+        assert_eq!(status, PrinterStatus::UNPROCESSED_DATA.bits());
+        assert!(printer.take_printout().is_none());
+
+        let (_, status) = send_packet(&mut printer, 0x02, &[0x01, 0x13, 0xE4, 0x40]);
+        assert_eq!(status, PrinterStatus::PRINTING.bits());
+
+        let printout = printer.take_printout().unwrap();
+        assert_eq!(printout.len(), 4 * PRINTER_W * 16);
+        assert_eq!(&printout[0..4], &[0x00, 0x00, 0x00, 0xFF]);
+        assert!(printer.take_printout().is_none());
+    }
+
+    #[test]
+    fn reports_checksum_errors() {
+        let mut printer = Printer::new();
+        for b in [0x88, 0x33, 0x0F, 0x00, 0x00, 0x00, 0xFF, 0xFF] {
+            printer.exchange(b);
+        }
+        printer.exchange(0x00);
+        assert_eq!(printer.exchange(0x00), PrinterStatus::CHECKSUM_ERROR.bits());
+    }
+
+    #[test]
+    fn decompresses_runs() {
+        let data = Printer::decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34]);
+        assert_eq!(data, vec![0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+    }
+}
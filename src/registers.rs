@@ -1,9 +1,10 @@
 use bitflags::bitflags;
-use crate::mode::GBMode;
+use crate::boot::BootValues;
+use crate::mode::Model;
 use std::fmt;
 use std::fmt::Formatter;
 
-#[derive(Debug)]
+#[derive(Debug, Hash)]
 pub struct Registers {
     pub a: u8,
     f: u8,
@@ -81,37 +82,25 @@ impl Registers {
         }
     }
 
-    pub fn new(mode: GBMode, booting: bool) -> Registers {
-        match mode {
-            GBMode::Classic => {
-                Registers {
-                    a: 0x01,
-                    f: (Flags::C | Flags::H | Flags::Z).bits(),
-                    b: 0x00,
-                    c: 0x13,
-                    d: 0x00,
-                    e: 0xD8,
-                    h: 0x01,
-                    l: 0x4D,
-                    pc: if booting { 0x0000 } else { 0x0100 },
-                    sp: 0xFFFE
-                }
-            },
-            GBMode::Color => {
-                Registers {
-                    a: 0x11,
-                    f: (Flags::Z).bits(),
-                    b: 0x00,
-                    c: 0x00,
-                    d: 0xFF,
-                    e: 0x56,
-                    h: 0x00,
-                    l: 0x0D,
-                    pc: if booting { 0x0000 } else { 0x0100 },
-                    sp: 0xFFFE
-                }
-            }
-        }
+    pub fn new(model: Model, booting: bool) -> Registers {
+        let values = BootValues::new(model);
+        let mut reg = Registers {
+            a: 0x00,
+            f: 0x00,
+            b: 0x00,
+            c: 0x00,
+            d: 0x00,
+            e: 0x00,
+            h: 0x00,
+            l: 0x00,
+            pc: if booting { 0x0000 } else { 0x0100 },
+            sp: values.sp
+        };
+        reg.set_af(values.af);
+        reg.set_bc(values.bc);
+        reg.set_de(values.de);
+        reg.set_hl(values.hl);
+        reg
     }
 }
 
@@ -0,0 +1,80 @@
+// Scale2x (EPX) upscaling of a w x h RGBA image into a 2w x 2h buffer.
+// Each pixel becomes a 2x2 block, where corners take the colour of two matching
+// neighbours to smooth diagonal edges. Neighbours past the border are clamped.
+pub fn scale2x(src: &[u8], w: usize, h: usize, out: &mut [u8]) {
+    assert_eq!(src.len(), 4 * w * h, "Source must be w x h RGBA pixels!");
+    assert_eq!(out.len(), 16 * w * h, "Output must be 2w x 2h RGBA pixels!");
+
+    let pixel = |x: usize, y: usize| -> &[u8] {
+        let offset = 4 * (y * w + x);
+        &src[offset..offset + 4]
+    };
+
+    for y in 0..h {
+        for x in 0..w {
+            let p = pixel(x, y);
+            let a = pixel(x, y.saturating_sub(1));
+            let b = pixel((x + 1).min(w - 1), y);
+            let c = pixel(x.saturating_sub(1), y);
+            let d = pixel(x, (y + 1).min(h - 1));
+
+            let e0 = if c == a && c != d && a != b { a } else { p };
+            let e1 = if a == b && a != c && b != d { b } else { p };
+            let e2 = if d == c && d != b && c != a { c } else { p };
+            let e3 = if b == d && b != a && d != c { d } else { p };
+
+            for (i, e) in [e0, e1, e2, e3].iter().enumerate() {
+                let ox = 2 * x + (i & 1);
+                let oy = 2 * y + (i >> 1);
+                let offset = 4 * (oy * 2 * w + ox);
+                out[offset..offset + 4].copy_from_slice(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const X: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+    const O: [u8; 4] = [0x00, 0x00, 0x00, 0xFF];
+
+    fn image(pixels: &[[u8; 4]]) -> Vec<u8> {
+        pixels.concat()
+    }
+
+    #[test]
+    fn lone_pixels_scale_to_blocks() {
+        // O surrounded by X has no two matching neighbours that differ from it
+        let src = image(&[X, X, X, X, O, X, X, X, X]);
+        let mut out = vec![0x00; 16 * 9];
+        scale2x(&src, 3, 3, &mut out);
+
+        let mut expected = vec![X; 36];
+        for i in [14, 15, 20, 21] {
+            expected[i] = O;
+        }
+        assert_eq!(out, image(&expected));
+    }
+
+    #[test]
+    fn diagonal_edges_are_smoothed() {
+        // X X O
+        // X O O
+        // O O O
+        let src = image(&[X, X, O, X, O, O, O, O, O]);
+        let mut out = vec![0x00; 16 * 9];
+        scale2x(&src, 3, 3, &mut out);
+
+        // The centre's top-left corner matches its top and left neighbours
+        let centre = |i: usize| {
+            let offset = 4 * ((2 + (i >> 1)) * 6 + 2 + (i & 1));
+            &out[offset..offset + 4]
+        };
+        assert_eq!(centre(0), X);
+        assert_eq!(centre(1), O);
+        assert_eq!(centre(2), O);
+        assert_eq!(centre(3), O);
+    }
+}
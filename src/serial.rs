@@ -1,31 +1,88 @@
+use std::hash::{Hash, Hasher};
 use std::io::Write;
 use crate::memory::Memory;
 use crate::mmu::Interrupts;
+use crate::mode::GBMode;
+use crate::printer::Printer;
 
 // TODO: Handle serial properly
 pub struct Serial {
+    mode: GBMode,
     pub interrupts: Interrupts,
     sb: u8,
     sc: u8,
-    print: bool
+    // CPU cycles left in the current internally clocked transfer
+    transfer_cycles: Option<u32>,
+    print: bool,
+    printer: Option<Printer>
 }
 
 impl Serial {
-    pub fn new(print: bool) -> Self {
+    pub fn new(mode: GBMode, print: bool) -> Self {
         Self {
+            mode,
             interrupts: Interrupts::empty(),
             sb: 0,
             sc: 0,
-            print
+            transfer_cycles: None,
+            print,
+            printer: None
         }
     }
+
+    pub fn attach_printer(&mut self) {
+        self.printer = Some(Printer::new());
+    }
+
+    pub fn take_printout(&mut self) -> Option<Vec<u8>> {
+        self.printer.as_mut().and_then(|p| p.take_printout())
+    }
+
+    // `cycles` is in CPU clocks, the serial clock is divided from it so
+    // double speed mode also doubles the transfer rate
+    pub fn cycle(&mut self, cycles: u32) {
+        if let Some(remaining) = self.transfer_cycles {
+            if cycles >= remaining {
+                self.transfer_cycles = None;
+                self.transfer();
+            } else {
+                self.transfer_cycles = Some(remaining - cycles);
+            }
+        }
+    }
+
+    // 8 bits at 8192 Hz, or 262144 Hz with the CGB fast clock selected
+    fn transfer_length(&self) -> u32 {
+        let cycles_per_bit = if self.mode == GBMode::Color && self.sc & 0x02 != 0 { 16 } else { 512 };
+        8 * cycles_per_bit
+    }
+
+    fn transfer(&mut self) {
+        // Nothing connected reads back as all 1s
+        self.sb = match self.printer.as_mut() {
+            Some(printer) => printer.exchange(self.sb),
+            None => 0xFF
+        };
+        self.sc &= 0x7F;
+        self.interrupts |= Interrupts::SERIAL;
+    }
+}
+
+impl Hash for Serial {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.interrupts.bits().hash(state);
+        self.sb.hash(state);
+        self.sc.hash(state);
+        self.transfer_cycles.hash(state);
+    }
 }
 
 impl Memory for Serial {
     fn read(&self, a: u16) -> u8 {
         match a {
             0xFF01 => self.sb,
-            0xFF02 => self.sc,
+            // Unused bits read as 1, bit 1 (clock speed) only exists on CGB
+            0xFF02 => self.sc | if self.mode == GBMode::Color { 0x7C } else { 0x7E },
             _ => panic!("Read to unsupported Serial address ({:#06x})!", a),
         }
     }
@@ -39,8 +96,57 @@ impl Memory for Serial {
                     let _ = std::io::stdout().flush();
                 }
             },
-            0xFF02 => self.sc = v,
+            0xFF02 => {
+                self.sc = v;
+                // Transfer enable with internal clock, completes once all 8 bits are shifted out
+                self.transfer_cycles = if v & 0x81 == 0x81 {
+                    Some(self.transfer_length())
+                } else {
+                    None
+                };
+            },
             _ => panic!("Write to unsupported Serial address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cycles from starting an internally clocked transfer to its interrupt
+    fn transfer_cycles(mode: GBMode, sc: u8) -> u32 {
+        let mut serial = Serial::new(mode, false);
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, sc);
+        let mut cycles = 0;
+        while !serial.interrupts.contains(Interrupts::SERIAL) {
+            serial.cycle(4);
+            cycles += 4;
+        }
+        cycles
+    }
+
+    #[test]
+    fn transfers_take_8_serial_clocks() {
+        assert_eq!(transfer_cycles(GBMode::Classic, 0x81), 4096);
+        assert_eq!(transfer_cycles(GBMode::Color, 0x81), 4096);
+        assert_eq!(transfer_cycles(GBMode::Color, 0x83), 128);
+        // The fast clock bit isn't there on DMG
+        assert_eq!(transfer_cycles(GBMode::Classic, 0x83), 4096);
+    }
+
+    #[test]
+    fn finished_transfers_shift_in_1s_and_clear_the_start_bit() {
+        let mut serial = Serial::new(GBMode::Classic, false);
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        serial.cycle(4092);
+        assert_eq!(serial.read(0xFF01), 0x42);
+        assert!(serial.interrupts.is_empty());
+
+        serial.cycle(4);
+        assert_eq!(serial.read(0xFF01), 0xFF);
+        assert_eq!(serial.read(0xFF02), 0x7F);
+    }
+}
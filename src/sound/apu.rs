@@ -1,12 +1,21 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::Ordering;
 use bitflags::bitflags;
 use crate::memory::Memory;
+use crate::mode::{ClockSpeed, GBMode};
 use crate::sound::sc1::SC1;
 use crate::sound::sc2::SC2;
 use crate::sound::sc3::{OutputLevel, SC3};
 use crate::sound::sc4::SC4;
 use crate::sound::synth::Synth;
+use crate::timing::{CPU_HZ, FRAME_DOTS};
+
+// Length of the fade out queued when pausing
+const PAUSE_FADE_MS: u32 = 100;
 
 pub struct APU {
+    mode: GBMode,
+    clock: ClockSpeed,
     audio_enabled: bool,
     is_ch_4_on: bool,
     is_ch_3_on: bool,
@@ -14,16 +23,23 @@ pub struct APU {
     is_ch_1_on: bool,
     left_volume: u8,
     right_volume: u8,
+    // NR50 bits 7 and 3, cartridge audio isn't emulated but the bits read back
+    vin: u8,
     panning: Panning,
+    // Frame sequencer step (0-7), advanced by DIV rather than a free-running counter
+    frame_step: u8,
     sc1: SC1,
     sc2: SC2,
     sc3: SC3,
     sc4: SC4,
-    synth: Synth
+    synth: Synth,
+    // Output samples rendered in the previous frame and so far in this one, see end_frame
+    last_frame_samples: u32,
+    frame_samples: u32
 }
 
 bitflags! {
-    #[derive(Copy, Clone)]
+    #[derive(Copy, Clone, Hash, Debug)]
     pub struct Panning: u8 {
         const CH4_LEFT = 0b1000_0000;
         const CH3_LEFT = 0b0100_0000;
@@ -36,11 +52,51 @@ bitflags! {
     }
 }
 
+// Read-only snapshot of a single channel for debuggers
+#[derive(Copy, Clone, Debug)]
+pub struct ChannelState {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    // Channel 4 has no period, only a frequency
+    pub period: Option<u16>,
+    pub frequency: f64,
+    // Channel 3 reports its output level (0-3) rather than a 4-bit volume
+    pub volume: u8,
+    pub duty: Option<DutyCycle>,
+    pub length: u16,
+    pub length_enabled: bool
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StereoMode {
+    Stereo,
+    // Left and right averaged
+    Mono,
+    // Blends toward mono, 0.0 is full stereo and 1.0 is mono
+    NarrowedStereo(f32)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MasterState {
+    pub audio_enabled: bool,
+    pub left_volume: u8,
+    pub right_volume: u8,
+    pub panning: Panning,
+    // Raw NR50, NR51, NR52 values
+    pub nr50: u8,
+    pub nr51: u8,
+    pub nr52: u8
+}
+
 impl APU {
-    pub fn new() -> Self {
-        let synth = Synth::new();
+    // `sample_rate` overrides the output device's preferred rate. Without `audio` the channels
+    // still run, but no output device is opened.
+    pub fn new(mode: GBMode, clock: ClockSpeed, sample_rate: Option<u32>, audio: bool) -> Self {
+        let synth = if audio { Synth::new(sample_rate) } else { Synth::silent(sample_rate) };
 
         Self {
+            mode,
+            clock,
             audio_enabled: true,
             is_ch_4_on: false,
             is_ch_3_on: false,
@@ -48,28 +104,48 @@ impl APU {
             is_ch_1_on: false,
             left_volume: 0,
             right_volume: 0,
+            vin: 0,
             panning: Panning::empty(),
+            frame_step: 0,
             sc1: SC1::new(),
             sc2: SC2::new(),
-            sc3: SC3::new(),
+            sc3: SC3::new(mode),
             sc4: SC4::new(),
-            synth
+            synth,
+            last_frame_samples: 0,
+            frame_samples: 0
         }
     }
 
+    // Back to power-on state, the synth and its audio stream keep running
+    pub fn reset(&mut self) {
+        self.audio_enabled = true;
+        self.is_ch_1_on = false;
+        self.is_ch_2_on = false;
+        self.is_ch_3_on = false;
+        self.is_ch_4_on = false;
+        self.left_volume = 0;
+        self.right_volume = 0;
+        self.vin = 0;
+        self.panning = Panning::empty();
+        self.frame_step = 0;
+        self.sc1 = SC1::new();
+        self.sc2 = SC2::new();
+        let wave_ram_bug_enabled = self.sc3.wave_ram_bug_enabled;
+        self.sc3 = SC3::new(self.mode);
+        self.sc3.wave_ram_bug_enabled = wave_ram_bug_enabled;
+        self.sc4 = SC4::new();
+        self.last_frame_samples = 0;
+        self.frame_samples = 0;
+    }
+
     pub fn cycle(&mut self, cycles: u32) {
         self.sc1.cycle(cycles);
         self.sc2.cycle(cycles);
         self.sc3.cycle(cycles);
         self.sc4.cycle(cycles);
 
-        let s1_vol = {
-            if self.sc1.dac_enabled {
-                self.sc1.volume as f64 / 0xF as f64
-            } else {
-                0.0
-            }
-        };
+        let s1_vol = self.sc1.volume as f64 / 0xF as f64;
 
         let s1_duty = {
             match self.sc1.duty_cycle {
@@ -81,13 +157,7 @@ impl APU {
             }
         };
 
-        let s2_vol = {
-            if self.sc2.dac_enabled {
-                self.sc2.volume as f64 / 0xF as f64
-            } else {
-                0.0
-            }
-        };
+        let s2_vol = self.sc2.volume as f64 / 0xF as f64;
 
         let s2_duty = {
             match self.sc2.duty_cycle {
@@ -99,27 +169,20 @@ impl APU {
             }
         };
 
-        let s3_vol = {
-            if self.sc3.dac_enabled {
-                match self.sc3.output_level {
-                    OutputLevel::MUTE => 0.0,
-                    OutputLevel::QUARTER => 0.25,
-                    OutputLevel::HALF => 0.5,
-                    OutputLevel::MAX => 1.0,
-                    _ => 0.0
-                }
-            } else {
-                0.0
+        // A stopped wave channel holds still, so play nothing rather than a frozen level
+        let s3_vol = if self.channel_frequency_hz(3) == 0.0 {
+            0.0
+        } else {
+            match self.sc3.output_level {
+                OutputLevel::MUTE => 0.0,
+                OutputLevel::QUARTER => 0.25,
+                OutputLevel::HALF => 0.5,
+                OutputLevel::MAX => 1.0,
+                _ => 0.0
             }
         };
 
-        let s4_vol = {
-            if self.sc4.dac_enabled {
-                self.sc4.final_volume as f64 / 0xF as f64
-            } else {
-                0.0
-            }
-        };
+        let s4_vol = self.sc4.final_volume as f64 / 0xF as f64;
 
         // TODO: Amplifier on original hardware NEVER completely mutes non-silent input
         let global_l = {
@@ -138,35 +201,264 @@ impl APU {
             }
         };
 
-        self.synth.s1_freq.set_value(131072.0 / (2048.0 - self.sc1.period as f64));
+        self.synth.s1_freq.set_value(self.channel_frequency_hz(1));
         self.synth.s1_vol.set_value(s1_vol);
+        // A channel that's been switched off, by length or otherwise, is treated like its DAC
+        // being off so both go to the centre the same way
+        self.synth.s1_dac.set_value(if self.sc1.dac_enabled && self.is_ch_1_on { 1.0 } else { 0.0 });
         self.synth.s1_duty.set_value(s1_duty);
         self.synth.s1_l.set_value(if self.panning.contains(Panning::CH1_LEFT) { 1.0 } else { 0.0 });
         self.synth.s1_r.set_value(if self.panning.contains(Panning::CH1_RIGHT) { 1.0 } else { 0.0 });
 
-        self.synth.s2_freq.set_value(131072.0 / (2048.0 - self.sc2.period as f64));
+        self.synth.s2_freq.set_value(self.channel_frequency_hz(2));
         self.synth.s2_vol.set_value(s2_vol);
+        self.synth.s2_dac.set_value(if self.sc2.dac_enabled && self.is_ch_2_on { 1.0 } else { 0.0 });
         self.synth.s2_duty.set_value(s2_duty);
         self.synth.s2_l.set_value(if self.panning.contains(Panning::CH2_LEFT) { 1.0 } else { 0.0 });
         self.synth.s2_r.set_value(if self.panning.contains(Panning::CH2_RIGHT) { 1.0 } else { 0.0 });
 
-        self.synth.s3_freq.set_value(65536.0 / (2048.0 - self.sc3.period as f64));
+        self.synth.s3_freq.set_value(self.channel_frequency_hz(3));
         self.synth.s3_vol.set_value(s3_vol);
+        self.synth.s3_dac.set_value(if self.sc3.dac_enabled && self.is_ch_3_on { 1.0 } else { 0.0 });
         self.synth.s3_l.set_value(if self.panning.contains(Panning::CH3_LEFT) { 1.0 } else { 0.0 });
         self.synth.s3_r.set_value(if self.panning.contains(Panning::CH3_RIGHT) { 1.0 } else { 0.0 });
 
-        self.synth.s4_freq.set_value(self.sc4.frequency as f64);
+        self.synth.s4_freq.set_value(self.channel_frequency_hz(4));
         self.synth.s4_vol.set_value(s4_vol);
+        self.synth.s4_dac.set_value(if self.sc4.dac_enabled && self.is_ch_4_on { 1.0 } else { 0.0 });
         self.synth.s4_l.set_value(if self.panning.contains(Panning::CH4_LEFT) { 1.0 } else { 0.0 });
         self.synth.s4_r.set_value(if self.panning.contains(Panning::CH4_RIGHT) { 1.0 } else { 0.0 });
 
         self.synth.global_l.set_value(global_l);
         self.synth.global_r.set_value(global_r);
+
+        self.frame_samples += self.synth.render(cycles, self.clock.frequency()) as u32;
+    }
+
+    // Called on each falling edge of DIV bit 4 (bit 5 in double speed), so 512 Hz
+    // normally, but DIV writes can produce an extra clock
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.audio_enabled {
+            return;
+        }
+
+        // Length runs on even steps, sweep on steps 2 and 6, and envelopes on step 7
+        if self.frame_step & 0x01 == 0 {
+            if !self.sc1.clock_length() {
+                self.is_ch_1_on = false;
+            }
+            if !self.sc2.clock_length() {
+                self.is_ch_2_on = false;
+            }
+            if !self.sc3.clock_length() {
+                self.is_ch_3_on = false;
+            }
+            if !self.sc4.clock_length() {
+                self.is_ch_4_on = false;
+            }
+        }
+        if (self.frame_step == 2 || self.frame_step == 6) && !self.sc1.clock_sweep() {
+            self.is_ch_1_on = false;
+        }
+        if self.frame_step == 7 {
+            self.sc1.clock_envelope();
+            self.sc2.clock_envelope();
+            self.sc4.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    // Drops whatever hasn't been played yet and queues a fade to silence in its place,
+    // so the output decays through the DC block instead of stopping dead
+    pub fn pause(&mut self) {
+        self.synth.master.set_value(0.0);
+        self.synth.flush();
+        self.synth.render_frames((self.synth.sample_rate / 1000 * PAUSE_FADE_MS) as usize);
+    }
+
+    pub fn resume(&mut self) {
+        self.synth.master.set_value(1.0);
+    }
+
+    // Frames the audio device has consumed, for pacing emulation to the audio clock
+    pub fn samples_played(&self) -> u64 {
+        self.synth.samples_played.load(Ordering::Relaxed)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.synth.sample_rate
     }
 
+    // Stereo frames rendered but not yet played or drained
+    pub fn buffered_samples(&self) -> usize {
+        self.synth.buffered_samples()
+    }
+
+    // Bounds the rendered frames waiting for the audio device, the oldest are dropped
+    // when full, e.g. while running faster than real time
+    pub fn set_buffer_capacity(&mut self, capacity: usize) {
+        self.synth.set_buffer_capacity(capacity);
+    }
+
+    // Takes rendered stereo frames instead of the audio device, returns how many were copied
+    pub fn drain_samples(&mut self, out: &mut [(f64, f64)]) -> usize {
+        self.synth.drain(out)
+    }
+
+    // Called at each VBlank, once the instruction that reached it has been rendered, so the
+    // count is exactly what was rendered since the previous VBlank. VBlank is only seen between
+    // instructions, so counts wander a sample either side of samples_per_frame (803.65 at 48 kHz),
+    // but with a fixed rate and deterministic input they're the same on every run.
+    pub fn end_frame(&mut self) {
+        self.last_frame_samples = std::mem::take(&mut self.frame_samples);
+    }
+
+    pub fn last_frame_samples(&self) -> u32 {
+        self.last_frame_samples
+    }
+
+    // Average of last_frame_samples
+    pub fn samples_per_frame(&self) -> f64 {
+        FRAME_DOTS as f64 * self.synth.sample_rate as f64 / self.clock.frequency() as f64
+    }
+
+    // Frequency in Hz the mixer plays channel n at, the LFSR clock rate for channel 4.
+    // Pitch scales with the clock, 131072 Hz and 65536 Hz bases at the standard clock.
+    pub fn channel_frequency_hz(&self, n: u8) -> f64 {
+        let pulse_base = self.clock.frequency() as f64 / 32.0;
+        let wave_base = self.clock.frequency() as f64 / 64.0;
+
+        match n {
+            1 => Self::period_to_hz(pulse_base, self.sc1.period),
+            2 => Self::period_to_hz(pulse_base, self.sc2.period),
+            3 => Self::period_to_hz(wave_base, self.sc3.period),
+            4 => self.sc4.frequency as f64,
+            _ => panic!("Unknown APU channel ({})!", n),
+        }
+    }
+
+    // A period of 2048 or more has no valid divider, treat it as a stopped (silent) channel
+    fn period_to_hz(base: f64, period: u16) -> f64 {
+        let divider = 2048.0 - period as f64;
+        if divider <= 0.0 {
+            0.0
+        } else {
+            base / divider
+        }
+    }
+
+    pub fn channel_state(&self, n: u8) -> ChannelState {
+        match n {
+            1 => ChannelState {
+                enabled: self.is_ch_1_on,
+                dac_enabled: self.sc1.dac_enabled,
+                period: Some(self.sc1.period),
+                frequency: self.channel_frequency_hz(1),
+                volume: self.sc1.volume,
+                duty: Some(self.sc1.duty_cycle),
+                length: self.sc1.length_timer as u16,
+                length_enabled: self.read(0xFF14) & 0b0100_0000 != 0
+            },
+            2 => ChannelState {
+                enabled: self.is_ch_2_on,
+                dac_enabled: self.sc2.dac_enabled,
+                period: Some(self.sc2.period),
+                frequency: self.channel_frequency_hz(2),
+                volume: self.sc2.volume,
+                duty: Some(self.sc2.duty_cycle),
+                length: self.sc2.length_timer as u16,
+                length_enabled: self.read(0xFF19) & 0b0100_0000 != 0
+            },
+            3 => ChannelState {
+                enabled: self.is_ch_3_on,
+                dac_enabled: self.sc3.dac_enabled,
+                period: Some(self.sc3.period),
+                frequency: self.channel_frequency_hz(3),
+                volume: self.sc3.output_level.bits() >> 5,
+                duty: None,
+                length: self.sc3.length_timer,
+                length_enabled: self.read(0xFF1E) & 0b0100_0000 != 0
+            },
+            4 => ChannelState {
+                enabled: self.is_ch_4_on,
+                dac_enabled: self.sc4.dac_enabled,
+                period: None,
+                frequency: self.channel_frequency_hz(4),
+                volume: self.sc4.volume,
+                duty: None,
+                length: self.sc4.length_timer as u16,
+                length_enabled: self.read(0xFF23) & 0b0100_0000 != 0
+            },
+            _ => panic!("Unknown APU channel ({})!", n),
+        }
+    }
+
+    // Per-channel output for oscilloscope views, off by default
+    pub fn set_scope_taps(&mut self, enabled: bool) {
+        for tap in &self.synth.scope_taps {
+            tap.set_enabled(enabled);
+        }
+    }
+
+    // Copies channel `channel`'s (1-4) samples at the output rate, before panning and mixing,
+    // returns how many were copied
+    pub fn drain_channel_samples(&mut self, channel: u8, out: &mut [i16]) -> usize {
+        match channel {
+            1..=4 => self.synth.scope_taps[channel as usize - 1].drain(out),
+            n => panic!("Unknown APU channel ({})!", n),
+        }
+    }
+
+    // Off by default, which matches CGB
+    pub fn set_wave_ram_bug(&mut self, enabled: bool) {
+        self.sc3.wave_ram_bug_enabled = enabled;
+    }
+
+    // Applied to the final mix, after each channel's panning
+    pub fn set_stereo_mode(&mut self, mode: StereoMode) {
+        let width = match mode {
+            StereoMode::Stereo => 1.0,
+            StereoMode::Mono => 0.0,
+            StereoMode::NarrowedStereo(amount) => 1.0 - amount.clamp(0.0, 1.0) as f64,
+        };
+        self.synth.width.set_value(width);
+    }
+
+    pub fn master_state(&self) -> MasterState {
+        MasterState {
+            audio_enabled: self.audio_enabled,
+            left_volume: self.left_volume,
+            right_volume: self.right_volume,
+            panning: self.panning,
+            nr50: self.read(0xFF24),
+            nr51: self.read(0xFF25),
+            nr52: self.read(0xFF26)
+        }
+    }
+
+    // Rates derived from the Game Boy's own clock (e.g. the frame sequencer)
+    // take the same number of emulated cycles regardless of ClockSpeed
     pub fn hz_to_cycles(hz: u32) -> u32 {
-        let gameboy_freq = 4 * 1024 * 1024;
-        return gameboy_freq / hz;
+        return CPU_HZ / hz;
+    }
+}
+
+impl Hash for APU {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.audio_enabled.hash(state);
+        self.is_ch_4_on.hash(state);
+        self.is_ch_3_on.hash(state);
+        self.is_ch_2_on.hash(state);
+        self.is_ch_1_on.hash(state);
+        self.left_volume.hash(state);
+        self.right_volume.hash(state);
+        self.vin.hash(state);
+        self.panning.hash(state);
+        self.frame_step.hash(state);
+        self.sc1.hash(state);
+        self.sc2.hash(state);
+        self.sc3.hash(state);
+        self.sc4.hash(state);
     }
 }
 
@@ -182,7 +474,8 @@ impl Memory for APU {
             // NR51: Sound Panning
             0xFF25 => self.panning.bits(),
             // NR50: Master Volume & VIN
-            0xFF24 => (self.left_volume & 0b0000_0111) << 4 |
+            0xFF24 => self.vin |
+                      (self.left_volume & 0b0000_0111) << 4 |
                       (self.right_volume & 0b0000_0111),
             0xFF10..=0xFF14 => self.sc1.read(a),
             0xFF15..=0xFF19 => self.sc2.read(a),
@@ -200,7 +493,12 @@ impl Memory for APU {
             // NR52: Audio Master Control
             0xFF26 => {
                 set_apu_control = true;
-                self.audio_enabled = (v >> 7) == 0x01;
+                let enabled = (v >> 7) == 0x01;
+                // Powering on restarts the frame sequencer at step 0
+                if enabled && !self.audio_enabled {
+                    self.frame_step = 0;
+                }
+                self.audio_enabled = enabled;
             },
             // NR51: Sound Panning
             0xFF25 => {
@@ -211,10 +509,17 @@ impl Memory for APU {
             // NR50: Master Volume & VIN
             0xFF24 => {
                 if self.audio_enabled {
-                    self.left_volume = v >> 4;
+                    self.left_volume = (v >> 4) & 0b0000_0111;
                     self.right_volume = v & 0b0000_0111;
+                    self.vin = v & 0b1000_1000;
                 }
             },
+            // Length counters are powered separately on DMG, so they can still be
+            // loaded while the APU is off. CGB blocks these writes like the rest.
+            0xFF11 if !self.audio_enabled && self.mode == GBMode::Classic => self.sc1.write_length(v),
+            0xFF16 if !self.audio_enabled && self.mode == GBMode::Classic => self.sc2.write_length(v),
+            0xFF1B if !self.audio_enabled && self.mode == GBMode::Classic => self.sc3.write(a, v),
+            0xFF20 if !self.audio_enabled && self.mode == GBMode::Classic => self.sc4.write(a, v),
             0xFF10..=0xFF14 => {
                 if self.audio_enabled {
                     self.sc1.write(a, v)
@@ -242,33 +547,44 @@ impl Memory for APU {
 
         if self.sc1.trigger {
             self.sc1.trigger = false;
-            if self.sc1.dac_enabled {
-                self.is_ch_1_on = true;
-            }
+            self.is_ch_1_on = self.sc1.on_trigger();
+        }
+
+        if self.sc1.negate_lock {
+            self.sc1.negate_lock = false;
+            self.is_ch_1_on = false;
         }
 
         if self.sc2.trigger {
             self.sc2.trigger = false;
-            if self.sc2.dac_enabled {
+            if self.sc2.on_trigger() {
                 self.is_ch_2_on = true;
             }
         }
 
         if self.sc3.trigger {
             self.sc3.trigger = false;
-            if self.sc3.dac_enabled {
+            if self.is_ch_3_on {
+                self.sc3.maybe_corrupt_wave_ram();
+            }
+            if self.sc3.on_trigger() {
                 self.is_ch_3_on = true;
             }
         }
 
         if self.sc4.trigger {
             self.sc4.trigger = false;
-            self.sc4.lfsr = 0;
-            if self.sc4.dac_enabled {
+            if self.sc4.on_trigger() {
                 self.is_ch_4_on = true;
             }
         }
 
+        // Turning a DAC off also turns its channel off
+        self.is_ch_1_on &= self.sc1.dac_enabled;
+        self.is_ch_2_on &= self.sc2.dac_enabled;
+        self.is_ch_3_on &= self.sc3.dac_enabled;
+        self.is_ch_4_on &= self.sc4.dac_enabled;
+
         if set_apu_control {
             if !self.audio_enabled {
                 self.is_ch_1_on = false;
@@ -277,24 +593,498 @@ impl Memory for APU {
                 self.is_ch_4_on = false;
                 self.left_volume = 0;
                 self.right_volume = 0;
+                self.vin = 0;
 
                 self.panning = Panning::empty();
 
+                // Everything but wave RAM is cleared, except that DMG keeps its length
+                // counters, which stay writable while the APU is off
+                let lengths = (self.sc1.length_timer, self.sc2.length_timer, self.sc3.length_timer, self.sc4.length_timer);
                 self.sc1.clear();
                 self.sc2.clear();
                 self.sc3.clear();
                 self.sc4.clear();
+                if self.mode == GBMode::Classic {
+                    (self.sc1.length_timer, self.sc2.length_timer, self.sc3.length_timer, self.sc4.length_timer) = lengths;
+                }
             }
         }
     }
 }
 
 bitflags! {
-    #[derive(Copy, Clone, PartialEq, Eq)]
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
     pub struct DutyCycle: u8 {
         const EIGHTH = 0b0000_0000;
         const QUARTER = 0b0000_0001;
         const HALF = 0b0000_00010;
         const THREE_QUARTERS = 0b0000_0011;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::SGB_CPU_HZ;
+
+    fn apu(mode: GBMode) -> APU {
+        APU::new(mode, ClockSpeed::Standard, None, false)
+    }
+
+    #[test]
+    fn channel_state_reflects_register_writes() {
+        let mut apu = apu(GBMode::Classic);
+        apu.write(0xFF11, 0x90);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+
+        let state = apu.channel_state(1);
+        assert!(state.enabled);
+        assert!(state.dac_enabled);
+        assert_eq!(state.period, Some(0x700));
+        assert_eq!(state.frequency, 512.0);
+        assert_eq!(state.volume, 0x0F);
+        assert_eq!(state.duty, Some(DutyCycle::HALF));
+        assert_eq!(state.length, 0x10);
+        assert!(!state.length_enabled);
+
+        let state = apu.channel_state(2);
+        assert!(!state.enabled);
+        assert!(!state.dac_enabled);
+    }
+
+    #[test]
+    fn master_state_reflects_register_writes() {
+        let mut apu = apu(GBMode::Classic);
+        apu.write(0xFF24, 0x35);
+        apu.write(0xFF25, 0xF3);
+
+        let state = apu.master_state();
+        assert!(state.audio_enabled);
+        assert_eq!(state.left_volume, 3);
+        assert_eq!(state.right_volume, 5);
+        assert_eq!(state.panning.bits(), 0xF3);
+        assert_eq!(state.nr50, 0x35);
+        assert_eq!(state.nr51, 0xF3);
+        assert_eq!(state.nr52, 0xF0);
+    }
+
+    #[test]
+    fn sgb_clock_scales_pitch_and_frame_time() {
+        let ratio = SGB_CPU_HZ as f64 / CPU_HZ as f64;
+        let mut standard = apu(GBMode::Classic);
+        let mut sgb = APU::new(GBMode::Classic, ClockSpeed::SGB, None, false);
+        for apu in [&mut standard, &mut sgb] {
+            apu.write(0xFF26, 0x80);
+            apu.write(0xFF13, 0x00);
+            apu.write(0xFF14, 0x07);
+        }
+
+        // The same number of emulated cycles, played back faster
+        assert_eq!(APU::hz_to_cycles(512), CPU_HZ / 512);
+        assert!((sgb.channel_frequency_hz(1) / standard.channel_frequency_hz(1) - ratio).abs() < 1e-9);
+        assert!((standard.samples_per_frame() / sgb.samples_per_frame() - ratio).abs() < 1e-9);
+        let steps = ClockSpeed::SGB.step_cycles() as f64 / ClockSpeed::Standard.step_cycles() as f64;
+        assert!((steps - ratio).abs() < 1e-4);
+    }
+
+    #[test]
+    fn clearing_nrx2_turns_the_dac_and_channel_off() {
+        let mut apu = apu(GBMode::Classic);
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x80);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x01);
+
+        apu.write(0xFF12, 0x00);
+        assert!(!apu.channel_state(1).dac_enabled);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn retriggering_restarts_the_envelope() {
+        let mut apu = apu(GBMode::Classic);
+        apu.write(0xFF26, 0x80);
+        // Volume 15, decreasing every envelope clock
+        apu.write(0xFF12, 0xF1);
+        apu.write(0xFF14, 0x80);
+        for _ in 0..16 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.channel_state(1).volume, 13);
+
+        apu.write(0xFF14, 0x80);
+        assert_eq!(apu.channel_state(1).volume, 15);
+        assert!(apu.channel_state(1).enabled);
+    }
+
+    #[test]
+    fn channel_frequencies_follow_the_period_registers() {
+        let mut apu = apu(GBMode::Classic);
+        // Period $700 is 512 Hz on the pulse channels and 256 Hz on the wave channel
+        for (lo, hi) in [(0xFF13, 0xFF14), (0xFF18, 0xFF19), (0xFF1D, 0xFF1E)] {
+            apu.write(lo, 0x00);
+            apu.write(hi, 0x07);
+        }
+        apu.write(0xFF22, 0x01);
+
+        assert_eq!(apu.channel_frequency_hz(1), 512.0);
+        assert_eq!(apu.channel_frequency_hz(2), 512.0);
+        assert_eq!(apu.channel_frequency_hz(3), 256.0);
+        assert_eq!(apu.channel_frequency_hz(4), 262144.0);
+
+        // The highest period, 131072 Hz on a pulse channel
+        apu.write(0xFF13, 0xFF);
+        assert_eq!(apu.channel_frequency_hz(1), 131072.0);
+    }
+
+    #[test]
+    fn period_2048_gives_a_silent_channel() {
+        assert_eq!(APU::period_to_hz(131072.0, 2048), 0.0);
+        assert_eq!(APU::period_to_hz(131072.0, 2100), 0.0);
+        assert_eq!(APU::period_to_hz(131072.0, 2047), 131072.0);
+
+        let mut apu = apu(GBMode::Classic);
+        apu.sc1.period = 2048;
+        apu.sc3.period = 2048;
+        assert_eq!(apu.channel_frequency_hz(1), 0.0);
+        assert_eq!(apu.channel_frequency_hz(3), 0.0);
+        assert!(apu.channel_state(1).frequency.is_finite());
+    }
+
+    // Triggers channel 1 at `period` with a sweep pace of 1, adding and a shift of 1
+    fn trigger_sweep(apu: &mut APU, period: u16) {
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, period as u8);
+        apu.write(0xFF14, 0x80 | (period >> 8) as u8);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_the_channel() {
+        let mut apu = apu(GBMode::Classic);
+        // $700 + ($700 >> 1) is past $7FF before the channel ever sounds
+        trigger_sweep(&mut apu, 0x700);
+        assert!(!apu.channel_state(1).enabled);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+
+        // $400 + $200 fits, but the check on the new period overflows on the first sweep
+        trigger_sweep(&mut apu, 0x400);
+        assert!(apu.channel_state(1).enabled);
+        assert!(!apu.sc1.clock_sweep());
+        assert_eq!(apu.sc1.period, 0x600);
+    }
+
+    #[test]
+    fn length_expiry_ramps_the_dac_to_the_centre() {
+        let mut apu = apu(GBMode::Classic);
+        apu.set_scope_taps(true);
+        // Channel 1 at 512 Hz with one length clock left
+        apu.write(0xFF11, 0xBF);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0xC7);
+        apu.cycle(CPU_HZ / 20);
+
+        let mut samples = [0; 4096];
+        apu.drain_channel_samples(1, &mut samples);
+        // The frame sequencer starts on a length step
+        apu.clock_frame_sequencer();
+        let state = apu.channel_state(1);
+        assert!(!state.enabled);
+        assert!(state.dac_enabled);
+        assert_eq!(state.volume, 0x0F);
+
+        apu.cycle(CPU_HZ / 20);
+        let n = apu.drain_channel_samples(1, &mut samples);
+        assert_eq!(apu.synth.s1_dac.value(), 0.0);
+        // Still near full level straight after expiry, then settles on the centre
+        // rather than the bottom of the range
+        assert!(samples[..10].iter().any(|s| s.unsigned_abs() > i16::MAX as u16 / 4));
+        assert!(samples[n - 1].unsigned_abs() < i16::MAX as u16 / 100);
+    }
+
+    #[test]
+    fn sample_buffer_stays_within_its_capacity() {
+        let mut apu = apu(GBMode::Classic);
+        apu.set_buffer_capacity(1024);
+        // A second of emulation is 48000 frames, far more than is ever drained
+        for _ in 0..1000 {
+            apu.cycle(CPU_HZ / 1000);
+            assert!(apu.buffered_samples() <= 1024);
+        }
+        assert_eq!(apu.buffered_samples(), 1024);
+
+        let mut out = [(0.0, 0.0); 100];
+        assert_eq!(apu.drain_samples(&mut out), 100);
+        assert_eq!(apu.buffered_samples(), 924);
+
+        // Shrinking drops the oldest frames straight away
+        apu.set_buffer_capacity(512);
+        assert_eq!(apu.buffered_samples(), 512);
+        apu.cycle(CPU_HZ / 10);
+        assert_eq!(apu.buffered_samples(), 512);
+    }
+
+    #[test]
+    fn length_writes_with_the_apu_off_depend_on_the_model() {
+        for (mode, length) in [(GBMode::Classic, 0x2A), (GBMode::Color, 0x00)] {
+            let mut apu = apu(mode);
+            apu.write(0xFF26, 0x00);
+            let duty = apu.channel_state(1).duty;
+            apu.write(0xFF11, 0xEA);
+            apu.write(0xFF20, 0x2A);
+            assert_eq!(apu.channel_state(1).length, length);
+            assert_eq!(apu.channel_state(4).length, length);
+            // Duty isn't part of the length counter, so it stays blocked either way
+            assert_eq!(apu.channel_state(1).duty, duty);
+        }
+    }
+
+    #[test]
+    fn only_lengths_and_wave_ram_are_writable_while_off() {
+        let mut classic = apu(GBMode::Classic);
+        classic.write(0xFF26, 0x00);
+        let cleared: Vec<u8> = (0xFF10..=0xFF25).map(|a| classic.read(a)).collect();
+
+        // Everything but NR52 and wave RAM, all bits set
+        for a in 0xFF10..=0xFF25 {
+            classic.write(a, 0xFF);
+        }
+        classic.write(0xFF30, 0x12);
+
+        // Only the length bits of NRx1 took effect, and those aren't readable
+        assert_eq!((0xFF10..=0xFF25).map(|a| classic.read(a)).collect::<Vec<_>>(), cleared);
+        for n in 1..=4 {
+            assert_eq!(classic.channel_state(n).length, if n == 3 { 0xFF } else { 0x3F });
+        }
+        assert_eq!(classic.read(0xFF30), 0x12);
+
+        // Powering off again keeps DMG's length counters
+        classic.write(0xFF26, 0x80);
+        classic.write(0xFF26, 0x00);
+        assert_eq!(classic.channel_state(1).length, 0x3F);
+
+        let mut color = apu(GBMode::Color);
+        color.write(0xFF11, 0x3F);
+        color.write(0xFF26, 0x00);
+        assert_eq!(color.channel_state(1).length, 0x00);
+    }
+
+    // Channel 1 at 512 Hz, only routed to the left terminal
+    fn hard_left_samples(mode: StereoMode) -> Vec<(f64, f64)> {
+        let mut apu = apu(GBMode::Classic);
+        apu.set_stereo_mode(mode);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0x10);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+
+        apu.cycle(CPU_HZ / 20);
+        let mut out = vec![(0.0, 0.0); 4096];
+        let n = apu.drain_samples(&mut out);
+        out.truncate(n);
+        out
+    }
+
+    #[test]
+    fn mono_folds_a_hard_left_channel_into_both_sides() {
+        let stereo = hard_left_samples(StereoMode::Stereo);
+        assert!(stereo.iter().any(|&(l, _)| l.abs() > 0.01));
+        assert!(stereo.iter().all(|&(_, r)| r.abs() < 1e-9));
+
+        let mono = hard_left_samples(StereoMode::Mono);
+        assert!(mono.iter().any(|&(l, _)| l.abs() > 0.01));
+        assert!(mono.iter().all(|&(l, r)| (l - r).abs() < 1e-9));
+    }
+
+    #[test]
+    fn narrowed_stereo_sits_between_stereo_and_mono() {
+        let energy = |samples: &[(f64, f64)]| {
+            samples.iter().fold((0.0, 0.0), |(l, r), &(sl, sr)| (l + sl * sl, r + sr * sr))
+        };
+        let (stereo_l, stereo_r) = energy(&hard_left_samples(StereoMode::Stereo));
+        let (mono_l, mono_r) = energy(&hard_left_samples(StereoMode::Mono));
+        let (narrow_l, narrow_r) = energy(&hard_left_samples(StereoMode::NarrowedStereo(0.5)));
+
+        // Some of the left side bleeds into the right, but less than in mono
+        assert!(narrow_l < stereo_l && narrow_l > mono_l);
+        assert!(narrow_r > stereo_r && narrow_r < mono_r);
+        assert!(narrow_r < narrow_l);
+    }
+
+    #[test]
+    fn registers_read_back_through_their_masks() {
+        // Unused and write-only bits read as 1
+        const MASKS: [u8; 0x17] = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF,
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+            0xFF, 0xFF, 0x00, 0x00, 0xBF,
+            0x00, 0x00, 0x70
+        ];
+        let mut apu = apu(GBMode::Classic);
+        apu.write(0xFF26, 0x80);
+        for a in 0xFF10..0xFF26 {
+            apu.write(a, 0x00);
+        }
+        for (i, &mask) in MASKS.iter().enumerate() {
+            let a = 0xFF10 + i as u16;
+            let expected = if a == 0xFF26 { mask | 0x80 } else { mask };
+            assert_eq!(apu.read(a), expected, "{:#06x}", a);
+        }
+        for a in 0xFF27..0xFF30 {
+            assert_eq!(apu.read(a), 0xFF, "{:#06x}", a);
+        }
+
+        // Readable bits come back as written, without triggering any channel
+        let written: [(u16, u8, u8); 13] = [
+            (0xFF10, 0x7F, 0xFF), (0xFF11, 0xC0, 0xFF), (0xFF12, 0xF7, 0xF7), (0xFF14, 0x40, 0xFF),
+            (0xFF16, 0x80, 0xBF), (0xFF17, 0x08, 0x08), (0xFF19, 0x40, 0xFF),
+            (0xFF1A, 0x80, 0xFF), (0xFF1C, 0x60, 0xFF), (0xFF1E, 0x40, 0xFF),
+            (0xFF21, 0x08, 0x08), (0xFF22, 0xFF, 0xFF), (0xFF24, 0xFF, 0xFF),
+        ];
+        for (a, v, expected) in written {
+            apu.write(a, v);
+            assert_eq!(apu.read(a), expected, "{:#06x}", a);
+        }
+        apu.write(0xFF25, 0xA5);
+        assert_eq!(apu.read(0xFF25), 0xA5);
+        assert_eq!(apu.read(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn leaving_negate_after_a_subtraction_disables_channel_1() {
+        let mut apu = apu(GBMode::Classic);
+        // Negate with a nonzero step subtracts straight away on trigger
+        trigger_sweep(&mut apu, 0x400);
+        apu.write(0xFF10, 0x19);
+        apu.write(0xFF14, 0x84);
+        assert!(apu.channel_state(1).enabled);
+        apu.write(0xFF10, 0x11);
+        assert!(!apu.channel_state(1).enabled);
+        assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+
+        // Without a shift nothing is calculated on trigger, so there's nothing to lock
+        apu.write(0xFF10, 0x18);
+        apu.write(0xFF14, 0x84);
+        apu.write(0xFF10, 0x10);
+        assert!(apu.channel_state(1).enabled);
+    }
+
+    #[test]
+    fn scope_taps_capture_each_channel_before_mixing() {
+        let mut apu = apu(GBMode::Classic);
+        // Channel 1 at 512 Hz, 50% duty, panned nowhere so only the tap hears it
+        apu.write(0xFF25, 0x00);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+
+        let mut samples = [0; 4096];
+        apu.cycle(CPU_HZ / 20);
+        assert_eq!(apu.drain_channel_samples(1, &mut samples), 0);
+
+        apu.set_scope_taps(true);
+        apu.cycle(CPU_HZ / 20);
+        let n = apu.drain_channel_samples(1, &mut samples);
+        assert!(n > 1000);
+        let mut silent = [1; 4096];
+        assert_eq!(apu.drain_channel_samples(2, &mut silent), n);
+        assert!(silent[..n].iter().all(|&s| s == 0));
+
+        // A square wave sits at one of two levels either side of the centre, half the time
+        // at each, with band-limited edges between them
+        let wave = &samples[..n];
+        let settled = wave.iter().filter(|&&s| s.unsigned_abs() > i16::MAX as u16 / 4).count();
+        assert!(settled > n * 9 / 10, "{} of {}", settled, n);
+        let high = wave.iter().filter(|&&s| s > 0).count();
+        assert!(high.abs_diff(n / 2) < n / 20, "{} of {}", high, n);
+        let edges = wave.windows(2).filter(|w| (w[0] > 0) != (w[1] > 0)).count();
+        // 512 Hz over a 20th of a second, two edges a cycle
+        assert!(edges.abs_diff(2 * 512 / 20) <= 2, "{} edges", edges);
+
+        // Channels 3 and 4 are tapped too, even though they aren't in the mix
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1C, 0x20);
+        apu.write(0xFF1E, 0x87);
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF22, 0x40);
+        apu.write(0xFF23, 0x80);
+        apu.cycle(CPU_HZ / 20);
+        for channel in [3, 4] {
+            let mut wave = [0; 4096];
+            let n = apu.drain_channel_samples(channel, &mut wave);
+            assert!(n > 1000, "channel {}", channel);
+            assert!(wave[..n].iter().any(|s| s.unsigned_abs() > i16::MAX as u16 / 4), "channel {}", channel);
+        }
+    }
+
+    #[test]
+    fn wave_ram_powers_on_with_the_model_pattern() {
+        let wave_ram = |apu: &APU| (0xFF30..0xFF40).map(|a| apu.read(a)).collect::<Vec<_>>();
+        let classic = apu(GBMode::Classic);
+        assert_eq!(wave_ram(&classic), [0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA]);
+        let color = apu(GBMode::Color);
+        assert_eq!(wave_ram(&color), [0x00, 0xFF].repeat(8));
+    }
+
+    #[test]
+    fn every_channel_stops_on_the_length_clock_that_reaches_the_limit() {
+        let mut apu = apu(GBMode::Classic);
+        // DAC on, one length clock left, then triggered with length enabled
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x3F);
+        apu.write(0xFF14, 0xC0);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x3F);
+        apu.write(0xFF19, 0xC0);
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1B, 0xFF);
+        apu.write(0xFF1E, 0xC0);
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF20, 0x3F);
+        apu.write(0xFF23, 0xC0);
+        for n in 1..=4 {
+            assert!(apu.channel_state(n).enabled, "channel {}", n);
+        }
+
+        apu.clock_frame_sequencer();
+        for n in 1..=4 {
+            assert!(!apu.channel_state(n).enabled, "channel {}", n);
+        }
+
+        // Retriggering reloads the full length
+        apu.write(0xFF1E, 0xC0);
+        assert_eq!(apu.channel_state(3).length, 0);
+        assert!(apu.channel_state(3).enabled);
+    }
+
+    #[test]
+    fn retriggering_channel_3_mid_read_corrupts_wave_ram_with_the_bug() {
+        for bug in [false, true] {
+            let mut apu = apu(GBMode::Classic);
+            apu.set_wave_ram_bug(bug);
+            for i in 0..16 {
+                apu.write(0xFF30 + i, i as u8 * 0x11);
+            }
+            // Fastest period, a sample every 2 cycles
+            apu.write(0xFF1A, 0x80);
+            apu.write(0xFF1D, 0xFF);
+            apu.write(0xFF1E, 0x87);
+            // Just about to read byte 5
+            apu.cycle(18);
+            apu.write(0xFF1E, 0x87);
+
+            apu.write(0xFF1A, 0x00);
+            let start = (0xFF30..0xFF34).map(|a| apu.read(a)).collect::<Vec<_>>();
+            if bug {
+                assert_eq!(start, [0x44, 0x55, 0x66, 0x77]);
+            } else {
+                assert_eq!(start, [0x00, 0x11, 0x22, 0x33]);
+            }
+        }
+    }
+}
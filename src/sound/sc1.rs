@@ -1,6 +1,7 @@
 use crate::memory::Memory;
-use crate::sound::apu::{APU, DutyCycle};
+use crate::sound::apu::DutyCycle;
 
+#[derive(Hash)]
 pub struct SC1 {
     pub dac_enabled: bool,
     sweep_pace: u8,
@@ -9,13 +10,22 @@ pub struct SC1 {
     pub duty_cycle: DutyCycle,
     pub length_timer: u8,
     pub volume: u8,
+    // Volume reloaded on trigger, the envelope then moves `volume` away from it
+    initial_volume: u8,
     positive_envelope: bool,
     envelope_pace: u8,
+    envelope_timer: u8,
     pub period: u16,
     pub trigger: bool,
     length_enabled: bool,
-    length_cycle_count: u32,
-    sweep_cycle_count: u32
+    // Sweep works on its own copy of the period, only written back when it steps
+    shadow_period: u16,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    // Set once a subtraction has been calculated since the last trigger
+    negate_used: bool,
+    // Raised when clearing the negate bit disables the channel, picked up by the APU like trigger
+    pub negate_lock: bool
 }
 
 impl SC1 {
@@ -28,13 +38,18 @@ impl SC1 {
             duty_cycle: DutyCycle::QUARTER,
             length_timer: 0,
             volume: 0,
+            initial_volume: 0,
             positive_envelope: false,
             envelope_pace: 0,
+            envelope_timer: 0,
             period: 0,
             trigger: false,
             length_enabled: false,
-            length_cycle_count: 0,
-            sweep_cycle_count: 0
+            shadow_period: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            negate_used: false,
+            negate_lock: false
         }
     }
 
@@ -46,54 +61,123 @@ impl SC1 {
         self.duty_cycle = DutyCycle::QUARTER;
         self.length_timer = 0;
         self.volume = 0;
+        self.initial_volume = 0;
         self.positive_envelope = false;
         self.envelope_pace = 0;
+        self.envelope_timer = 0;
         self.period = 0;
         self.trigger = false;
         self.length_enabled = false;
+        self.shadow_period = 0;
+        self.sweep_timer = 0;
+        self.sweep_enabled = false;
+        self.negate_used = false;
+        self.negate_lock = false;
     }
 
-    pub fn cycle(&mut self, cycles: u32) {
-        if self.length_enabled {
-            self.length_cycle_count += cycles;
+    // Clocked at 256 Hz by the frame sequencer, returns false once the length expires.
+    // The DAC stays on, the channel just stops feeding it.
+    pub fn clock_length(&mut self) -> bool {
+        if self.length_enabled && self.length_timer < 64 {
+            self.length_timer += 1;
+            return self.length_timer < 64;
+        }
+        true
+    }
 
-            if self.length_cycle_count >= APU::hz_to_cycles(256) {
-                self.length_cycle_count = 0;
+    // Reloads the channel's counters from its registers, returns whether the channel turns on.
+    // The pulse phase lives in the synth oscillator, which keeps running across triggers.
+    pub fn on_trigger(&mut self) -> bool {
+        if self.length_timer >= 64 {
+            self.length_timer = 0;
+        }
+        self.volume = self.initial_volume;
+        self.envelope_timer = self.envelope_pace;
 
-                if self.length_timer >= 64 {
-                    println!("NOTE OVER");
-                    self.dac_enabled = false;
-                    self.length_enabled = false;
-                } else {
-                    self.length_timer += 1;
-                }
+        self.shadow_period = self.period;
+        self.sweep_timer = if self.sweep_pace == 0 { 8 } else { self.sweep_pace };
+        self.sweep_enabled = self.sweep_pace != 0 || self.sweep_step != 0;
+        self.negate_used = false;
+        // With a nonzero step the overflow check runs straight away, so the channel
+        // can be disabled before it ever sounds
+        if self.sweep_step != 0 && self.calculate_sweep() > 0x7FF {
+            return false;
+        }
+
+        self.dac_enabled
+    }
+
+    fn calculate_sweep(&mut self) -> u16 {
+        if self.negative_direction {
+            self.negate_used = true;
+        }
+        self.sweep_target()
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let step = self.shadow_period >> self.sweep_step;
+        if self.negative_direction {
+            self.shadow_period.wrapping_sub(step)
+        } else {
+            self.shadow_period + step
+        }
+    }
+
+    // Clocked at 128 Hz by the frame sequencer, returns false if the period overflowed
+    // and the channel was disabled
+    pub fn clock_sweep(&mut self) -> bool {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer != 0 {
+            return true;
+        }
+
+        self.sweep_timer = if self.sweep_pace == 0 { 8 } else { self.sweep_pace };
+        if !self.sweep_enabled || self.sweep_pace == 0 {
+            return true;
+        }
+
+        let target = self.calculate_sweep();
+        if target > 0x7FF {
+            return false;
+        }
+
+        if self.sweep_step != 0 {
+            self.shadow_period = target;
+            self.period = target;
+
+            // The new period is checked again, but not written back
+            if self.calculate_sweep() > 0x7FF {
+                return false;
             }
         }
 
-        // if self.sweep_pace != 0 {
-        //     self.sweep_cycle_count += cycles;
-        //
-        //     if self.sweep_cycle_count >= (APU::hz_to_cycles(128) * self.sweep_pace as u32) {
-        //         self.sweep_cycle_count = 0;
-        //
-        //         let divisor = 2 ^ (self.sweep_step as u16);
-        //         if divisor != 0 {
-        //             let step = self.period / divisor;
-        //             if self.negative_direction {
-        //                 self.period -= step;
-        //             } else {
-        //                 let (value, overflow) = self.period.overflowing_add(step);
-        //
-        //                 if value > 0x7FF || overflow {
-        //                     self.dac_enabled = false;
-        //                     self.clear();
-        //                 } else {
-        //                     self.period = value;
-        //                 }
-        //             }
-        //         }
-        //     }
-        // }
+        true
+    }
+
+    // Clocked at 64 Hz by the frame sequencer, a pace of 0 disables the envelope
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_pace == 0 {
+            return;
+        }
+
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+        if self.envelope_timer == 0 {
+            self.envelope_timer = self.envelope_pace;
+            if self.positive_envelope && self.volume < 0x0F {
+                self.volume += 1;
+            } else if !self.positive_envelope && self.volume > 0x00 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    // The length half of NR11, which DMG lets through even while the APU is off
+    pub fn write_length(&mut self, v: u8) {
+        self.length_timer = v & 0b0011_1111;
+    }
+
+    pub fn cycle(&mut self, _cycles: u32) {
+
     }
 }
 
@@ -105,7 +189,7 @@ impl Memory for SC1 {
             // NR11: Length Timer & Duty Cycle
             0xFF11 => (self.duty_cycle.bits()) << 6 | 0x3F,
             // NR12: Volume & Envelope
-            0xFF12 => (self.volume & 0b0000_1111) << 4 | (self.positive_envelope as u8) << 3 | (self.envelope_pace & 0b0000_0111),
+            0xFF12 => (self.initial_volume & 0b0000_1111) << 4 | (self.positive_envelope as u8) << 3 | (self.envelope_pace & 0b0000_0111),
             // NR13: Period Low
             0xFF13 => 0xFF,
             // NR14: Period High & Control
@@ -118,24 +202,29 @@ impl Memory for SC1 {
         match a {
             // NR10: Sweep
             0xFF10 => {
+                let negative_direction = ((v & 0b0000_1000) >> 3) != 0;
+                // Leaving negate mode after a subtraction has been used disables the channel
+                if self.negative_direction && !negative_direction && self.negate_used {
+                    self.negate_lock = true;
+                }
+
                 self.sweep_pace = (v & 0b0111_0000) >> 4;
-                self.negative_direction = ((v & 0b0000_1000) >> 3) != 0;
+                self.negative_direction = negative_direction;
                 self.sweep_step = v & 0b0000_0111;
             },
             // NR11: Length Timer & Duty Cycle
             0xFF11 => {
                 self.duty_cycle = DutyCycle::from_bits_truncate(v >> 6);
-                self.length_timer = v & 0b0011_1111;
+                self.write_length(v);
             },
             // NR12: Volume & Envelope
             0xFF12 => {
-                self.volume = (v & 0b1111_0000) >> 4;
+                self.initial_volume = (v & 0b1111_0000) >> 4;
                 self.positive_envelope = ((v & 0b0000_1000) >> 3) != 0;
                 self.envelope_pace = v & 0b0000_0111;
 
-                if self.read(0xFF12) & 0xF8 != 0 {
-                    self.dac_enabled = true;
-                }
+                // The DAC is only on while the upper 5 bits are set
+                self.dac_enabled = self.read(0xFF12) & 0xF8 != 0;
             },
             // NR13: Period Low
             0xFF13 => {
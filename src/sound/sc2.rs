@@ -1,17 +1,20 @@
 use crate::memory::Memory;
 use crate::sound::apu::DutyCycle;
 
+#[derive(Hash)]
 pub struct SC2 {
     pub dac_enabled: bool,
     pub duty_cycle: DutyCycle,
-    length_timer: u8,
+    pub length_timer: u8,
     pub volume: u8,
+    // Volume reloaded on trigger, the envelope then moves `volume` away from it
+    initial_volume: u8,
     positive_envelope: bool,
     envelope_pace: u8,
+    envelope_timer: u8,
     pub period: u16,
     pub trigger: bool,
-    length_enabled: bool,
-    length_cycle_count: u32
+    length_enabled: bool
 }
 
 impl SC2 {
@@ -21,12 +24,13 @@ impl SC2 {
             duty_cycle: DutyCycle::QUARTER,
             length_timer: 0,
             volume: 0,
+            initial_volume: 0,
             positive_envelope: false,
             envelope_pace: 0,
+            envelope_timer: 0,
             period: 0,
             trigger: false,
-            length_enabled: false,
-            length_cycle_count: 0
+            length_enabled: false
         }
     }
 
@@ -35,13 +39,57 @@ impl SC2 {
         self.duty_cycle = DutyCycle::QUARTER;
         self.length_timer = 0;
         self.volume = 0;
+        self.initial_volume = 0;
         self.positive_envelope = false;
         self.envelope_pace = 0;
+        self.envelope_timer = 0;
         self.period = 0;
         self.trigger = false;
         self.length_enabled = false;
     }
 
+    // Clocked at 256 Hz by the frame sequencer, returns false once the length expires
+    pub fn clock_length(&mut self) -> bool {
+        if self.length_enabled && self.length_timer < 64 {
+            self.length_timer += 1;
+            return self.length_timer < 64;
+        }
+        true
+    }
+
+    // Reloads the channel's counters from its registers, returns whether the channel turns on.
+    // The pulse phase lives in the synth oscillator, which keeps running across triggers.
+    pub fn on_trigger(&mut self) -> bool {
+        if self.length_timer >= 64 {
+            self.length_timer = 0;
+        }
+        self.volume = self.initial_volume;
+        self.envelope_timer = self.envelope_pace;
+        self.dac_enabled
+    }
+
+    // Clocked at 64 Hz by the frame sequencer, a pace of 0 disables the envelope
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_pace == 0 {
+            return;
+        }
+
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+        if self.envelope_timer == 0 {
+            self.envelope_timer = self.envelope_pace;
+            if self.positive_envelope && self.volume < 0x0F {
+                self.volume += 1;
+            } else if !self.positive_envelope && self.volume > 0x00 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    // The length half of NR21, which DMG lets through even while the APU is off
+    pub fn write_length(&mut self, v: u8) {
+        self.length_timer = v & 0b0011_1111;
+    }
+
     pub fn cycle(&mut self, cycles: u32) {
 
     }
@@ -53,7 +101,7 @@ impl Memory for SC2 {
             // NR21: Length Timer & Duty Cycle
             0xFF16 => (self.duty_cycle.bits()) << 6 | 0x3F,
             // NR22: Volume & Envelope
-            0xFF17 => (self.volume & 0b0000_1111) << 4 | (self.positive_envelope as u8) << 3 | (self.envelope_pace & 0b0000_0111),
+            0xFF17 => (self.initial_volume & 0b0000_1111) << 4 | (self.positive_envelope as u8) << 3 | (self.envelope_pace & 0b0000_0111),
             // NR23: Period Low
             0xFF18 => 0xFF,
             // NR24: Period High & Control
@@ -67,17 +115,16 @@ impl Memory for SC2 {
             // NR21: Length Timer & Duty Cycle
             0xFF16 => {
                 self.duty_cycle = DutyCycle::from_bits_truncate(v >> 6);
-                self.length_timer = v & 0b0011_1111;
+                self.write_length(v);
             },
             // NR22: Volume & Envelope
             0xFF17 => {
-                self.volume = (v & 0b1111_0000) >> 4;
+                self.initial_volume = (v & 0b1111_0000) >> 4;
                 self.positive_envelope = ((v & 0b0000_1000) >> 3) != 0;
                 self.envelope_pace = v & 0b0000_0111;
 
-                if self.read(0xFF17) & 0xF8 != 0 {
-                    self.dac_enabled = true;
-                }
+                // The DAC is only on while the upper 5 bits are set
+                self.dac_enabled = self.read(0xFF17) & 0xF8 != 0;
             },
             // NR23: Period Low
             0xFF18 => {
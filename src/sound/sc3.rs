@@ -1,18 +1,34 @@
 use bitflags::bitflags;
 use crate::memory::Memory;
+use crate::mode::GBMode;
 
+// Cycles before a sample step in which the channel is reading wave RAM
+const WAVE_READ_WINDOW: u32 = 2;
+
+// Wave RAM isn't cleared at power-on. DMG units come up with a pattern that varies from unit
+// to unit, this is a typical one. CGB units reliably alternate $00 and $FF.
+const DMG_WAVE_RAM: [u8; 16] = [0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA];
+const CGB_WAVE_RAM: [u8; 16] = [0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF];
+
+#[derive(Hash)]
 pub struct SC3 {
     pub dac_enabled: bool,
-    length_timer: u8,
+    // Counts up to 256, so it doesn't fit NR31's byte
+    pub length_timer: u16,
     pub output_level: OutputLevel,
     pub period: u16,
     pub trigger: bool,
     length_enabled: bool,
-    wave_ram: [u8; 16]
+    wave_ram: [u8; 16],
+    // Sample (nibble) being played, and cycles until the next one
+    position: u8,
+    period_timer: u32,
+    // DMG corrupts wave RAM when retriggered mid-read, CGB doesn't
+    pub wave_ram_bug_enabled: bool
 }
 
 bitflags! {
-    #[derive(Copy, Clone, PartialEq, Eq)]
+    #[derive(Copy, Clone, PartialEq, Eq, Hash)]
     pub struct OutputLevel: u8 {
         const MUTE = 0b0000_0000;
         const MAX = 0b0010_0000;
@@ -22,7 +38,7 @@ bitflags! {
 }
 
 impl SC3 {
-    pub fn new() -> Self {
+    pub fn new(mode: GBMode) -> Self {
         Self {
             dac_enabled: false,
             length_timer: 0,
@@ -30,7 +46,53 @@ impl SC3 {
             period: 0,
             trigger: false,
             length_enabled: false,
-            wave_ram: [0; 16]
+            wave_ram: match mode {
+                GBMode::Classic => DMG_WAVE_RAM,
+                GBMode::Color => CGB_WAVE_RAM,
+            },
+            position: 0,
+            period_timer: 0,
+            wave_ram_bug_enabled: false
+        }
+    }
+
+    // Clocked at 256 Hz by the frame sequencer, returns false once the length expires
+    pub fn clock_length(&mut self) -> bool {
+        if self.length_enabled && self.length_timer < 256 {
+            self.length_timer += 1;
+            return self.length_timer < 256;
+        }
+        true
+    }
+
+    // Returns whether the channel turns on
+    pub fn on_trigger(&mut self) -> bool {
+        if self.length_timer >= 256 {
+            self.length_timer = 0;
+        }
+        self.position = 0;
+        self.period_timer = self.period_cycles();
+        self.dac_enabled
+    }
+
+    fn period_cycles(&self) -> u32 {
+        (2048 - self.period as u32) * 2
+    }
+
+    // Called when the channel is retriggered while playing. If that lands just as it reads
+    // a sample, DMG copies the byte being read over the start of wave RAM: on its own if it's
+    // one of the first 4, otherwise along with the rest of its 4-byte aligned block.
+    pub fn maybe_corrupt_wave_ram(&mut self) {
+        if !self.wave_ram_bug_enabled || !self.dac_enabled || self.period_timer > WAVE_READ_WINDOW {
+            return;
+        }
+
+        let byte = (((self.position + 1) % 32) / 2) as usize;
+        if byte < 4 {
+            self.wave_ram[0] = self.wave_ram[byte];
+        } else {
+            let block = byte & !0b11;
+            self.wave_ram.copy_within(block..block + 4, 0);
         }
     }
 
@@ -41,10 +103,18 @@ impl SC3 {
         self.period = 0;
         self.trigger = false;
         self.length_enabled = false;
+        self.position = 0;
+        self.period_timer = 0;
     }
 
     pub fn cycle(&mut self, cycles: u32) {
-
+        let mut cycles = cycles;
+        while cycles >= self.period_timer {
+            cycles -= self.period_timer;
+            self.period_timer = self.period_cycles();
+            self.position = (self.position + 1) % 32;
+        }
+        self.period_timer -= cycles;
     }
 }
 
@@ -77,7 +147,7 @@ impl Memory for SC3 {
             // NR30: DAC Enable
             0xFF1A => self.dac_enabled = ((v & 0b1000_0000) >> 7) != 0,
             // NR31: Length Timer
-            0xFF1B => self.length_timer = v,
+            0xFF1B => self.length_timer = v as u16,
             // NR32: Output Level
             0xFF1C => self.output_level = OutputLevel::from_bits_truncate(v),
             // NR33: Period Low
@@ -100,4 +170,51 @@ impl Memory for SC3 {
             _ => panic!("Write to unsupported SC3 address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Playing at the highest pitch, two cycles a sample, so it's always in the read window
+    // after stepping, with the sample after `position` about to be read
+    fn playing(bug: bool, position: u8) -> SC3 {
+        let mut sc3 = SC3::new(GBMode::Classic);
+        sc3.wave_ram = core::array::from_fn(|i| i as u8 * 0x11);
+        sc3.wave_ram_bug_enabled = bug;
+        sc3.write(0xFF1A, 0x80);
+        sc3.write(0xFF1D, 0xFF);
+        sc3.write(0xFF1E, 0x87);
+        assert!(sc3.on_trigger());
+        sc3.cycle(2 * position as u32);
+        assert_eq!(sc3.position, position);
+        sc3
+    }
+
+    #[test]
+    fn retriggering_leaves_wave_ram_alone_without_the_bug() {
+        let mut sc3 = playing(false, 9);
+        sc3.maybe_corrupt_wave_ram();
+        assert_eq!(sc3.wave_ram, core::array::from_fn(|i| i as u8 * 0x11));
+    }
+
+    #[test]
+    fn retriggering_corrupts_wave_ram_with_the_bug() {
+        // Reading byte 5 copies its aligned block of 4 over the start
+        let mut sc3 = playing(true, 9);
+        sc3.maybe_corrupt_wave_ram();
+        assert_eq!(sc3.wave_ram[..4], [0x44, 0x55, 0x66, 0x77]);
+        assert_eq!(sc3.wave_ram[4..], core::array::from_fn::<u8, 12, _>(|i| (i as u8 + 4) * 0x11));
+
+        // Reading one of the first 4 bytes only copies that byte
+        let mut sc3 = playing(true, 5);
+        sc3.maybe_corrupt_wave_ram();
+        assert_eq!(sc3.wave_ram[..4], [0x33, 0x11, 0x22, 0x33]);
+
+        // Away from a read nothing happens
+        let mut sc3 = playing(true, 9);
+        sc3.period_timer = WAVE_READ_WINDOW + 1;
+        sc3.maybe_corrupt_wave_ram();
+        assert_eq!(sc3.wave_ram[0], 0x00);
+    }
+}
@@ -1,12 +1,16 @@
 use crate::memory::Memory;
 use crate::sound::apu::APU;
 
+#[derive(Hash)]
 pub struct SC4 {
     pub dac_enabled: bool,
-    length_timer: u8,
-    volume: u8,
+    pub length_timer: u8,
+    pub volume: u8,
+    // Volume reloaded on trigger, the envelope then moves `volume` away from it
+    initial_volume: u8,
     positive_envelope: bool,
     envelope_pace: u8,
+    envelope_timer: u8,
     clock: u8,
     // False = 15-bit, True = 7-bit
     lfsr_width: bool,
@@ -16,8 +20,7 @@ pub struct SC4 {
     pub frequency: u32,
     pub lfsr: u16,
     pub final_volume: u8,
-    lfsr_cycle_count: u32,
-    length_cycle_count: u32
+    lfsr_cycle_count: u32
 }
 
 impl SC4 {
@@ -26,8 +29,10 @@ impl SC4 {
             dac_enabled: false,
             length_timer: 0,
             volume: 0,
+            initial_volume: 0,
             positive_envelope: false,
             envelope_pace: 0,
+            envelope_timer: 0,
             clock: 0,
             lfsr_width: false,
             clock_divider: 0,
@@ -36,8 +41,7 @@ impl SC4 {
             frequency: 0,
             lfsr: 0,
             final_volume: 0,
-            lfsr_cycle_count: 0,
-            length_cycle_count: 0
+            lfsr_cycle_count: 0
         }
     }
 
@@ -45,8 +49,10 @@ impl SC4 {
         self.dac_enabled = false;
         self.length_timer = 0;
         self.volume = 0;
+        self.initial_volume = 0;
         self.positive_envelope = false;
         self.envelope_pace = 0;
+        self.envelope_timer = 0;
         self.clock = 0;
         self.lfsr_width = false;
         self.clock_divider = 0;
@@ -56,36 +62,66 @@ impl SC4 {
         self.lfsr = 0;
         self.final_volume = 0;
         self.lfsr_cycle_count = 0;
-        self.length_cycle_count = 0;
     }
 
-    pub fn cycle(&mut self, cycles: u32) {
-        if self.length_enabled {
-            self.length_cycle_count += cycles;
+    // Clocked at 256 Hz by the frame sequencer whether or not the DAC is on, returns false
+    // once the length expires
+    pub fn clock_length(&mut self) -> bool {
+        if self.length_enabled && self.length_timer < 64 {
+            self.length_timer += 1;
+            return self.length_timer < 64;
+        }
+        true
+    }
+
+    // Reloads the channel's counters from its registers, returns whether the channel turns on
+    pub fn on_trigger(&mut self) -> bool {
+        if self.length_timer >= 64 {
+            self.length_timer = 0;
+        }
+        self.volume = self.initial_volume;
+        self.envelope_timer = self.envelope_pace;
+        self.lfsr = 0;
+        self.lfsr_cycle_count = 0;
+        self.dac_enabled
+    }
 
-            if self.length_cycle_count >= APU::hz_to_cycles(256) {
-                self.length_cycle_count = 0;
+    // Clocked at 64 Hz by the frame sequencer, a pace of 0 disables the envelope
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_pace == 0 {
+            return;
+        }
 
-                if self.dac_enabled {
-                    if self.length_timer >= 64 {
-                        self.dac_enabled = false;
-                    } else {
-                        self.length_timer += 1;
-                    }
-                }
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+        if self.envelope_timer == 0 {
+            self.envelope_timer = self.envelope_pace;
+            if self.positive_envelope && self.volume < 0x0F {
+                self.volume += 1;
+            } else if !self.positive_envelope && self.volume > 0x00 {
+                self.volume -= 1;
             }
         }
+    }
+
+    // LFSR clock in Hz from NR43: 262144 / (divisor * 2^shift), with a divisor code of 0
+    // acting as 0.5. Shifts of 14 and 15 stop the LFSR entirely.
+    fn lfsr_frequency(&self) -> u32 {
+        if self.clock >= 14 {
+            return 0;
+        }
 
-        self.lfsr_cycle_count += cycles;
-        let final_divider = if self.clock_divider == 0 { 1 } else { 2 };
-        let divisor = (final_divider as i64 ^ self.clock as i64) as u32;
+        match self.clock_divider {
+            0 => 524288 >> self.clock,
+            r => 262144 / ((r as u32) << self.clock)
+        }
+    }
 
-        if divisor != 0 {
-            // Frequency in Hz
-            self.frequency = 262144 / divisor;
+    pub fn cycle(&mut self, cycles: u32) {
+        if self.frequency != 0 {
+            self.lfsr_cycle_count += cycles;
 
-            if self.lfsr_cycle_count >= APU::hz_to_cycles(self.frequency) {
-                self.lfsr_cycle_count = 0;
+            while self.lfsr_cycle_count >= APU::hz_to_cycles(self.frequency) {
+                self.lfsr_cycle_count -= APU::hz_to_cycles(self.frequency);
 
                 let bit = {
                     let bit_0 = (self.lfsr & 0b0000_0000_0000_0001) >> 0;
@@ -122,9 +158,9 @@ impl Memory for SC4 {
             // NR41: Length Timer
             0xFF20 => 0xFF,
             // NR42: Volume & Envelope
-            0xFF21 => (self.volume & 0b0000_1111) << 4 | (self.positive_envelope as u8) << 3 | (self.envelope_pace & 0b0000_0111),
+            0xFF21 => (self.initial_volume & 0b0000_1111) << 4 | (self.positive_envelope as u8) << 3 | (self.envelope_pace & 0b0000_0111),
             // NR43: Frequency & Randomness
-            0xFF22 => (self.clock & 0b0000_1111 << 4) | (self.lfsr_width as u8) << 3 | (self.clock_divider & 0b0000_0111),
+            0xFF22 => (self.clock & 0b0000_1111) << 4 | (self.lfsr_width as u8) << 3 | (self.clock_divider & 0b0000_0111),
             // NR44: Control
             0xFF23 => (self.length_enabled as u8) << 6 | 0xBF,
             _ => 0xFF
@@ -137,19 +173,19 @@ impl Memory for SC4 {
             0xFF20 => self.length_timer = v & 0b0011_1111,
             // NR42: Volume & Envelope
             0xFF21 => {
-                self.volume = (v & 0b1111_0000) >> 4;
+                self.initial_volume = (v & 0b1111_0000) >> 4;
                 self.positive_envelope = ((v & 0b0000_1000) >> 3) != 0;
                 self.envelope_pace = v & 0b0000_0111;
 
-                if self.read(0xFF21) & 0xF8 != 0 {
-                    self.dac_enabled = true;
-                }
+                // The DAC is only on while the upper 5 bits are set
+                self.dac_enabled = self.read(0xFF21) & 0xF8 != 0;
             },
             // NR43: Frequency & Randomness
             0xFF22 => {
                 self.clock = (v & 0b1111_0000) >> 4;
                 self.lfsr_width = ((v & 0b0000_1000) >> 3) != 0;
                 self.clock_divider = v & 0b0000_0111;
+                self.frequency = self.lfsr_frequency();
             },
             // NR44: Control
             0xFF23 => {
@@ -159,4 +195,37 @@ impl Memory for SC4 {
             _ => panic!("Write to unsupported SC4 address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frequency_for(nr43: u8) -> u32 {
+        let mut sc4 = SC4::new();
+        sc4.write(0xFF22, nr43);
+        sc4.frequency
+    }
+
+    #[test]
+    fn nr43_sets_the_lfsr_frequency() {
+        // Divisor code 0 acts as 0.5
+        assert_eq!(frequency_for(0x00), 524288);
+        assert_eq!(frequency_for(0x01), 262144);
+        assert_eq!(frequency_for(0x07), 262144 / 7);
+        assert_eq!(frequency_for(0x30), 524288 >> 3);
+        assert_eq!(frequency_for(0x52), 262144 / (2 << 5));
+        // The width bit doesn't change the rate
+        assert_eq!(frequency_for(0x5A), 262144 / (2 << 5));
+        // Shifts of 14 and 15 stop the LFSR
+        assert_eq!(frequency_for(0xE1), 0);
+        assert_eq!(frequency_for(0xF0), 0);
+    }
+
+    #[test]
+    fn nr43_reads_back() {
+        let mut sc4 = SC4::new();
+        sc4.write(0xFF22, 0x5A);
+        assert_eq!(sc4.read(0xFF22), 0x5A);
+    }
+}
@@ -1,229 +1,347 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI16, AtomicU64, AtomicUsize, Ordering};
 use std::time::Duration;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, StreamConfig, FromSample, SizedSample};
+use cpal::{Device, SampleRate, StreamConfig, FromSample, SizedSample};
 use fundsp::hacker::*;
 use assert_no_alloc::*;
 
+// Samples kept per scope tap, older ones are overwritten if nobody drains them
+const SCOPE_TAP_SIZE: usize = 4096;
+// Stereo frames rendered ahead of the audio device by default, the oldest are dropped once
+// it's full so running faster than real time can't grow it without bound
+const DEFAULT_BUFFER_CAPACITY: usize = 8192;
+
+// The APU publishes channel parameters here and renders the mix as it runs,
+// the audio callback only plays back what has been rendered so far.
 pub struct Synth {
     pub s1_freq: Shared<f64>,
     pub s1_vol: Shared<f64>,
+    pub s1_dac: Shared<f64>,
     pub s1_duty: Shared<f64>,
     pub s1_l: Shared<f64>,
     pub s1_r: Shared<f64>,
 
     pub s2_freq: Shared<f64>,
     pub s2_vol: Shared<f64>,
+    pub s2_dac: Shared<f64>,
     pub s2_duty: Shared<f64>,
     pub s2_l: Shared<f64>,
     pub s2_r: Shared<f64>,
 
     pub s3_freq: Shared<f64>,
     pub s3_vol: Shared<f64>,
+    pub s3_dac: Shared<f64>,
     pub s3_l: Shared<f64>,
     pub s3_r: Shared<f64>,
 
     pub s4_freq: Shared<f64>,
     pub s4_vol: Shared<f64>,
+    pub s4_dac: Shared<f64>,
     pub s4_l: Shared<f64>,
     pub s4_r: Shared<f64>,
 
     pub global_l: Shared<f64>,
-    pub global_r: Shared<f64>
+    pub global_r: Shared<f64>,
+    // Fades the whole mix out when paused
+    pub master: Shared<f64>,
+    // Stereo separation, 1.0 leaves the mix alone and 0.0 folds it down to mono
+    pub width: Shared<f64>,
+    // Stereo frames consumed by the audio device so far
+    pub samples_played: Arc<AtomicU64>,
+    // Each channel's output before panning and mixing, for channels 1-4
+    pub scope_taps: [Arc<ScopeTap>; 4],
+    pub sample_rate: u32,
+    mixer: Box<dyn AudioUnit64>,
+    // Rendered frames waiting for the audio device, at most buffer_capacity of them
+    buffer: Arc<Mutex<VecDeque<(f64, f64)>>>,
+    buffer_capacity: usize,
+    // Leftover fraction of an output sample, in cycles times the sample rate
+    sample_clock: u64
+}
+
+// Ring buffer of one channel's samples. Samples are pushed while the mix renders and can be
+// drained from any thread, so they're stored through atomics and nothing is written while disabled.
+pub struct ScopeTap {
+    enabled: AtomicBool,
+    samples: Vec<AtomicI16>,
+    // Total samples written and read, indices wrap around the buffer
+    write: AtomicUsize,
+    read: AtomicUsize
+}
+
+impl ScopeTap {
+    fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            samples: (0..SCOPE_TAP_SIZE).map(|_| AtomicI16::new(0)).collect(),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        // Skip anything left over from the last time the tap was on
+        self.read.store(self.write.load(Ordering::Acquire), Ordering::Relaxed);
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    fn push(&self, v: f64) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let write = self.write.load(Ordering::Relaxed);
+        let sample = (v.clamp(-1.0, 1.0) * i16::MAX as f64) as i16;
+        self.samples[write % SCOPE_TAP_SIZE].store(sample, Ordering::Relaxed);
+        self.write.store(write + 1, Ordering::Release);
+    }
+
+    // Copies the oldest unread samples into `out`, returns how many were copied
+    pub fn drain(&self, out: &mut [i16]) -> usize {
+        let write = self.write.load(Ordering::Acquire);
+        let read = Ord::max(self.read.load(Ordering::Relaxed), write.saturating_sub(SCOPE_TAP_SIZE));
+        let n = Ord::min(out.len(), write - read);
+
+        for (i, sample) in out[..n].iter_mut().enumerate() {
+            *sample = self.samples[(read + i) % SCOPE_TAP_SIZE].load(Ordering::Relaxed);
+        }
+        self.read.store(read + n, Ordering::Relaxed);
+        n
+    }
 }
 
 impl Synth {
-    pub fn new() -> Self {
+    // Plays through the default output device, `sample_rate` overrides its preferred rate
+    pub fn new(sample_rate: Option<u32>) -> Self {
+        let mut synth = Synth::silent(sample_rate);
         let host = cpal::default_host();
 
+        let device = host
+            .default_output_device()
+            .expect("Failed to find a default output device");
+        let config = device.default_output_config().unwrap();
+        let sample_format = config.sample_format();
+        let mut config: StreamConfig = config.into();
+        if let Some(sample_rate) = sample_rate {
+            config.sample_rate = SampleRate(sample_rate);
+        }
+        synth.sample_rate = config.sample_rate.0;
+        synth.mixer.set_sample_rate(synth.sample_rate as f64);
+
+        let buffer = synth.buffer.clone();
+        let samples_played = synth.samples_played.clone();
+        match sample_format {
+            cpal::SampleFormat::F32 => Synth::run_audio::<f32>(buffer, samples_played, device, config),
+            cpal::SampleFormat::I16 => Synth::run_audio::<i16>(buffer, samples_played, device, config),
+            cpal::SampleFormat::U16 => Synth::run_audio::<u16>(buffer, samples_played, device, config),
+            _ => panic!("Unsupported format"),
+        }
+
+        synth
+    }
+
+    // Renders the mix like any other synth, but nothing plays it, so no audio device is needed
+    pub fn silent(sample_rate: Option<u32>) -> Self {
         let s1_freq = shared(0.0);
         let s1_vol = shared(0.0);
+        let s1_dac = shared(0.0);
         let s1_duty = shared(0.0);
         let s1_l = shared(0.0);
         let s1_r = shared(0.0);
 
         let s2_freq = shared(0.0);
         let s2_vol = shared(0.0);
+        let s2_dac = shared(0.0);
         let s2_duty = shared(0.0);
         let s2_l = shared(0.0);
         let s2_r = shared(0.0);
 
         let s3_freq = shared(0.0);
         let s3_vol = shared(0.0);
+        let s3_dac = shared(0.0);
         let s3_l = shared(0.0);
         let s3_r = shared(0.0);
 
         let s4_freq = shared(0.0);
         let s4_vol = shared(0.0);
+        let s4_dac = shared(0.0);
         let s4_l = shared(0.0);
         let s4_r = shared(0.0);
 
         let global_l = shared(0.0);
         let global_r = shared(0.0);
-
-        let device = host
-            .default_output_device()
-            .expect("Failed to find a default output device");
-        let config = device.default_output_config().unwrap();
-
-        match config.sample_format() {
-            cpal::SampleFormat::F32 => {
-                Synth::run_audio::<f32>(s1_freq.clone(),
-                                        s1_vol.clone(),
-                                        s1_duty.clone(),
-                                        s1_l.clone(),
-                                        s1_r.clone(),
-                                        s2_freq.clone(),
-                                        s2_vol.clone(),
-                                        s2_duty.clone(),
-                                        s2_l.clone(),
-                                        s2_r.clone(),
-                                        s3_freq.clone(),
-                                        s3_vol.clone(),
-                                        s3_l.clone(),
-                                        s3_r.clone(),
-                                        s4_freq.clone(),
-                                        s4_vol.clone(),
-                                        s4_l.clone(),
-                                        s4_r.clone(),
-                                        global_l.clone(),
-                                        global_r.clone(),
-                                        device,
-                                        config.into())
-            },
-            cpal::SampleFormat::I16 => {
-                Synth::run_audio::<i16>(s1_freq.clone(),
-                                        s1_vol.clone(),
-                                        s1_duty.clone(),
-                                        s1_l.clone(),
-                                        s1_r.clone(),
-                                        s2_freq.clone(),
-                                        s2_vol.clone(),
-                                        s2_duty.clone(),
-                                        s2_l.clone(),
-                                        s2_r.clone(),
-                                        s3_freq.clone(),
-                                        s3_vol.clone(),
-                                        s3_l.clone(),
-                                        s3_r.clone(),
-                                        s4_freq.clone(),
-                                        s4_vol.clone(),
-                                        s4_l.clone(),
-                                        s4_r.clone(),
-                                        global_l.clone(),
-                                        global_r.clone(),
-                                        device,
-                                        config.into())
-            },
-            cpal::SampleFormat::U16 => {
-                Synth::run_audio::<u16>(s1_freq.clone(),
-                                        s1_vol.clone(),
-                                        s1_duty.clone(),
-                                        s1_l.clone(),
-                                        s1_r.clone(),
-                                        s2_freq.clone(),
-                                        s2_vol.clone(),
-                                        s2_duty.clone(),
-                                        s2_l.clone(),
-                                        s2_r.clone(),
-                                        s3_freq.clone(),
-                                        s3_vol.clone(),
-                                        s3_l.clone(),
-                                        s3_r.clone(),
-                                        s4_freq.clone(),
-                                        s4_vol.clone(),
-                                        s4_l.clone(),
-                                        s4_r.clone(),
-                                        global_l.clone(),
-                                        global_r.clone(),
-                                        device,
-                                        config.into())
-            },
-            _ => panic!("Unsupported format"),
-        }
-
-        Self {
+        let master = shared(1.0);
+        let width = shared(1.0);
+        let samples_played = Arc::new(AtomicU64::new(0));
+        let scope_taps = [(); 4].map(|_| Arc::new(ScopeTap::new()));
+        // Nominal, there's no device to ask
+        let sample_rate = sample_rate.unwrap_or(48_000);
+
+        let mut synth = Self {
             s1_freq,
             s1_vol,
+            s1_dac,
             s1_duty,
             s1_l,
             s1_r,
 
             s2_freq,
             s2_vol,
+            s2_dac,
             s2_duty,
             s2_l,
             s2_r,
 
             s3_freq,
             s3_vol,
+            s3_dac,
             s3_l,
             s3_r,
 
             s4_freq,
             s4_vol,
+            s4_dac,
             s4_l,
             s4_r,
 
             global_l,
             global_r,
-        }
+            master,
+            width,
+            samples_played,
+            scope_taps,
+            sample_rate,
+            // Replaced below, the mixer reads its parameters from the finished synth
+            mixer: Box::new(dc((0.0, 0.0))),
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(DEFAULT_BUFFER_CAPACITY))),
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            sample_clock: 0
+        };
+        synth.mixer = synth.mixer();
+        synth.mixer.set_sample_rate(sample_rate as f64);
+        synth
     }
 
-    fn run_audio<T>(
-        s1_freq: Shared<f64>,
-        s1_vol: Shared<f64>,
-        s1_duty: Shared<f64>,
-        s1_l: Shared<f64>,
-        s1_r: Shared<f64>,
-        s2_freq: Shared<f64>,
-        s2_vol: Shared<f64>,
-        s2_duty: Shared<f64>,
-        s2_l: Shared<f64>,
-        s2_r: Shared<f64>,
-        s3_freq: Shared<f64>,
-        s3_vol: Shared<f64>,
-        s3_l: Shared<f64>,
-        s3_r: Shared<f64>,
-        s4_freq: Shared<f64>,
-        s4_vol: Shared<f64>,
-        s4_l: Shared<f64>,
-        s4_r: Shared<f64>,
-        global_l: Shared<f64>,
-        global_r: Shared<f64>,
-        device: Device,
-        config: StreamConfig
-    ) where T: SizedSample + FromSample<f64>, {
+    // Renders the output samples falling within `cycles` of a `clock` Hz Game Boy, returns how many
+    pub fn render(&mut self, cycles: u32, clock: u32) -> usize {
+        self.sample_clock += cycles as u64 * self.sample_rate as u64;
+        let frames = (self.sample_clock / clock as u64) as usize;
+        self.sample_clock %= clock as u64;
+        self.render_frames(frames);
+        frames
+    }
 
+    // Renders `frames` more output samples with the current parameters, without emulating anything
+    pub fn render_frames(&mut self, frames: usize) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for _ in 0..frames {
+            if buffer.len() >= self.buffer_capacity {
+                buffer.pop_front();
+            }
+            buffer.push_back(self.mixer.get_stereo());
+        }
+    }
 
-        tokio::spawn(async move {
-            let sample_rate = config.sample_rate.0 as f64;
-            let channels = config.channels as usize;
+    // Frames rendered but not yet played
+    pub fn buffered_samples(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
 
-            let sc1_mono = (lfo(move |_| (var(&s1_freq).0.value(), var(&s1_duty).0.value())) >> pulse()) * var(&s1_vol) * constant(0.25);
-            let sc2_mono = (lfo(move |_| (var(&s2_freq).0.value(), var(&s2_duty).0.value())) >> pulse()) * var(&s2_vol) * constant(0.25);
-            let sc3_mono = var(&s3_freq) >> sine() * var(&s3_vol) * constant(0.25);
-            let sc4_mono = var(&s4_freq) >> square() * var(&s4_vol) * constant(0.25);
+    // Limits how far rendering can run ahead of playback, dropping the oldest frames if
+    // more than `capacity` are already waiting
+    pub fn set_buffer_capacity(&mut self, capacity: usize) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let excess = buffer.len().saturating_sub(capacity);
+        buffer.drain(..excess);
+        let missing = capacity.saturating_sub(buffer.len());
+        buffer.reserve(missing);
+        self.buffer_capacity = capacity;
+    }
 
-            let sc1_stereo = sc1_mono >> ((pass() * var(&s1_l)) ^ (pass() * var(&s1_r)));
-            let sc2_stereo = sc2_mono >> ((pass() * var(&s2_l)) ^ (pass() * var(&s2_r)));
-            let sc3_stereo = sc3_mono >> ((pass() * var(&s3_l)) ^ (pass() * var(&s3_r)));
-            let sc4_stereo = sc4_mono >> ((pass() * var(&s4_l)) ^ (pass() * var(&s4_r)));
+    // Drops everything rendered but not yet played
+    pub fn flush(&mut self) {
+        self.buffer.lock().unwrap().clear();
+    }
 
-            let total_stereo = sc1_stereo + sc2_stereo; // +*/ sc4_stereo; //+ sc3_stereo; //+ sc4_stereo;
+    // Copies the oldest unplayed frames into `out` instead of the audio device, returns how many
+    pub fn drain(&mut self, out: &mut [(f64, f64)]) -> usize {
+        let mut buffer = self.buffer.lock().unwrap();
+        let n = Ord::min(out.len(), buffer.len());
+        for (frame, sample) in out[..n].iter_mut().zip(buffer.drain(..n)) {
+            *frame = sample;
+        }
+        n
+    }
 
-            let mut c = total_stereo >> (pass() * var(&global_l) | pass() * var(&global_r));
+    // The whole mix, from the published channel parameters to the filtered stereo output
+    fn mixer(&self) -> Box<dyn AudioUnit64> {
+        // Each channel produces a digital 0.0..1.0 value, which its DAC maps to -1.0..1.0.
+        // A disabled DAC outputs the centre, reached through a short ramp, and the DC block
+        // at the end of the chain removes the resulting offset gradually instead of snapping to it.
+        let (s1_freq, s1_duty) = (self.s1_freq.clone(), self.s1_duty.clone());
+        let sc1_digital = ((lfo(move |_| (s1_freq.value(), s1_duty.value())) >> pulse()) * constant(0.5) + constant(0.5)) * var(&self.s1_vol);
+        let (s2_freq, s2_duty) = (self.s2_freq.clone(), self.s2_duty.clone());
+        let sc2_digital = ((lfo(move |_| (s2_freq.value(), s2_duty.value())) >> pulse()) * constant(0.5) + constant(0.5)) * var(&self.s2_vol);
+        let sc3_digital = ((var(&self.s3_freq) >> sine()) * constant(0.5) + constant(0.5)) * var(&self.s3_vol);
+        let sc4_digital = ((var(&self.s4_freq) >> square()) * constant(0.5) + constant(0.5)) * var(&self.s4_vol);
+
+        // Passes the DAC output through unchanged, copying it to the channel's scope tap
+        let tap = |n: usize| {
+            let tap = self.scope_taps[n].clone();
+            map(move |f: &Frame<f64, U1>| {
+                tap.push(f[0]);
+                f[0]
+            })
+        };
+
+        let sc1_mono = (((sc1_digital * constant(2.0) - constant(1.0)) * (var(&self.s1_dac) >> follow(0.005))) >> tap(0)) * constant(0.25);
+        let sc2_mono = (((sc2_digital * constant(2.0) - constant(1.0)) * (var(&self.s2_dac) >> follow(0.005))) >> tap(1)) * constant(0.25);
+        let sc3_mono = (((sc3_digital * constant(2.0) - constant(1.0)) * (var(&self.s3_dac) >> follow(0.005))) >> tap(2)) * constant(0.25);
+        let sc4_mono = (((sc4_digital * constant(2.0) - constant(1.0)) * (var(&self.s4_dac) >> follow(0.005))) >> tap(3)) * constant(0.25);
+
+        let sc1_stereo = sc1_mono >> ((pass() * var(&self.s1_l)) ^ (pass() * var(&self.s1_r)));
+        let sc2_stereo = sc2_mono >> ((pass() * var(&self.s2_l)) ^ (pass() * var(&self.s2_r)));
+        let sc3_stereo = sc3_mono >> ((pass() * var(&self.s3_l)) ^ (pass() * var(&self.s3_r)));
+        let sc4_stereo = sc4_mono >> ((pass() * var(&self.s4_l)) ^ (pass() * var(&self.s4_r)));
+
+        // Channels 3 and 4 aren't mixed in yet, but still run at zero gain so their taps fill
+        let total_stereo = sc1_stereo + sc2_stereo + (sc3_stereo + sc4_stereo) * constant((0.0, 0.0));
+
+        // Muting goes through a short ramp and then the DC block, so pausing
+        // decays to silence instead of clicking or holding the last level
+        let master_gain = var(&self.master) >> follow(0.02) >> split::<U2>();
+        // Width scales the side (L-R) signal after panning and master volume
+        let stereo_width = (pass() | pass() | var(&self.width)) >> map(|f: &Frame<f64, U3>| {
+            let mid = (f[0] + f[1]) * 0.5;
+            let side = (f[0] - f[1]) * 0.5 * f[2];
+            (mid + side, mid - side)
+        });
+        let mixer = ((total_stereo >> (pass() * var(&self.global_l) | pass() * var(&self.global_r))) * master_gain) >> stereo_width >> (dcblock() | dcblock());
 
-            c.set_sample_rate(sample_rate);
-            c.allocate();
+        Box::new(mixer)
+    }
 
-            let mut next_value = move || assert_no_alloc(|| c.get_stereo());
+    // Plays rendered frames as the device asks for them, and silence if there aren't enough
+    fn run_audio<T>(
+        buffer: Arc<Mutex<VecDeque<(f64, f64)>>>,
+        samples_played: Arc<AtomicU64>,
+        device: Device,
+        config: StreamConfig
+    ) where T: SizedSample + FromSample<f64>, {
+        tokio::spawn(async move {
+            let channels = config.channels as usize;
 
             let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
 
             let stream = device.build_output_stream(
                 &config,
                 move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    Synth::write_data(data, channels, &mut next_value)
+                    let mut buffer = buffer.lock().unwrap();
+                    let mut next_value = || assert_no_alloc(|| buffer.pop_front()).unwrap_or((0.0, 0.0));
+                    Synth::write_data(data, channels, &mut next_value);
+                    samples_played.fetch_add((data.len() / channels) as u64, Ordering::Relaxed);
                 },
                 err_fn,
                 None,
@@ -252,3 +370,31 @@ impl Synth {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    #[test]
+    fn disabling_a_dac_ramps_to_the_centre() {
+        let mut synth = Synth::silent(Some(SAMPLE_RATE));
+        synth.s1_freq.set_value(1000.0);
+        synth.s1_duty.set_value(0.5);
+        synth.s1_vol.set_value(1.0);
+        synth.s1_dac.set_value(1.0);
+        synth.scope_taps[0].set_enabled(true);
+
+        let mut samples = [0; SCOPE_TAP_SIZE];
+        synth.render_frames(2400);
+        synth.scope_taps[0].drain(&mut samples);
+
+        synth.s1_dac.set_value(0.0);
+        synth.render_frames(2400);
+        assert_eq!(synth.scope_taps[0].drain(&mut samples), 2400);
+        // The first sample can land on a pulse edge, the rest are still near full level
+        assert!(samples[..10].iter().any(|s| s.unsigned_abs() > i16::MAX as u16 / 4));
+        assert!(samples[2399].unsigned_abs() < i16::MAX as u16 / 100);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::memory::Memory;
 use crate::mmu::Interrupts;
 
+#[derive(Hash)]
 pub struct Timer {
     div: u8,
     tima: u8,
@@ -9,7 +10,10 @@ pub struct Timer {
     enabled: bool,
     step: u32,
     internal_count: u32,
-    internal_divider: u32
+    internal_divider: u32,
+    // Falling edges of the DIV bit that clocks the APU frame sequencer
+    pub apu_ticks: u32,
+    pub double_speed: bool
 }
 
 impl Timer {
@@ -22,14 +26,35 @@ impl Timer {
             enabled: false,
             step: 256,
             internal_count: 0,
-            internal_divider: 0
+            internal_divider: 0,
+            apu_ticks: 0,
+            double_speed: false
         }
     }
 
+    fn apu_bit(&self) -> u8 {
+        if self.double_speed { 0b0010_0000 } else { 0b0001_0000 }
+    }
+
+    pub fn set_div(&mut self, div: u8) {
+        self.div = div;
+        self.internal_divider = 0;
+    }
+
+    // Sets the whole 16-bit divider, DIV is its upper byte
+    pub fn set_divider(&mut self, divider: u16) {
+        self.div = (divider >> 8) as u8;
+        self.internal_divider = (divider & 0xFF) as u32;
+    }
+
     pub fn cycle(&mut self, cycles: u32) {
         self.internal_divider += cycles;
         while self.internal_divider >= 256 {
+            let old_div = self.div;
             self.div = self.div.wrapping_add(1);
+            if old_div & !self.div & self.apu_bit() != 0 {
+                self.apu_ticks += 1;
+            }
             self.internal_divider -= 256;
         }
 
@@ -73,7 +98,15 @@ impl Memory for Timer {
 
     fn write(&mut self, a: u16, v: u8) {
         match a {
-            0xFF04 => self.div = 0x00,
+            0xFF04 => {
+                // Resetting DIV while the APU bit is set counts as a falling edge
+                if self.div & self.apu_bit() != 0 {
+                    self.apu_ticks += 1;
+                }
+                // The whole 16-bit divider resets, not just the upper byte DIV shows
+                self.div = 0x00;
+                self.internal_divider = 0;
+            },
             0xFF05 => self.tima = v,
             0xFF06 => self.tma = v,
             0xFF07 => {
@@ -89,4 +122,25 @@ impl Memory for Timer {
             _ => panic!("Write to unsupported timer address ({:#06x})!", a),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frame sequencer ticks over one second of CPU clocks
+    fn ticks_per_second(double_speed: bool) -> u32 {
+        let mut timer = Timer::new();
+        timer.double_speed = double_speed;
+        let cycles = if double_speed { 2 * 4_194_304 } else { 4_194_304 };
+        for _ in 0..cycles / 4 {
+            timer.cycle(4);
+        }
+        timer.apu_ticks
+    }
+
+    #[test]
+    fn frame_sequencer_runs_at_512_hz_in_both_speeds() {
+        assert_eq!(ticks_per_second(false), 512);
+        assert_eq!(ticks_per_second(true), 512);
+    }
+}
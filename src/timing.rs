@@ -0,0 +1,30 @@
+// Game Boy timing in dots (one dot per 4.194304 MHz clock, which the CPU runs at in normal speed)
+
+pub const CPU_HZ: u32 = 4_194_304;
+// SNES master clock (21.477272 MHz) / 5
+pub const SGB_CPU_HZ: u32 = 4_295_454;
+
+// Mode 2
+pub const OAM_SCAN_DOTS: u32 = 80;
+// Mode 3 with no sprites, fetches and scrolling add to it
+pub const MODE3_MIN_DOTS: u32 = 172;
+pub const SCANLINE_DOTS: u32 = 456;
+
+pub const VISIBLE_LINES: u32 = 144;
+pub const VBLANK_LINES: u32 = 10;
+pub const FRAME_DOTS: u32 = SCANLINE_DOTS * (VISIBLE_LINES + VBLANK_LINES);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_154_scanlines() {
+        assert_eq!(FRAME_DOTS, SCANLINE_DOTS * 154);
+        assert_eq!(FRAME_DOTS, 70224);
+        // About 59.7 frames a second
+        assert_eq!(CPU_HZ / FRAME_DOTS, 59);
+        // What is left of a line without sprites is HBlank
+        assert_eq!(SCANLINE_DOTS - OAM_SCAN_DOTS - MODE3_MIN_DOTS, 204);
+    }
+}